@@ -0,0 +1,73 @@
+use crate::error::UpdateError;
+use crate::hooks;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Install locations of a device with A/B slots. Updates go to the slot that
+/// is not running, which is then marked for the next boot; confirming or
+/// reverting it is left to the bootloader.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AbSlots {
+    pub slot_a_dir: PathBuf,
+    pub slot_b_dir: PathBuf,
+    /// Shell command printing the running slot, `a` or `b`.
+    pub active_slot_command: String,
+    /// Shell command marking a slot for the next boot; `{slot}` is replaced
+    /// with `a` or `b`.
+    pub set_active_command: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn name(self) -> &'static str {
+        match self {
+            Slot::A => "a",
+            Slot::B => "b",
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+impl AbSlots {
+    pub fn dir(&self, slot: Slot) -> &Path {
+        match slot {
+            Slot::A => &self.slot_a_dir,
+            Slot::B => &self.slot_b_dir,
+        }
+    }
+
+    /// The slot not currently running, which receives the update.
+    pub fn inactive_slot(&self) -> Result<Slot, UpdateError> {
+        let active = hooks::run_output_command("active slot", &self.active_slot_command)?;
+        let active = match active.to_ascii_lowercase().as_str() {
+            "a" => Slot::A,
+            "b" => Slot::B,
+            other => {
+                return Err(UpdateError::HookError(format!(
+                    "active slot command printed '{}', expected 'a' or 'b'",
+                    other
+                )))
+            }
+        };
+        Ok(active.other())
+    }
+
+    /// Marks `slot` to be booted next.
+    pub fn set_active(&self, slot: Slot) -> Result<(), UpdateError> {
+        let command = self.set_active_command.replace("{slot}", slot.name());
+        hooks::run_output_command("set active slot", &command)?;
+        tracing::info!("Slot {} marked active for the next boot", slot.name());
+        Ok(())
+    }
+}
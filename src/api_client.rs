@@ -1,12 +1,85 @@
+use crate::cancel;
+use crate::cert_expiry;
+use crate::chunks::{ChunkHashes, ChunkVerifier};
 use crate::config::Config;
+use crate::download_state::DownloadState;
 use crate::error::UpdateError;
+use crate::hooks;
+use crate::retry::{self, retry_with_backoff, RetryPolicy};
+use crate::signing;
+use crate::status_line;
 use reqwest::{
-    header::{ACCEPT_RANGES, RANGE},
-    Client, ClientBuilder,
+    header::{
+        ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_RANGE, CONTENT_TYPE, ETAG, RANGE,
+        RETRY_AFTER,
+    },
+    Client, ClientBuilder, Method, RequestBuilder,
 };
 use serde::{Deserialize, Serialize};
-use std::{path::Path, time::Duration};
-use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+};
+use tokio_util::sync::CancellationToken;
+
+/// Replaces `{{name}}` placeholders with JSON-escaped values (without quotes),
+/// so they can be used inside JSON strings of a webhook payload template.
+fn render_template(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |acc, (name, value)| {
+            let escaped = serde_json::to_string(value).unwrap_or_default();
+            acc.replace(&format!("{{{{{}}}}}", name), &escaped[1..escaped.len() - 1])
+        })
+}
+
+/// How much downloaded data may be written before the sidecar state is updated.
+const STATE_COMMIT_INTERVAL_BYTES: u64 = 1024 * 1024;
+
+/// Times a download is resumed at a chunk that failed verification before
+/// giving up on it for this cycle.
+const MAX_CHUNK_REFETCHES: u32 = 3;
+
+/// Flushes the partial download to disk and records it in the sidecar state.
+async fn commit_progress(
+    file: &mut File,
+    state: &mut DownloadState,
+    written: u64,
+    destination_path: &Path,
+) -> Result<(), UpdateError> {
+    file.flush()
+        .await
+        .and(file.sync_data().await)
+        .map_err(|e| UpdateError::FileIOError(format!("Failed to flush download: {}", e)))?;
+    state.bytes_committed = written;
+    state.save(destination_path).await
+}
+
+/// Cuts a partial download back to `len` bytes.
+async fn truncate_download(destination_path: &Path, len: u64) -> Result<(), UpdateError> {
+    let file = OpenOptions::new()
+        .write(true)
+        .open(destination_path)
+        .await
+        .map_err(|e| {
+            UpdateError::FileIOError(format!(
+                "Failed to open partial download {:?}: {}",
+                destination_path, e
+            ))
+        })?;
+    file.set_len(len).await.map_err(|e| {
+        UpdateError::FileIOError(format!(
+            "Failed to truncate partial download {:?}: {}",
+            destination_path, e
+        ))
+    })
+}
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct UpdateInfo {
@@ -14,6 +87,88 @@ pub struct UpdateInfo {
     pub version_code: i32,
     #[serde(rename = "fileUrl")]
     pub file_url: String,
+    /// Hex-encoded SHA-256 of the artifact (manifest mode only).
+    #[serde(default)]
+    pub checksum: Option<String>,
+    /// Hashes of fixed-size chunks, checked while downloading.
+    #[serde(default, rename = "chunkHashes")]
+    pub chunk_hashes: Option<ChunkHashes>,
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default, rename = "releaseNotes")]
+    pub release_notes: Option<String>,
+    /// Server-requested delay before the next check, clamped by config.
+    #[serde(default, rename = "pollIntervalSeconds")]
+    pub poll_interval_seconds: Option<u64>,
+    /// Server-requested minimum delay before the next check, e.g. during an
+    /// incident; clamped like `poll_interval_seconds`.
+    #[serde(default, rename = "retryAfterSeconds")]
+    pub retry_after_seconds: Option<u64>,
+    /// Whether applying the update reboots the device, if the server knows.
+    #[serde(default, rename = "rebootRequired")]
+    pub reboot_required: Option<bool>,
+    /// Device models the build may be installed on, as globs matched against
+    /// `device_model` (e.g. `podbox-v2*` for a model family). Empty allows
+    /// any model.
+    #[serde(default, rename = "compatibleModels")]
+    pub compatible_models: Vec<String>,
+    /// Share of devices, 0..=100, that take the build during a staged
+    /// rollout; each device's stable bucket decides whether it is one.
+    #[serde(default, rename = "canaryPercent")]
+    pub canary_percent: Option<u8>,
+}
+
+impl UpdateInfo {
+    /// Delay before the next check the server asked for: the longer of
+    /// `pollIntervalSeconds` and `retryAfterSeconds`.
+    pub fn requested_poll_seconds(&self) -> Option<u64> {
+        self.poll_interval_seconds.max(self.retry_after_seconds)
+    }
+
+    /// Whether the build may be installed on a device of `model`. A build
+    /// restricted to some models is never installed on a device whose model
+    /// is unknown.
+    pub fn supports_model(&self, model: Option<&str>) -> bool {
+        if self.compatible_models.is_empty() {
+            return true;
+        }
+        let Some(model) = model else {
+            return false;
+        };
+        self.compatible_models.iter().any(|pattern| {
+            glob::Pattern::new(pattern).map_or(pattern == model, |glob| glob.matches(model))
+        })
+    }
+}
+
+/// Response of the check endpoint when `manifest_mode` is enabled.
+#[derive(Deserialize, Debug, Clone)]
+struct ManifestPointer {
+    #[serde(rename = "manifestUrl")]
+    manifest_url: String,
+}
+
+fn is_throttled(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+}
+
+/// Delay requested by a `Retry-After` header, given either in seconds or as
+/// an HTTP date.
+fn retry_after_seconds(response: &reqwest::Response) -> Option<u64> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(seconds);
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(std::time::SystemTime::now())
+            .map_or(0, |delay| delay.as_secs()),
+    )
+}
+
+fn is_auth_failure(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -21,45 +176,465 @@ pub struct UpdateErr {
     pub message: String,
 }
 
+/// File under `download_base_dir` holding recent failed status reports.
+const STATUS_FAILURES_FILE_NAME: &str = "status_failures.jsonl";
+/// Failed status reports kept in `STATUS_FAILURES_FILE_NAME`.
+const STATUS_FAILURES_KEPT: usize = 20;
+
+/// Batched status reports held back while the batch endpoint fails; the
+/// oldest are dropped beyond this.
+const MAX_PENDING_STATUS_REPORTS: usize = 500;
+
+/// Final outcomes held back while the server throttles or the status breaker
+/// is open; the oldest are dropped beyond this.
+const MAX_HELD_TERMINAL_REPORTS: usize = 20;
+
+/// Largest `device_metrics_command` output attached to a status report.
+const MAX_DEVICE_METRICS_BYTES: usize = 4096;
+
+/// How long `device_metrics_command` may run before the report goes out
+/// without metrics.
+const DEVICE_METRICS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs `device_metrics_command` on the blocking pool and parses its JSON
+/// output. Best effort: any failure, running past `DEVICE_METRICS_TIMEOUT`,
+/// or output over `MAX_DEVICE_METRICS_BYTES`, is logged and yields `None`.
+async fn device_metrics(command: &str) -> Option<serde_json::Value> {
+    let command = command.to_string();
+    let output = cancel::run_blocking("device metrics", &CancellationToken::new(), move |cancel| {
+        hooks::run_output_command_with_timeout(
+            "device metrics",
+            &command,
+            DEVICE_METRICS_TIMEOUT,
+            cancel,
+        )
+    })
+    .await;
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            tracing::warn!("{}", e);
+            return None;
+        }
+    };
+    if output.len() > MAX_DEVICE_METRICS_BYTES {
+        tracing::warn!(
+            "Ignoring device metrics of {} bytes, over the limit of {}",
+            output.len(),
+            MAX_DEVICE_METRICS_BYTES
+        );
+        return None;
+    }
+    serde_json::from_str(&output)
+        .map_err(|e| tracing::warn!("Ignoring device metrics that are not JSON: {}", e))
+        .ok()
+}
+
+/// Delay before the first status report retry; doubled on each further attempt.
+const STATUS_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+/// Ceiling on the delay between two status report attempts.
+const STATUS_RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Retries of the update check and of the requests starting a download,
+/// which only get past connection failures and timeouts.
+const REQUEST_RETRY: RetryPolicy = RetryPolicy {
+    max_attempts: 3,
+    base_delay: Duration::from_secs(2),
+    multiplier: 2,
+    max_delay: Duration::from_secs(30),
+    retryable: retry::is_transient,
+};
+
+/// Longest release-notes excerpt echoed back in a status report.
+const MAX_STATUS_RELEASE_NOTES_CHARS: usize = 200;
+
 #[derive(Serialize, Debug)]
-struct StatusReportPayload {
+pub struct StatusReportPayload {
     #[serde(rename = "versionCode")]
     version_code: i32,
     #[serde(rename = "statusMessage")]
     status_message: String,
+    #[serde(rename = "releaseNotes", skip_serializing_if = "Option::is_none")]
+    release_notes: Option<String>,
+    #[serde(rename = "fromVersion", skip_serializing_if = "Option::is_none")]
+    from_version: Option<i32>,
+    #[serde(rename = "toVersion", skip_serializing_if = "Option::is_none")]
+    to_version: Option<i32>,
+    #[serde(rename = "phaseTimingsMs", skip_serializing_if = "Option::is_none")]
+    phase_timings_ms: Option<BTreeMap<&'static str, u64>>,
+    /// Days left on the update server's certificate, once within
+    /// `cert_expiry_warn_days`.
+    #[serde(rename = "certExpiresInDays", skip_serializing_if = "Option::is_none")]
+    cert_expires_in_days: Option<i32>,
+    /// Kind of failure that needs its own triage, e.g. `disk_full`.
+    #[serde(rename = "failureCategory", skip_serializing_if = "Option::is_none")]
+    failure_category: Option<&'static str>,
+    /// Output of `device_metrics_command`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network: Option<serde_json::Value>,
+    /// Final state of an update; never held back by
+    /// `status_report_min_interval_seconds`.
+    #[serde(skip)]
+    terminal: bool,
+}
+
+impl StatusReportPayload {
+    pub fn new(version_code: i32, status_message: String) -> Self {
+        StatusReportPayload {
+            version_code,
+            status_message,
+            release_notes: None,
+            from_version: None,
+            to_version: None,
+            phase_timings_ms: None,
+            cert_expires_in_days: None,
+            failure_category: None,
+            network: None,
+            terminal: false,
+        }
+    }
+
+    /// Marks the report as the final state of an update (success, failure,
+    /// cancellation), which bypasses the status rate limit.
+    pub fn terminal(mut self) -> Self {
+        self.terminal = true;
+        self
+    }
+
+    /// Attaches the milliseconds spent in each update phase.
+    pub fn with_phase_timings(mut self, timings: Option<BTreeMap<&'static str, u64>>) -> Self {
+        self.phase_timings_ms = timings;
+        self
+    }
+
+    /// Tags a failure report with what kind of failure it was, when known.
+    pub fn with_failure_category(mut self, error: &UpdateError) -> Self {
+        self.failure_category = match error {
+            UpdateError::ScriptDiskFull(_) => Some("disk_full"),
+            UpdateError::ServiceUnhealthy(_) => Some("service_unhealthy"),
+            UpdateError::ScriptNotAllowed(_) => Some("script_not_allowed"),
+            _ => None,
+        };
+        self
+    }
+
+    /// Marks the report as being about the transition `from` -> `to`.
+    pub fn with_transition(mut self, from: i32, to: i32) -> Self {
+        self.from_version = Some(from);
+        self.to_version = Some(to);
+        self
+    }
+
+    /// Attaches release notes, truncated so they can't bloat the payload.
+    pub fn with_release_notes(mut self, notes: Option<&str>) -> Self {
+        self.release_notes = notes.map(|notes| {
+            if notes.chars().count() > MAX_STATUS_RELEASE_NOTES_CHARS {
+                let mut truncated: String =
+                    notes.chars().take(MAX_STATUS_RELEASE_NOTES_CHARS).collect();
+                truncated.push_str("...");
+                truncated
+            } else {
+                notes.to_string()
+            }
+        });
+        self
+    }
+}
+
+/// Builder with the connection pool tuned for a device that only talks to its
+/// update server: at most one idle connection, reaped after
+/// `pool_idle_timeout_seconds`.
+fn pooled_client_builder(config: &Config) -> ClientBuilder {
+    let builder = ClientBuilder::new().connect_timeout(Duration::from_secs(10));
+    if config.pool_idle_timeout_seconds == 0 {
+        builder.pool_max_idle_per_host(0)
+    } else {
+        builder
+            .pool_max_idle_per_host(1)
+            .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_seconds))
+    }
+}
+
+/// Whether `host` matches an entry of `download_host_allowlist`: either the
+/// exact host or, for `*.example.com`, any subdomain of it. An empty list
+/// allows every host.
+fn download_host_allowed(allowlist: &[String], host: Option<&str>) -> bool {
+    if allowlist.is_empty() {
+        return true;
+    }
+    let Some(host) = host.map(str::to_ascii_lowercase) else {
+        return false;
+    };
+    allowlist.iter().any(|entry| {
+        let entry = entry.to_ascii_lowercase();
+        match entry.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => host == entry,
+        }
+    })
 }
 
+/// Follows redirects of artifact downloads only towards allowed hosts.
+fn download_redirect_policy(config: &Config) -> reqwest::redirect::Policy {
+    let allowlist = config.download_host_allowlist.clone();
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= 10 {
+            attempt.error("too many redirects")
+        } else if download_host_allowed(&allowlist, attempt.url().host_str()) {
+            attempt.follow()
+        } else {
+            let message = format!(
+                "redirect to host {:?} is not in download_host_allowlist",
+                attempt.url().host_str().unwrap_or_default()
+            );
+            tracing::warn!("Refusing download {}", message);
+            attempt.error(message)
+        }
+    })
+}
+
+#[derive(Clone)]
 pub struct ApiClient {
     client: Client,
+    /// Client for artifact downloads. It has no read timeout; stalls are
+    /// detected per chunk with `download_inactivity_timeout_seconds` instead.
+    download_client: Client,
     config: Config,
     token: String,
+    /// Reports waiting for `flush_status_reports` when `batch_status_reports`
+    /// is set.
+    pending_status: Arc<Mutex<Vec<StatusReportPayload>>>,
+    status_limiter: Arc<Mutex<StatusLimiter>>,
+    /// Days left on the server certificate when last seen within
+    /// `cert_expiry_warn_days` of expiring.
+    cert_expiry_warning: Arc<Mutex<Option<i32>>>,
+    /// End of the `Retry-After` delay the server last throttled us with.
+    throttled_until: Arc<Mutex<Option<Instant>>>,
+    status_breaker: Arc<Mutex<StatusBreaker>>,
+}
+
+/// Circuit breaker of the status endpoint: after `status_breaker_threshold`
+/// failed reports in a row it opens, and reports are held instead of sent
+/// until `status_breaker_cooldown_seconds` have passed. The next report is
+/// then a single-attempt probe that closes it again or reopens it.
+#[derive(Default)]
+struct StatusBreaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// What the status breaker lets through.
+enum BreakerState {
+    Closed,
+    /// The cooldown is over; one attempt decides.
+    Probing,
+    Open,
+}
+
+/// State of `status_report_min_interval_seconds`: when the last report went
+/// out, and the latest report held back since. Final outcomes held back are
+/// kept apart so a later progress report can't replace them.
+#[derive(Default)]
+struct StatusLimiter {
+    last_sent: Option<Instant>,
+    held: Option<StatusReportPayload>,
+    held_terminal: Vec<StatusReportPayload>,
+}
+
+impl StatusLimiter {
+    fn hold(&mut self, payload: StatusReportPayload) {
+        if !payload.terminal {
+            self.held = Some(payload);
+            return;
+        }
+        self.held_terminal.push(payload);
+        let dropped = self
+            .held_terminal
+            .len()
+            .saturating_sub(MAX_HELD_TERMINAL_REPORTS);
+        if dropped > 0 {
+            tracing::warn!("Dropping {} held final status reports", dropped);
+            self.held_terminal.drain(..dropped);
+        }
+    }
 }
 
 impl ApiClient {
     pub fn new(config: Config, token: String) -> Self {
         ApiClient {
-            client: ClientBuilder::new()
-                .connect_timeout(Duration::from_secs(10))
+            client: pooled_client_builder(&config)
                 .read_timeout(Duration::from_secs(10))
+                .gzip(config.compress_api_responses)
+                .brotli(config.compress_api_responses)
+                .tls_info(config.cert_expiry_warn_days > 0)
+                .build()
+                .unwrap(),
+            // Ranged downloads must see the raw bytes, so never negotiate compression here.
+            download_client: pooled_client_builder(&config)
+                .redirect(download_redirect_policy(&config))
+                .no_gzip()
+                .no_brotli()
                 .build()
                 .unwrap(),
             config,
             token,
+            pending_status: Arc::default(),
+            status_limiter: Arc::default(),
+            cert_expiry_warning: Arc::default(),
+            throttled_until: Arc::default(),
+            status_breaker: Arc::default(),
+        }
+    }
+
+    fn status_breaker_state(&self) -> BreakerState {
+        match self.status_breaker.lock().unwrap().open_until {
+            None => BreakerState::Closed,
+            Some(until) if Instant::now() < until => BreakerState::Open,
+            Some(_) => BreakerState::Probing,
+        }
+    }
+
+    /// Feeds the outcome of a status delivery to the breaker, logging when
+    /// it opens or closes.
+    fn record_status_delivery(&self, result: &Result<(), UpdateError>) {
+        let threshold = self.config.status_breaker_threshold;
+        if threshold == 0 {
+            return;
+        }
+        let mut breaker = self.status_breaker.lock().unwrap();
+        match result {
+            Ok(()) => {
+                if breaker.open_until.is_some() {
+                    tracing::info!("Status endpoint reachable again, resuming status reports");
+                }
+                *breaker = StatusBreaker::default();
+            }
+            // Throttling is no sign of a dead endpoint.
+            Err(UpdateError::Throttled { .. }) => {}
+            Err(e) => {
+                breaker.consecutive_failures = breaker.consecutive_failures.saturating_add(1);
+                let probe_failed = breaker.open_until.is_some();
+                if probe_failed || breaker.consecutive_failures >= threshold {
+                    let cooldown = Duration::from_secs(self.config.status_breaker_cooldown_seconds);
+                    if !probe_failed {
+                        tracing::warn!(
+                            "Status endpoint failed {} times in a row ({}), holding status \
+                             reports for {} seconds while updates continue",
+                            breaker.consecutive_failures,
+                            e,
+                            cooldown.as_secs()
+                        );
+                    } else {
+                        tracing::debug!("Status endpoint still unreachable: {}", e);
+                    }
+                    breaker.open_until = Some(Instant::now() + cooldown);
+                }
+            }
+        }
+    }
+
+    /// Records that `what` was throttled with `status`; with a `Retry-After`,
+    /// no request goes out until it has passed.
+    fn note_throttle(&self, what: &str, status: reqwest::StatusCode, retry_after: Option<u64>) {
+        tracing::warn!(
+            "{} throttled by the server with status {}, Retry-After: {:?}",
+            what,
+            status,
+            retry_after
+        );
+        if let Some(seconds) = retry_after {
+            let until = Instant::now() + Duration::from_secs(seconds);
+            let mut throttled_until = self.throttled_until.lock().unwrap();
+            *throttled_until = Some(throttled_until.map_or(until, |current| current.max(until)));
         }
     }
 
+    /// Time left before the server's last `Retry-After` delay has passed.
+    pub fn throttle_remaining(&self) -> Option<Duration> {
+        self.throttled_until
+            .lock()
+            .unwrap()
+            .map(|until| until.saturating_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Warns when the certificate `response` came with expires within
+    /// `cert_expiry_warn_days`, and remembers it for the status reports.
+    fn check_cert_expiry(&self, response: &reqwest::Response) {
+        if self.config.cert_expiry_warn_days == 0 {
+            return;
+        }
+        let Some(days) = response
+            .extensions()
+            .get::<reqwest::tls::TlsInfo>()
+            .and_then(|info| info.peer_certificate())
+            .and_then(cert_expiry::days_until_expiry)
+        else {
+            return;
+        };
+        let warning = (days <= self.config.cert_expiry_warn_days as i32).then_some(days);
+        if warning.is_some() {
+            tracing::warn!(
+                "The certificate of {} expires in {} days",
+                response.url().host_str().unwrap_or("the update server"),
+                days
+            );
+        }
+        *self.cert_expiry_warning.lock().unwrap() = warning;
+    }
+
+    /// Shares `other`'s queue of batched status reports, its rate limit, its
+    /// throttling state and its status breaker, so reports still waiting survive replacing the
+    /// client on a config reload.
+    pub fn with_pending_status_of(mut self, other: &ApiClient) -> Self {
+        self.pending_status = Arc::clone(&other.pending_status);
+        self.status_limiter = Arc::clone(&other.status_limiter);
+        self.throttled_until = Arc::clone(&other.throttled_until);
+        self.status_breaker = Arc::clone(&other.status_breaker);
+        self
+    }
+
     pub async fn check_for_updates(&self) -> Result<UpdateInfo, UpdateError> {
         tracing::info!(
             "Checking for updates at: {}",
             self.config.update_check_api_url
         );
+        retry_with_backoff(&REQUEST_RETRY, "Update check", || {
+            self.check_for_updates_once()
+        })
+        .await
+    }
 
-        let response = self
-            .client
-            .get(&self.config.update_check_api_url)
-            .header("device-token", &self.token)
-            .send()
-            .await?;
+    async fn check_for_updates_once(&self) -> Result<UpdateInfo, UpdateError> {
+        let mut request = self.authorized(self.client.get(&self.config.update_check_api_url));
+        if let Some(model) = &self.config.device_model {
+            request = request.query(&[("model", model)]);
+        }
+        let response = request.send().await?;
+        self.check_cert_expiry(&response);
+
+        if is_auth_failure(response.status()) {
+            let status = response.status();
+            let message = response.text().await.unwrap_or_default();
+            tracing::error!(
+                "Update check rejected the device token with status {}: {}",
+                status,
+                message
+            );
+            return Err(UpdateError::Unauthorized { status, message });
+        }
+
+        if is_throttled(response.status()) {
+            let status = response.status();
+            let retry_after_seconds = retry_after_seconds(&response);
+            self.note_throttle("Update check", status, retry_after_seconds);
+            return Err(UpdateError::Throttled {
+                status,
+                retry_after_seconds,
+            });
+        }
 
         if !response.status().is_success() {
             let status = response.status();
@@ -75,15 +650,144 @@ impl ApiClient {
             });
         }
 
+        if self.config.manifest_mode {
+            let pointer = response.json::<ManifestPointer>().await?;
+            tracing::debug!("Received manifest pointer: {:?}", pointer);
+            return self.fetch_manifest(&pointer.manifest_url).await;
+        }
+
         let update_info = response.json::<UpdateInfo>().await?;
         tracing::debug!("Received update info: {:?}", update_info);
         Ok(update_info)
     }
 
+    /// Fetches the manifest the check endpoint pointed to. The device token
+    /// is only sent along when the manifest is served from the origin of
+    /// `update_check_api_url`, so a pointer elsewhere can't collect it.
+    async fn fetch_manifest(&self, manifest_url: &str) -> Result<UpdateInfo, UpdateError> {
+        tracing::info!("Fetching update manifest from: {}", manifest_url);
+
+        let check_url = reqwest::Url::parse(&self.config.update_check_api_url).map_err(|e| {
+            UpdateError::ConfigError(format!("Invalid update_check_api_url: {}", e))
+        })?;
+        let url = check_url.join(manifest_url).map_err(|e| {
+            UpdateError::DownloadError(format!("Invalid manifest URL {}: {}", manifest_url, e))
+        })?;
+        let request = self.client.get(url.clone());
+        let request = if url.origin() == check_url.origin() {
+            self.authorized(request)
+        } else {
+            tracing::debug!(
+                "Manifest is served from another origin than the API, not sending the device token"
+            );
+            request
+        };
+        let response = request.send().await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_message = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            tracing::error!(
+                "Manifest request failed with status {}: {}",
+                status,
+                error_message
+            );
+            return Err(UpdateError::ApiRequestFailed {
+                status,
+                message: error_message,
+            });
+        }
+
+        let manifest = response.json::<UpdateInfo>().await?;
+        tracing::debug!("Received update manifest: {:?}", manifest);
+        Ok(manifest)
+    }
+
+    /// Attaches the device token to an API request, in `device_token_header`
+    /// or as a bearer token.
+    fn authorized(&self, request: RequestBuilder) -> RequestBuilder {
+        if self.config.device_token_bearer {
+            request.bearer_auth(&self.token)
+        } else {
+            request.header(self.config.device_token_header.as_str(), &self.token)
+        }
+    }
+
+    /// Request against an artifact URL. Only `download_token` is attached,
+    /// never the device token, which must not leak to the storage host.
+    fn download_request(&self, method: Method, url: &str) -> RequestBuilder {
+        // Byte ranges and sizes refer to the stored artifact, so transport
+        // compression, which the absence of Accept-Encoding would allow, is
+        // refused outright.
+        let request = self
+            .download_client
+            .request(method, url)
+            .header(ACCEPT_ENCODING, "identity");
+        match &self.config.download_token {
+            Some(token) => request.header(self.config.download_token_header.as_str(), token),
+            None => request,
+        }
+    }
+
+    /// Rejects an artifact URL whose host is not in `download_host_allowlist`.
+    /// Redirects are checked by the download client's redirect policy.
+    pub fn check_download_host(&self, url: &str) -> Result<(), UpdateError> {
+        let parsed = reqwest::Url::parse(url)
+            .map_err(|e| UpdateError::DownloadError(format!("Invalid file URL {}: {}", url, e)))?;
+        if !download_host_allowed(&self.config.download_host_allowlist, parsed.host_str()) {
+            return Err(UpdateError::DownloadError(format!(
+                "host {:?} of file URL {} is not in download_host_allowlist",
+                parsed.host_str().unwrap_or_default(),
+                url
+            )));
+        }
+        Ok(())
+    }
+
+    /// Downloads `url` to `destination_path`, resuming a partial download.
+    /// With `chunks`, each chunk is verified as it arrives, and a bad one is
+    /// fetched again with a range request starting at it.
+    #[tracing::instrument(name = "download", skip_all, fields(resumed_from = tracing::field::Empty))]
     pub async fn download_update(
         &self,
         url: &str,
         destination_path: &Path,
+        chunks: Option<&ChunkHashes>,
+    ) -> Result<(), UpdateError> {
+        let chunks = chunks.filter(|chunks| {
+            if chunks.chunk_size == 0 {
+                tracing::warn!("Ignoring chunk hashes with a chunk size of 0");
+            }
+            chunks.chunk_size > 0
+        });
+        let mut refetches = 0;
+        loop {
+            match self.download_once(url, destination_path, chunks).await {
+                Err(UpdateError::ChunkMismatch { index, offset })
+                    if refetches < MAX_CHUNK_REFETCHES =>
+                {
+                    refetches += 1;
+                    tracing::warn!(
+                        "Fetching chunk {} again from byte {} ({}/{})",
+                        index,
+                        offset,
+                        refetches,
+                        MAX_CHUNK_REFETCHES
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn download_once(
+        &self,
+        url: &str,
+        destination_path: &Path,
+        chunks: Option<&ChunkHashes>,
     ) -> Result<(), UpdateError> {
         // Ensure parent directory exists
         if let Some(parent_dir) = destination_path.parent() {
@@ -97,8 +801,21 @@ impl ApiClient {
             }
         }
 
+        self.check_download_host(url)?;
+        let inactivity_timeout =
+            Duration::from_secs(self.config.download_inactivity_timeout_seconds);
+
         // Step 1: Head Request
-        let response = self.client.head(url).send().await?;
+        let response = retry_with_backoff(&REQUEST_RETRY, "Download HEAD request", || async {
+            tokio::time::timeout(
+                inactivity_timeout,
+                self.download_request(Method::HEAD, url).send(),
+            )
+            .await
+            .map_err(|_| UpdateError::TimeoutError)?
+            .map_err(UpdateError::from)
+        })
+        .await?;
 
         if !response.status().is_success() {
             return Err(UpdateError::HeadError(format!(
@@ -113,19 +830,27 @@ impl ApiClient {
             .and_then(|val| val.to_str().ok())
             .and_then(|s| s.parse::<u64>().ok());
 
-        let supports_range = response.headers().get(ACCEPT_RANGES).map_or(false, |val| {
-            val.to_str().map_or(false, |s| s.contains("bytes"))
-        });
+        let supports_range = response
+            .headers()
+            .get(ACCEPT_RANGES)
+            .is_some_and(|val| val.to_str().is_ok_and(|s| s.contains("bytes")));
+
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|val| val.to_str().ok())
+            .map(str::to_string);
 
         tracing::debug!(
-            "file size and range support is: {} , {}",
-            total_size_opt.unwrap(),
-            supports_range
+            "file size, range support and etag are: {:?} , {} , {:?}",
+            total_size_opt,
+            supports_range,
+            etag
         );
 
         // STEP 2: Determine current downloaded size
 
-        let current_offset = if destination_path.exists() {
+        let mut current_offset = if destination_path.exists() {
             tokio::fs::metadata(destination_path)
                 .await
                 .map_err(|e| {
@@ -145,6 +870,34 @@ impl ApiClient {
             current_offset
         );
 
+        // Only resume when the sidecar agrees with both the file and the server.
+        if current_offset > 0 {
+            let mismatch = match DownloadState::load(destination_path).await {
+                Some(state) => {
+                    state.resume_mismatch(url, total_size_opt, etag.as_deref(), current_offset)
+                }
+                None => Some("no download state recorded".to_string()),
+            };
+            if let Some(reason) = mismatch {
+                tracing::warn!(
+                    "Discarding partial download {}: {}",
+                    destination_path.display(),
+                    reason
+                );
+                tokio::fs::remove_file(destination_path)
+                    .await
+                    .map_err(|e| {
+                        UpdateError::FileSystemError(format!(
+                            "Failed to remove partial download {}: {}",
+                            destination_path.display(),
+                            e
+                        ))
+                    })?;
+                DownloadState::remove(destination_path).await;
+                current_offset = 0;
+            }
+        }
+
         // Step 3: Compare downloaded size
         if let Some(total_size) = total_size_opt {
             if current_offset >= total_size && total_size > 0 {
@@ -158,15 +911,134 @@ impl ApiClient {
             }
         }
 
+        // Only whole chunks can be verified, so a partial one is fetched again.
+        if let Some(chunks) = chunks {
+            let aligned = chunks.chunk_start(current_offset);
+            if aligned != current_offset {
+                tracing::debug!(
+                    "Cutting partial download back from {} to chunk boundary {}",
+                    current_offset,
+                    aligned
+                );
+                truncate_download(destination_path, aligned).await?;
+                current_offset = aligned;
+            }
+        }
+
+        tracing::Span::current().record("resumed_from", current_offset);
         tracing::info!("Downloading from {} to {:?}", url, destination_path);
 
-        let mut request_builder = self.client.get(url);
+        let response = loop {
+            let offset = current_offset;
+            let response = retry_with_backoff(&REQUEST_RETRY, "Download request", || async move {
+                let mut request_builder = self.download_request(Method::GET, url);
+                if offset > 0 {
+                    request_builder = request_builder.header(RANGE, format!("bytes={}-", offset));
+                }
+                tokio::time::timeout(inactivity_timeout, request_builder.send())
+                    .await
+                    .map_err(|_| UpdateError::TimeoutError)?
+                    .map_err(UpdateError::from)
+            })
+            .await?;
 
-        if current_offset > 0 {
-            request_builder = request_builder.header(RANGE, format!("bytes={}-", current_offset));
-        }
+            // The server rejects a range starting at or past its end, meaning
+            // the local file may already be complete.
+            if response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE && current_offset > 0
+            {
+                // A 416 carries the full size as `Content-Range: bytes */<size>`.
+                let server_size = total_size_opt.or_else(|| {
+                    response
+                        .headers()
+                        .get(CONTENT_RANGE)
+                        .and_then(|val| val.to_str().ok())
+                        .and_then(|s| s.strip_prefix("bytes */"))
+                        .and_then(|s| s.parse::<u64>().ok())
+                });
+                if server_size == Some(current_offset) {
+                    tracing::info!(
+                        "Range not satisfiable, {} is already complete ({} bytes)",
+                        destination_path.display(),
+                        current_offset
+                    );
+                    DownloadState {
+                        url: url.to_string(),
+                        total_size: total_size_opt,
+                        etag,
+                        bytes_committed: current_offset,
+                    }
+                    .save(destination_path)
+                    .await?;
+                    return Ok(());
+                }
 
-        let response = request_builder.send().await?;
+                tracing::warn!(
+                    "Range not satisfiable but {} holds {} bytes (expected {:?}), restarting download",
+                    destination_path.display(),
+                    current_offset,
+                    server_size
+                );
+                tokio::fs::remove_file(destination_path)
+                    .await
+                    .map_err(|e| {
+                        UpdateError::FileSystemError(format!(
+                            "Failed to remove partial download {}: {}",
+                            destination_path.display(),
+                            e
+                        ))
+                    })?;
+                DownloadState::remove(destination_path).await;
+                current_offset = 0;
+                continue;
+            }
+
+            if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+                // `Content-Range: bytes <start>-<end>/<size>`
+                let range_start = response
+                    .headers()
+                    .get(CONTENT_RANGE)
+                    .and_then(|val| val.to_str().ok())
+                    .and_then(|s| s.strip_prefix("bytes "))
+                    .and_then(|s| s.split('-').next())
+                    .and_then(|s| s.parse::<u64>().ok());
+                let restart_reason = match range_start {
+                    Some(start) if start == current_offset => None,
+                    Some(start) => Some(format!(
+                        "server resumed at byte {} instead of {}",
+                        start, current_offset
+                    )),
+                    None if self.config.trust_range_without_content_range => {
+                        tracing::info!(
+                            "Partial content without Content-Range, trusting it to start at byte {}",
+                            current_offset
+                        );
+                        None
+                    }
+                    None => Some("partial content without Content-Range".to_string()),
+                };
+                if let Some(reason) = restart_reason {
+                    tracing::warn!(
+                        "Cannot validate resume of {} ({}), restarting download",
+                        destination_path.display(),
+                        reason
+                    );
+                    tokio::fs::remove_file(destination_path)
+                        .await
+                        .map_err(|e| {
+                            UpdateError::FileSystemError(format!(
+                                "Failed to remove partial download {}: {}",
+                                destination_path.display(),
+                                e
+                            ))
+                        })?;
+                    DownloadState::remove(destination_path).await;
+                    current_offset = 0;
+                    continue;
+                }
+            }
+
+            break response;
+        };
 
         if !response.status().is_success() {
             return Err(UpdateError::DownloadError(format!(
@@ -174,6 +1046,18 @@ impl ApiClient {
                 response.status()
             )));
         }
+        // Written as is, an encoded body would not be the artifact.
+        if let Some(encoding) = response
+            .headers()
+            .get(CONTENT_ENCODING)
+            .map(|val| val.to_str().unwrap_or("?").trim())
+            .filter(|encoding| !encoding.eq_ignore_ascii_case("identity"))
+        {
+            return Err(UpdateError::DownloadError(format!(
+                "server sent the artifact with Content-Encoding {} although identity was requested",
+                encoding
+            )));
+        }
 
         let mut dest_file_builder = OpenOptions::new();
         dest_file_builder.create(true);
@@ -181,6 +1065,7 @@ impl ApiClient {
         if response.status() == reqwest::StatusCode::OK {
             //NOTE: server wants to send the file from the beginning.
             dest_file_builder.write(true).truncate(true);
+            current_offset = 0;
         } else {
             dest_file_builder.append(true);
         }
@@ -194,66 +1079,858 @@ impl ApiClient {
                 ))
             })?;
 
+        let mut state = DownloadState {
+            url: url.to_string(),
+            total_size: total_size_opt,
+            etag,
+            bytes_committed: current_offset,
+        };
+        state.save(destination_path).await?;
+
+        // Without `x-content-length`, a ranged or full body still tells us where it ends.
+        let expected_size =
+            total_size_opt.or_else(|| response.content_length().map(|len| current_offset + len));
+
         tracing::debug!("{:?}", response.headers());
+        let mut verifier = chunks.map(|chunks| ChunkVerifier::new(chunks, current_offset));
         let mut stream = response.bytes_stream();
-        while let Some(item) = futures_util::StreamExt::next(&mut stream).await {
-            let chunk = item.map_err(|e| {
-                if e.to_string() == "error decoding response body" {
-                    UpdateError::TimeoutError
-                } else {
-                    UpdateError::DownloadError(format!("Error reading download stream: {}", e))
+        let mut written = current_offset;
+        let stream_result = loop {
+            let item = match tokio::time::timeout(
+                inactivity_timeout,
+                futures_util::StreamExt::next(&mut stream),
+            )
+            .await
+            {
+                Ok(item) => item,
+                Err(_) => {
+                    tracing::warn!(
+                        "No data received for {} seconds, aborting download",
+                        inactivity_timeout.as_secs()
+                    );
+                    break Err(UpdateError::TimeoutError);
                 }
+            };
+            let Some(item) = item else {
+                break verifier.as_mut().map_or(Ok(()), ChunkVerifier::finish);
+            };
+            let chunk = match item {
+                Ok(chunk) => chunk,
+                Err(e) if e.to_string() == "error decoding response body" => {
+                    break Err(UpdateError::TimeoutError)
+                }
+                Err(e) => {
+                    break Err(UpdateError::DownloadError(format!(
+                        "Error reading download stream: {}",
+                        e
+                    )))
+                }
+            };
+            if let Err(e) = dest_file.write_all(&chunk).await {
+                break Err(UpdateError::FileIOError(format!(
+                    "Failed to write chunk to file: {}",
+                    e
+                )));
+            }
+            written += chunk.len() as u64;
+            status_line::download_progress(written, expected_size);
+            if let Some(verifier) = verifier.as_mut() {
+                if let Err(e) = verifier.update(&chunk) {
+                    break Err(e);
+                }
+            }
+            if written - state.bytes_committed >= STATE_COMMIT_INTERVAL_BYTES {
+                if let Err(e) =
+                    commit_progress(&mut dest_file, &mut state, written, destination_path).await
+                {
+                    break Err(e);
+                }
+            }
+        };
+
+        // A chunk that failed verification is dropped, so the download
+        // resumes at its start.
+        if let Err(UpdateError::ChunkMismatch { offset, .. }) = &stream_result {
+            dest_file.set_len(*offset).await.map_err(|e| {
+                UpdateError::FileIOError(format!("Failed to drop a corrupt chunk: {}", e))
             })?;
-            dest_file.write_all(&chunk).await.map_err(|e| {
-                UpdateError::FileIOError(format!("Failed to write chunk to file: {}", e))
-            })?;
+            written = *offset;
+        }
+        // Record whatever reached the file, even when the stream failed, so
+        // the next attempt can resume from it.
+        commit_progress(&mut dest_file, &mut state, written, destination_path).await?;
+        stream_result?;
+
+        // A connection closed cleanly but early ends the stream without an error.
+        if let Some(expected) = expected_size.filter(|_| self.config.verify_download_size) {
+            if written != expected {
+                tracing::warn!(
+                    "Download of {:?} ended after {} of {} bytes, keeping it for resume",
+                    destination_path,
+                    written,
+                    expected
+                );
+                return Err(UpdateError::IncompleteDownload {
+                    received: written,
+                    expected,
+                });
+            }
         }
 
         tracing::info!("Download complete: {:?}", destination_path);
         Ok(())
     }
 
+    /// Downloads `url` into memory, for artifacts below
+    /// `in_memory_threshold_bytes` whose archive should never touch flash.
+    /// A body growing past `limit` bytes is refused. Nothing is kept to
+    /// resume from: an interrupted download starts over.
+    pub async fn download_to_memory(
+        &self,
+        url: &str,
+        chunks: Option<&ChunkHashes>,
+        limit: u64,
+    ) -> Result<Vec<u8>, UpdateError> {
+        self.check_download_host(url)?;
+        let inactivity_timeout =
+            Duration::from_secs(self.config.download_inactivity_timeout_seconds);
+        tracing::info!("Downloading {} into memory", url);
+
+        let mut response = retry_with_backoff(&REQUEST_RETRY, "Download request", || async {
+            tokio::time::timeout(
+                inactivity_timeout,
+                self.download_request(Method::GET, url).send(),
+            )
+            .await
+            .map_err(|_| UpdateError::TimeoutError)?
+            .map_err(UpdateError::from)
+        })
+        .await?;
+        if !response.status().is_success() {
+            return Err(UpdateError::DownloadError(format!(
+                "Download request failed with status: {}",
+                response.status()
+            )));
+        }
+        if let Some(encoding) = response
+            .headers()
+            .get(CONTENT_ENCODING)
+            .map(|val| val.to_str().unwrap_or("?").trim())
+            .filter(|encoding| !encoding.eq_ignore_ascii_case("identity"))
+        {
+            return Err(UpdateError::DownloadError(format!(
+                "server sent the artifact with Content-Encoding {} although identity was requested",
+                encoding
+            )));
+        }
+        let expected_size = response.content_length();
+        if let Some(size) = expected_size.filter(|size| *size > limit) {
+            return Err(UpdateError::DownloadError(format!(
+                "artifact of {} bytes exceeds in_memory_threshold_bytes ({})",
+                size, limit
+            )));
+        }
+
+        let chunks = chunks.filter(|chunks| chunks.chunk_size > 0);
+        let mut verifier = chunks.map(|chunks| ChunkVerifier::new(chunks, 0));
+        let mut data = Vec::with_capacity(expected_size.unwrap_or(0) as usize);
+        loop {
+            let chunk = tokio::time::timeout(inactivity_timeout, response.chunk())
+                .await
+                .map_err(|_| UpdateError::TimeoutError)??;
+            let Some(chunk) = chunk else {
+                break;
+            };
+            if data.len() as u64 + chunk.len() as u64 > limit {
+                return Err(UpdateError::DownloadError(format!(
+                    "artifact exceeds in_memory_threshold_bytes ({})",
+                    limit
+                )));
+            }
+            if let Some(verifier) = verifier.as_mut() {
+                verifier.update(&chunk)?;
+            }
+            data.extend_from_slice(&chunk);
+            status_line::download_progress(data.len() as u64, expected_size);
+        }
+        if let Some(verifier) = verifier.as_mut() {
+            verifier.finish()?;
+        }
+
+        if let Some(expected) = expected_size.filter(|_| self.config.verify_download_size) {
+            if data.len() as u64 != expected {
+                return Err(UpdateError::IncompleteDownload {
+                    received: data.len() as u64,
+                    expected,
+                });
+            }
+        }
+        tracing::info!("Download complete: {} bytes held in memory", data.len());
+        Ok(data)
+    }
+
+    /// Size of the artifact at `url` as advertised by a `HEAD` request, if any.
+    pub async fn remote_size(&self, url: &str) -> Result<Option<u64>, UpdateError> {
+        self.check_download_host(url)?;
+        let response = self.download_request(Method::HEAD, url).send().await?;
+        if !response.status().is_success() {
+            return Err(UpdateError::HeadError(format!(
+                "Head request failed with status: {}",
+                response.status()
+            )));
+        }
+        Ok(response
+            .headers()
+            .get("x-content-length")
+            .and_then(|val| val.to_str().ok())
+            .and_then(|s| s.parse::<u64>().ok()))
+    }
+
+    /// First `len` bytes of the artifact at `url`, asked for with a `Range`
+    /// request. Servers that ignore the range are cut off after `len` bytes.
+    pub async fn fetch_prefix(&self, url: &str, len: usize) -> Result<Vec<u8>, UpdateError> {
+        self.check_download_host(url)?;
+        let inactivity_timeout =
+            Duration::from_secs(self.config.download_inactivity_timeout_seconds);
+        let request = self
+            .download_request(Method::GET, url)
+            .header(RANGE, format!("bytes=0-{}", len.saturating_sub(1)));
+        let mut response = tokio::time::timeout(inactivity_timeout, request.send())
+            .await
+            .map_err(|_| UpdateError::TimeoutError)??;
+        if !response.status().is_success() {
+            return Err(UpdateError::DownloadError(format!(
+                "Prefix request failed with status: {}",
+                response.status()
+            )));
+        }
+
+        let mut prefix = Vec::with_capacity(len);
+        while prefix.len() < len {
+            let chunk = tokio::time::timeout(inactivity_timeout, response.chunk())
+                .await
+                .map_err(|_| UpdateError::TimeoutError)??;
+            let Some(chunk) = chunk else {
+                break;
+            };
+            prefix.extend_from_slice(&chunk);
+        }
+        prefix.truncate(len);
+        Ok(prefix)
+    }
+
+    /// Sends a lightweight authenticated `HEAD` request to check that an
+    /// endpoint is reachable and accepts the device token.
+    pub async fn probe_endpoint(&self, url: &str) -> Result<(), UpdateError> {
+        let response = self.authorized(self.client.head(url)).send().await?;
+
+        let status = response.status();
+        if is_auth_failure(status) {
+            return Err(UpdateError::Unauthorized {
+                status,
+                message: format!("probe of {} failed", url),
+            });
+        }
+        if status.is_server_error() {
+            return Err(UpdateError::ApiRequestFailed {
+                status,
+                message: format!("probe of {} failed", url),
+            });
+        }
+        Ok(())
+    }
+
+    /// Posts an update outcome to `notify_webhook_url` in the background.
+    ///
+    /// This is best-effort: it never blocks the caller and failures are only
+    /// logged.
+    pub fn notify(&self, from: i32, to: i32, outcome: &str, error: Option<&str>) {
+        let Some(url) = self.config.notify_webhook_url.clone() else {
+            return;
+        };
+        if self.config.local_source_dir.is_some() {
+            return;
+        }
+
+        let body = match &self.config.notify_payload_template {
+            Some(template) => render_template(
+                template,
+                &[
+                    ("service", &self.config.service_name),
+                    ("from", &from.to_string()),
+                    ("to", &to.to_string()),
+                    ("outcome", outcome),
+                    ("error", error.unwrap_or("")),
+                ],
+            ),
+            None => serde_json::json!({
+                "service": self.config.service_name,
+                "from": from,
+                "to": to,
+                "outcome": outcome,
+                "error": error,
+            })
+            .to_string(),
+        };
+
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            let result = client
+                .post(&url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => tracing::debug!("Webhook notification sent to {}", url),
+                Err(e) => tracing::warn!("Webhook notification to {} failed: {}", url, e),
+            }
+        });
+    }
+
+    /// Posts the gzipped output of the update script of `version` to
+    /// `log_upload_api_url`; the device is identified by its token as in
+    /// every API request. Best effort: failures are only logged.
+    pub async fn upload_script_log(&self, version: i32, succeeded: bool, log: Vec<u8>) {
+        let Some(url) = &self.config.log_upload_api_url else {
+            return;
+        };
+        if self.config.local_source_dir.is_some() {
+            return;
+        }
+
+        tracing::info!(
+            "Uploading {} bytes of update script log to {}",
+            log.len(),
+            url
+        );
+        let outcome = if succeeded { "succeeded" } else { "failed" };
+        let version = version.to_string();
+        let result = self
+            .authorized(self.client.post(url))
+            .query(&[
+                ("service", self.config.service_name.as_str()),
+                ("versionCode", version.as_str()),
+                ("outcome", outcome),
+            ])
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(log)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => tracing::debug!("Update script log uploaded"),
+            Err(e) => tracing::warn!("Update script log upload to {} failed: {}", url, e),
+        }
+    }
+
     pub async fn report_status(
         &self,
         version_code: i32, // The version involved in the update attempt
         status_message: String,
     ) -> Result<(), UpdateError> {
-        let payload = StatusReportPayload {
-            version_code,
-            status_message,
-        };
+        self.send_status(StatusReportPayload::new(version_code, status_message))
+            .await
+    }
+
+    /// Sends a status report, retrying transient failures with exponential
+    /// backoff up to `status_report_retries` times.
+    pub async fn send_status(&self, mut payload: StatusReportPayload) -> Result<(), UpdateError> {
+        if self.config.local_source_dir.is_some() {
+            tracing::debug!(
+                "Offline update source, skipping status report: {:?}",
+                payload
+            );
+            return Ok(());
+        }
+
+        if let Some(command) = &self.config.device_metrics_command {
+            payload.network = device_metrics(command).await;
+        }
+        payload.cert_expires_in_days = *self.cert_expiry_warning.lock().unwrap();
+
+        if self.config.batch_status_reports {
+            tracing::info!("Queueing status for the next batch: {:?}", payload);
+            self.pending_status.lock().unwrap().push(payload);
+            return Ok(());
+        }
 
+        let min_interval = Duration::from_secs(self.config.status_report_min_interval_seconds);
+        {
+            let mut limiter = self.status_limiter.lock().unwrap();
+            if let Some(remaining) = self.throttle_remaining() {
+                tracing::debug!(
+                    "Server throttling for another {:?}, holding status report: {:?}",
+                    remaining,
+                    payload
+                );
+                limiter.hold(payload);
+                return Ok(());
+            }
+            if !payload.terminal
+                && limiter
+                    .last_sent
+                    .is_some_and(|sent| sent.elapsed() < min_interval)
+            {
+                tracing::debug!("Status report rate limited, holding it: {:?}", payload);
+                limiter.hold(payload);
+                return Ok(());
+            }
+            // Whatever was held is older than this report.
+            limiter.held = None;
+            limiter.last_sent = Some(Instant::now());
+        }
+        self.deliver_status(payload).await
+    }
+
+    async fn deliver_status(&self, payload: StatusReportPayload) -> Result<(), UpdateError> {
+        let mut policy = self.status_retry_policy();
+        match self.status_breaker_state() {
+            BreakerState::Open => {
+                tracing::debug!("Status breaker open, holding status report: {:?}", payload);
+                self.status_limiter.lock().unwrap().hold(payload);
+                return Ok(());
+            }
+            BreakerState::Probing => policy.max_attempts = 1,
+            BreakerState::Closed => {}
+        }
         tracing::info!(
             "Reporting status: {:?} to {}",
             payload,
             self.config.status_report_api_url
         );
 
-        let response = self
-            .client
-            .put(&self.config.status_report_api_url)
-            .header("device-token", &self.token)
-            .json(&payload)
-            .send()
-            .await?;
+        let url = &self.config.status_report_api_url;
+        let result = retry_with_backoff(&policy, "Status report", || {
+            self.send_status_once(url, &payload)
+        })
+        .await;
+        self.record_status_delivery(&result);
+        match result {
+            Ok(()) => {
+                tracing::info!("Status report successful");
+                Ok(())
+            }
+            Err(e) if payload.terminal => {
+                tracing::error!("Status report failed, keeping it for the next flush: {}", e);
+                self.status_limiter.lock().unwrap().hold(payload);
+                Err(e)
+            }
+            Err(e) => {
+                tracing::error!("Status report dropped: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends what is waiting at the end of a cycle: the final outcomes held
+    /// back, then the latest other report held back, then the queued batch.
+    pub async fn flush_status_reports(&self) -> Result<(), UpdateError> {
+        if let Some(remaining) = self.throttle_remaining() {
+            tracing::info!(
+                "Server throttling for another {:?}, keeping status reports for later",
+                remaining
+            );
+            return Ok(());
+        }
+        let held = {
+            let mut limiter = self.status_limiter.lock().unwrap();
+            let mut held = std::mem::take(&mut limiter.held_terminal);
+            held.extend(limiter.held.take());
+            if !held.is_empty() {
+                limiter.last_sent = Some(Instant::now());
+            }
+            held
+        };
+        let mut held = held.into_iter();
+        while let Some(payload) = held.next() {
+            if let Err(e) = self.deliver_status(payload).await {
+                // Whatever wasn't tried yet waits for the next flush.
+                let mut limiter = self.status_limiter.lock().unwrap();
+                held.for_each(|payload| limiter.hold(payload));
+                return Err(e);
+            }
+        }
+        self.flush_status_batch().await
+    }
+
+    /// Sends the queued status reports as one JSON array to
+    /// `status_batch_api_url`. On failure they stay queued for the next
+    /// flush, up to `MAX_PENDING_STATUS_REPORTS`.
+    async fn flush_status_batch(&self) -> Result<(), UpdateError> {
+        let mut policy = self.status_retry_policy();
+        match self.status_breaker_state() {
+            BreakerState::Open => {
+                tracing::debug!("Status breaker open, keeping batched reports queued");
+                return Ok(());
+            }
+            BreakerState::Probing => policy.max_attempts = 1,
+            BreakerState::Closed => {}
+        }
+        let batch = std::mem::take(&mut *self.pending_status.lock().unwrap());
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let url = self
+            .config
+            .status_batch_api_url
+            .as_ref()
+            .unwrap_or(&self.config.status_report_api_url);
+        tracing::info!("Reporting {} batched statuses to {}", batch.len(), url);
+
+        let result = retry_with_backoff(&policy, "Status batch", || {
+            self.send_status_once(url, &batch)
+        })
+        .await;
+        self.record_status_delivery(&result);
+        match result {
+            Ok(()) => {
+                tracing::info!("Status batch successful");
+                Ok(())
+            }
+            Err(e) => {
+                let mut pending = self.pending_status.lock().unwrap();
+                let mut batch = batch;
+                batch.append(&mut pending);
+                let dropped = batch.len().saturating_sub(MAX_PENDING_STATUS_REPORTS);
+                batch.drain(..dropped);
+                tracing::error!(
+                    "Status batch failed, keeping {} reports for the next flush ({} dropped): {}",
+                    batch.len(),
+                    dropped,
+                    e
+                );
+                *pending = batch;
+                Err(e)
+            }
+        }
+    }
+
+    fn status_retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.config.status_report_retries.saturating_add(1),
+            base_delay: STATUS_RETRY_BASE_DELAY,
+            multiplier: 2,
+            max_delay: STATUS_RETRY_MAX_DELAY,
+            // The same token will be rejected again, and a throttling server
+            // has said when to come back.
+            retryable: |e| {
+                !matches!(
+                    e,
+                    UpdateError::Unauthorized { .. } | UpdateError::Throttled { .. }
+                )
+            },
+        }
+    }
+
+    async fn send_status_once<T: Serialize + ?Sized>(
+        &self,
+        url: &str,
+        payload: &T,
+    ) -> Result<(), UpdateError> {
+        let request = self.authorized(self.client.put(url));
+        let request = match &self.config.hmac_secret {
+            Some(secret) => {
+                // The signature covers the exact bytes sent.
+                let body = serde_json::to_vec(payload).map_err(|e| {
+                    UpdateError::FileIOError(format!("Failed to serialize status report: {}", e))
+                })?;
+                let signature = signing::sign(secret, &body)?;
+                request
+                    .header(CONTENT_TYPE, "application/json")
+                    .header(signing::SIGNATURE_HEADER, signature.signature)
+                    .header(signing::TIMESTAMP_HEADER, signature.timestamp)
+                    .header(signing::NONCE_HEADER, signature.nonce)
+                    .body(body)
+            }
+            None => request.json(payload),
+        };
+        let response = match request.send().await {
+            Ok(response) => {
+                self.check_cert_expiry(&response);
+                response
+            }
+            Err(e) => {
+                self.capture_status_failure(url, payload, None, &e.to_string())
+                    .await;
+                return Err(e.into());
+            }
+        };
 
         if !response.status().is_success() {
             let status = response.status();
+            let retry_after_seconds = retry_after_seconds(&response);
             let error_message = response
                 .text()
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
-            tracing::error!(
+            self.capture_status_failure(url, payload, Some(status), &error_message)
+                .await;
+            tracing::debug!(
                 "Status report API request failed with status {}: {}",
                 status,
                 error_message
             );
+            if is_throttled(status) {
+                self.note_throttle("Status report", status, retry_after_seconds);
+                return Err(UpdateError::Throttled {
+                    status,
+                    retry_after_seconds,
+                });
+            }
+            if is_auth_failure(status) {
+                return Err(UpdateError::Unauthorized {
+                    status,
+                    message: error_message,
+                });
+            }
             return Err(UpdateError::ApiRequestFailed {
                 status,
                 message: error_message,
             });
         }
-        tracing::info!("Status report successful");
         Ok(())
     }
+
+    /// Appends a failed status report attempt to the debug capture file when
+    /// `debug_capture_status_failures` is set, keeping the last
+    /// `STATUS_FAILURES_KEPT` attempts. The device token is redacted. The
+    /// file is rewritten on the blocking pool through a temporary file, so a
+    /// crash never leaves it half written.
+    async fn capture_status_failure<T: Serialize + ?Sized>(
+        &self,
+        url: &str,
+        payload: &T,
+        status: Option<reqwest::StatusCode>,
+        body: &str,
+    ) {
+        if !self.config.debug_capture_status_failures {
+            return;
+        }
+
+        let timestamp = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        let record = serde_json::json!({
+            "timestamp": timestamp,
+            "url": url,
+            "payload": payload,
+            "status": status.map(|s| s.as_u16()),
+            "body": body,
+        })
+        .to_string();
+        let record = if self.token.is_empty() {
+            record
+        } else {
+            record.replace(&self.token, "[REDACTED]")
+        };
+
+        let path = self
+            .config
+            .download_base_dir
+            .join(STATUS_FAILURES_FILE_NAME);
+        tokio::task::spawn_blocking(move || {
+            // Captures of concurrent reports would otherwise drop each other.
+            static CAPTURE_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+            let _lock = CAPTURE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let existing = std::fs::read_to_string(&path).unwrap_or_default();
+            let mut lines: Vec<&str> = existing.lines().collect();
+            lines.push(&record);
+            let keep_from = lines.len().saturating_sub(STATUS_FAILURES_KEPT);
+            let mut data = lines[keep_from..].join("\n");
+            data.push('\n');
+            let tmp_path = path.with_extension("jsonl.tmp");
+            let written =
+                std::fs::write(&tmp_path, data).and_then(|_| std::fs::rename(&tmp_path, &path));
+            if let Err(e) = written {
+                tracing::warn!("Failed to capture status failure in {:?}: {}", path, e);
+            }
+        })
+        .await
+        .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::sync::{Arc, Mutex};
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    /// Serves every connection with the response `respond` builds from the
+    /// request head, then closes it. Returns the artifact URL and the request
+    /// heads seen so far.
+    async fn serve(respond: fn(&str) -> Vec<u8>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/update.zip", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut head = Vec::new();
+                let mut buf = [0; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => head.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let head = String::from_utf8_lossy(&head).to_lowercase();
+                let response = respond(&head);
+                seen.lock().unwrap().push(head);
+                stream.write_all(&response).await.ok();
+                stream.shutdown().await.ok();
+            }
+        });
+        (url, requests)
+    }
+
+    fn client() -> ApiClient {
+        ApiClient::new(test_support::config(""), String::new())
+    }
+
+    #[tokio::test]
+    async fn range_not_satisfiable_accepts_complete_file() {
+        let (url, requests) = serve(|head| {
+            let response = if head.starts_with("head ") {
+                "HTTP/1.1 200 OK\r\naccept-ranges: bytes\r\ncontent-length: 0\r\n\r\n"
+            } else if head.contains("range: bytes=10-") {
+                "HTTP/1.1 416 Range Not Satisfiable\r\ncontent-range: bytes */10\r\n\
+                 content-length: 0\r\n\r\n"
+            } else {
+                "HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nXXXXXXXXXX"
+            };
+            response.as_bytes().to_vec()
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.zip");
+        std::fs::write(&path, b"0123456789").unwrap();
+        DownloadState {
+            url: url.clone(),
+            total_size: None,
+            etag: None,
+            bytes_committed: 10,
+        }
+        .save(&path)
+        .await
+        .unwrap();
+
+        client().download_update(&url, &path, None).await.unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2, "{:?}", requests);
+        assert!(requests[1].contains("range: bytes=10-"));
+    }
+
+    #[tokio::test]
+    async fn early_close_keeps_partial_download_resumable() {
+        let (url, _) = serve(|head| {
+            let response = if head.starts_with("head ") {
+                "HTTP/1.1 200 OK\r\naccept-ranges: bytes\r\nx-content-length: 10\r\n\
+                 etag: \"v1\"\r\ncontent-length: 0\r\n\r\n"
+            } else {
+                // No Content-Length: the body ends when the connection closes.
+                "HTTP/1.1 200 OK\r\nconnection: close\r\n\r\n0123"
+            };
+            response.as_bytes().to_vec()
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.zip");
+
+        let result = client().download_update(&url, &path, None).await;
+
+        assert!(
+            matches!(
+                result,
+                Err(UpdateError::IncompleteDownload {
+                    received: 4,
+                    expected: 10
+                })
+            ),
+            "{:?}",
+            result
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123");
+        let state = DownloadState::load(&path).await.unwrap();
+        assert_eq!(state.bytes_committed, 4);
+        assert_eq!(
+            state.resume_mismatch(&url, Some(10), Some("\"v1\""), 4),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn manifest_on_another_origin_gets_no_device_token() {
+        let (url, requests) = serve(|head| {
+            let body = if head.starts_with("get /update.zip") {
+                // Same server, but `localhost` is another origin than `127.0.0.1`.
+                let port = head.split("host: 127.0.0.1:").nth(1).unwrap_or_default();
+                let port = port.lines().next().unwrap_or_default();
+                format!(r#"{{"manifestUrl": "http://localhost:{}/manifest"}}"#, port)
+            } else {
+                r#"{"versionCode": 7, "fileUrl": "http://localhost/update.zip"}"#.to_string()
+            };
+            format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                 content-length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .into_bytes()
+        })
+        .await;
+        let mut config = test_support::config("manifest_mode = true");
+        config.update_check_api_url = url;
+        let api = ApiClient::new(config, "secret-token".to_string());
+
+        let update_info = api.check_for_updates().await.unwrap();
+
+        assert_eq!(update_info.version_code, 7);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2, "{:?}", requests);
+        assert!(requests[0].contains("device-token: secret-token"));
+        assert!(!requests[1].contains("secret-token"), "{}", requests[1]);
+    }
+
+    #[test]
+    fn status_payload_skips_unset_fields() {
+        let json = serde_json::to_value(StatusReportPayload::new(7, "done".to_string())).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "versionCode": 7, "statusMessage": "done" })
+        );
+    }
+
+    #[test]
+    fn status_payload_renames_fields() {
+        let payload = StatusReportPayload::new(7, "installed".to_string())
+            .with_transition(6, 7)
+            .with_release_notes(Some("fixes"));
+        let json = serde_json::to_value(payload).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "versionCode": 7,
+                "statusMessage": "installed",
+                "fromVersion": 6,
+                "toVersion": 7,
+                "releaseNotes": "fixes",
+            })
+        );
+    }
+
+    #[test]
+    fn status_payload_tags_failure_category() {
+        let payload = StatusReportPayload::new(7, "failed".to_string())
+            .with_failure_category(&UpdateError::ScriptDiskFull("no space".to_string()));
+        let json = serde_json::to_value(payload).unwrap();
+        assert_eq!(json["failureCategory"], "disk_full");
+    }
 }
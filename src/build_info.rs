@@ -0,0 +1,79 @@
+use crate::config::Config;
+use crate::error::UpdateError;
+use crate::extract::{self, ArchiveSource};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+/// File at the root of an archive describing the build it holds.
+pub const BUILD_INFO_FILE_NAME: &str = "build-info.json";
+
+/// File under `download_base_dir` recording the build installed last.
+pub const INSTALLED_BUILD_INFO_FILE_NAME: &str = "installed_build_info.json";
+
+/// Contents of `build-info.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// Build time, in seconds since the Unix epoch.
+    pub timestamp: i64,
+    pub version: i32,
+}
+
+impl BuildInfo {
+    /// Reads `build-info.json` from the update archive.
+    pub fn from_archive(cfg: &Config, archive: &ArchiveSource) -> Result<Self, UpdateError> {
+        let data =
+            extract::read_archive_file(cfg, archive, BUILD_INFO_FILE_NAME)?.ok_or_else(|| {
+                UpdateError::ArchiveError(format!(
+                    "verify_build_info is set but the archive has no {}",
+                    BUILD_INFO_FILE_NAME
+                ))
+            })?;
+        serde_json::from_slice(&data).map_err(|e| {
+            UpdateError::ArchiveError(format!("Invalid {}: {}", BUILD_INFO_FILE_NAME, e))
+        })
+    }
+
+    /// The build installed last, if it was recorded.
+    pub fn installed(base_dir: &Path) -> Option<Self> {
+        let path = base_dir.join(INSTALLED_BUILD_INFO_FILE_NAME);
+        let data = fs::read(&path).ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(info) => Some(info),
+            Err(e) => {
+                tracing::warn!("Ignoring corrupt build record {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Refuses a build that isn't the offered `version`, or that was built
+    /// before the installed one: an old artifact re-released by mistake.
+    pub fn check(&self, base_dir: &Path, version: i32) -> Result<(), UpdateError> {
+        if self.version != version {
+            return Err(UpdateError::StaleBuild(format!(
+                "{} names version {}, but the archive was offered as version {}",
+                BUILD_INFO_FILE_NAME, self.version, version
+            )));
+        }
+        if let Some(installed) = Self::installed(base_dir) {
+            if self.timestamp < installed.timestamp {
+                return Err(UpdateError::StaleBuild(format!(
+                    "version {} was built at {}, before the installed version {} (built at {})",
+                    self.version, self.timestamp, installed.version, installed.timestamp
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Records this build as the one installed.
+    pub fn record_installed(&self, base_dir: &Path) -> Result<(), UpdateError> {
+        let path = base_dir.join(INSTALLED_BUILD_INFO_FILE_NAME);
+        let data = serde_json::to_vec(self).map_err(|e| {
+            UpdateError::FileIOError(format!("Failed to serialize build record: {}", e))
+        })?;
+        fs::write(&path, data).map_err(|e| {
+            UpdateError::FileIOError(format!("Failed to write build record {:?}: {}", path, e))
+        })
+    }
+}
@@ -0,0 +1,151 @@
+use crate::api_client::UpdateInfo;
+use crate::config::Config;
+use crate::crypto;
+use crate::error::UpdateError;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Verified artifacts kept under `cache_dir` so a version fetched before is
+/// not downloaded again, e.g. when rolling forward after a rollback. Entries
+/// are keyed by version and checksum; the least recently used ones are
+/// evicted beyond `cache_max_bytes`. Artifacts without a checksum are not
+/// cached, since a hit could not be verified.
+///
+/// Fetching and storing copy whole artifacts and block while doing so; code
+/// on the async runtime runs them on the blocking pool.
+pub struct ArtifactCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl ArtifactCache {
+    pub fn from_config(cfg: &Config) -> Option<Self> {
+        cfg.cache_dir.clone().map(|dir| ArtifactCache {
+            dir,
+            max_bytes: cfg.cache_max_bytes,
+        })
+    }
+
+    fn entry_path(&self, update_info: &UpdateInfo) -> Option<PathBuf> {
+        let checksum = update_info.checksum.as_deref()?.trim().to_ascii_lowercase();
+        if checksum.is_empty() || !checksum.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(
+            self.dir
+                .join(format!("v{}-{}.zip", update_info.version_code, checksum)),
+        )
+    }
+
+    /// Copies the cached artifact of `update_info` to `download_path` if
+    /// there is one and it still matches its checksum. A tampered or corrupt
+    /// entry is removed.
+    pub fn fetch(&self, update_info: &UpdateInfo, download_path: &Path) -> bool {
+        let (Some(path), Some(checksum)) = (self.entry_path(update_info), &update_info.checksum)
+        else {
+            return false;
+        };
+        if !path.exists() {
+            return false;
+        }
+        if let Err(e) = crypto::verify_sha256(&path, checksum) {
+            tracing::warn!("Discarding cached artifact {:?}: {}", path, e);
+            if let Err(e) = fs::remove_file(&path) {
+                tracing::warn!("Failed to remove cached artifact {:?}: {}", path, e);
+            }
+            return false;
+        }
+        if let Err(e) = fs::copy(&path, download_path) {
+            tracing::warn!("Failed to copy cached artifact {:?}: {}", path, e);
+            return false;
+        }
+        // The modification time orders entries for eviction.
+        if let Err(e) = fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+        {
+            tracing::debug!("Failed to mark {:?} as recently used: {}", path, e);
+        }
+        tracing::info!(
+            "Using cached artifact {:?} for version {}",
+            path,
+            update_info.version_code
+        );
+        true
+    }
+
+    /// Adds a verified artifact to the cache, then evicts the least recently
+    /// used entries until the cache fits `cache_max_bytes`.
+    pub fn store(&self, update_info: &UpdateInfo, download_path: &Path) {
+        let Some(path) = self.entry_path(update_info) else {
+            return;
+        };
+        if path.exists() {
+            return;
+        }
+        let size = fs::metadata(download_path).map(|m| m.len()).unwrap_or(0);
+        if size > self.max_bytes {
+            tracing::debug!(
+                "Not caching version {}: {} bytes exceed cache_max_bytes",
+                update_info.version_code,
+                size
+            );
+            return;
+        }
+
+        let tmp_path = path.with_extension("zip.tmp");
+        let result = fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::copy(download_path, &tmp_path))
+            .and_then(|_| fs::rename(&tmp_path, &path));
+        match result {
+            Ok(()) => tracing::info!(
+                "Cached artifact of version {} as {:?}",
+                update_info.version_code,
+                path
+            ),
+            Err(e) => {
+                tracing::warn!("Failed to cache artifact as {:?}: {}", path, e);
+                fs::remove_file(&tmp_path).ok();
+                return;
+            }
+        }
+        if let Err(e) = self.evict() {
+            tracing::warn!("{}", e);
+        }
+    }
+
+    fn evict(&self) -> Result<(), UpdateError> {
+        let entries = fs::read_dir(&self.dir).map_err(|e| {
+            UpdateError::FileSystemError(format!("Failed to list cache {:?}: {}", self.dir, e))
+        })?;
+        let mut cached: Vec<(SystemTime, u64, PathBuf)> = entries
+            .flatten()
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "zip"))
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+            })
+            .collect();
+        cached.sort();
+
+        let mut total: u64 = cached.iter().map(|(_, size, _)| size).sum();
+        for (_, size, path) in cached {
+            if total <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(&path).map_err(|e| {
+                UpdateError::FileSystemError(format!(
+                    "Failed to evict cached artifact {:?}: {}",
+                    path, e
+                ))
+            })?;
+            tracing::info!("Evicted cached artifact {:?}", path);
+            total -= size;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,25 @@
+use crate::config::Config;
+use sha2::{Digest, Sha256};
+use std::fs;
+
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
+
+/// Identity hashed into the canary bucket: `device_id`, else the machine id,
+/// else the device token.
+fn device_identity(cfg: &Config) -> String {
+    if let Some(id) = &cfg.device_id {
+        return id.clone();
+    }
+    match fs::read_to_string(MACHINE_ID_PATH) {
+        Ok(id) if !id.trim().is_empty() => id.trim().to_string(),
+        _ => cfg.device_token.clone(),
+    }
+}
+
+/// The device's place in `0..100`, the same on every poll.
+pub fn device_bucket(cfg: &Config) -> u64 {
+    let digest = Sha256::digest(device_identity(cfg).as_bytes());
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(prefix) % 100
+}
@@ -0,0 +1,126 @@
+use crate::error::UpdateError;
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    signal::unix::{signal, SignalKind},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Lets an operator abort the update in progress, without stopping the
+/// updater, by sending it `SIGUSR1` or `CANCEL` over `control_socket_path`.
+/// Only the current cycle is cancelled.
+#[derive(Clone, Default)]
+pub struct OperatorCancel {
+    current: Arc<Mutex<CancellationToken>>,
+}
+
+impl OperatorCancel {
+    /// Starts listening for `SIGUSR1`, and for commands on `socket` when
+    /// given. When neither can be set up cancellation is unavailable, which
+    /// is logged.
+    pub fn listen(socket: Option<&Path>) -> Self {
+        let cancel = OperatorCancel::default();
+        if let Some(path) = socket {
+            cancel.listen_socket(path);
+        }
+        let mut signals = match signal(SignalKind::user_defined1()) {
+            Ok(signals) => signals,
+            Err(e) => {
+                tracing::warn!("Cannot listen for SIGUSR1: {}", e);
+                return cancel;
+            }
+        };
+
+        let current = Arc::clone(&cancel.current);
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                tracing::warn!("SIGUSR1 received, cancelling the update in progress");
+                current.lock().unwrap().cancel();
+            }
+        });
+        cancel
+    }
+
+    /// Accepts connections on the Unix socket at `path`, replacing one left
+    /// behind by an earlier run.
+    fn listen_socket(&self, path: &Path) {
+        fs::remove_file(path).ok();
+        let listener = match UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::warn!("Cannot listen on control socket {:?}: {}", path, e);
+                return;
+            }
+        };
+        tracing::info!("Listening for operator commands on {:?}", path);
+
+        let current = Arc::clone(&self.current);
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_control(stream, Arc::clone(&current)));
+                    }
+                    Err(e) => tracing::warn!("Control socket accept failed: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Token for the cycle about to start, so a cancellation that arrived
+    /// between cycles doesn't carry over.
+    pub fn next_cycle(&self) -> CancellationToken {
+        let token = CancellationToken::new();
+        *self.current.lock().unwrap() = token.clone();
+        token
+    }
+}
+
+/// Answers the line-based commands of one control socket connection:
+/// `CANCEL` aborts the update in progress.
+async fn serve_control(stream: UnixStream, current: Arc<Mutex<CancellationToken>>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let reply = match line.trim() {
+            "CANCEL" => {
+                tracing::warn!("CANCEL received, cancelling the update in progress");
+                current.lock().unwrap().cancel();
+                "OK\n".to_string()
+            }
+            command => format!("ERR unknown command {:?}\n", command),
+        };
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Runs the blocking `work` on the blocking pool with a child of `cancel`.
+/// The child is cancelled when this future is dropped, e.g. by the cycle
+/// timeout, so what `work` started is stopped rather than left behind.
+pub async fn run_blocking<T, F>(
+    what: &str,
+    cancel: &CancellationToken,
+    work: F,
+) -> Result<T, UpdateError>
+where
+    T: Send + 'static,
+    F: FnOnce(&CancellationToken) -> Result<T, UpdateError> + Send + 'static,
+{
+    let cancel = cancel.child_token();
+    let _cancel_on_drop = cancel.clone().drop_guard();
+    tokio::task::spawn_blocking(move || work(&cancel))
+        .await
+        .unwrap_or_else(|e| {
+            Err(UpdateError::HookError(format!(
+                "{} task failed: {}",
+                what, e
+            )))
+        })
+}
@@ -0,0 +1,18 @@
+use openssl::{asn1::Asn1Time, x509::X509};
+
+/// Whole days until the DER-encoded certificate `der` expires, negative once
+/// it has. `None` when it can't be parsed.
+pub fn days_until_expiry(der: &[u8]) -> Option<i32> {
+    let cert = match X509::from_der(der) {
+        Ok(cert) => cert,
+        Err(e) => {
+            tracing::debug!("Cannot parse the server certificate: {}", e);
+            return None;
+        }
+    };
+    let diff = Asn1Time::days_from_now(0)
+        .and_then(|now| now.diff(cert.not_after()))
+        .map_err(|e| tracing::debug!("Cannot compare certificate expiry: {}", e))
+        .ok()?;
+    Some(diff.days)
+}
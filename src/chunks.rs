@@ -0,0 +1,94 @@
+use crate::error::UpdateError;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// SHA-256 of each `chunk_size` slice of an artifact, the last one possibly
+/// shorter, published with the update so a download is verified as it
+/// arrives instead of only once it is complete.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChunkHashes {
+    #[serde(rename = "chunkSize")]
+    pub chunk_size: u64,
+    /// Hex-encoded, in file order.
+    pub hashes: Vec<String>,
+}
+
+impl ChunkHashes {
+    /// Start of the chunk holding byte `offset`.
+    pub fn chunk_start(&self, offset: u64) -> u64 {
+        offset - offset % self.chunk_size
+    }
+}
+
+/// Hashes downloaded bytes chunk by chunk and checks every completed chunk
+/// against its published hash.
+pub struct ChunkVerifier<'a> {
+    chunks: &'a ChunkHashes,
+    /// Chunk being filled.
+    index: u64,
+    filled: u64,
+    hasher: Sha256,
+}
+
+impl<'a> ChunkVerifier<'a> {
+    /// Verifier for a download continuing at `offset`, which must be the
+    /// start of a chunk.
+    pub fn new(chunks: &'a ChunkHashes, offset: u64) -> Self {
+        debug_assert_eq!(chunks.chunk_start(offset), offset);
+        ChunkVerifier {
+            chunks,
+            index: offset / chunks.chunk_size,
+            filled: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Feeds bytes as they are written. Fails with `ChunkMismatch` on the
+    /// first chunk that doesn't match, whose start the download must be cut
+    /// back to.
+    pub fn update(&mut self, mut data: &[u8]) -> Result<(), UpdateError> {
+        while !data.is_empty() {
+            let take = data
+                .len()
+                .min((self.chunks.chunk_size - self.filled) as usize);
+            self.hasher.update(&data[..take]);
+            self.filled += take as u64;
+            data = &data[take..];
+            if self.filled == self.chunks.chunk_size {
+                self.check_chunk()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the trailing, shorter chunk once the download has ended.
+    pub fn finish(&mut self) -> Result<(), UpdateError> {
+        if self.filled > 0 {
+            self.check_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn check_chunk(&mut self) -> Result<(), UpdateError> {
+        let actual = hex::encode(std::mem::take(&mut self.hasher).finalize());
+        let offset = self.index * self.chunks.chunk_size;
+        let expected = self.chunks.hashes.get(self.index as usize);
+        if !expected.is_some_and(|expected| actual.eq_ignore_ascii_case(expected.trim())) {
+            tracing::warn!(
+                "Chunk {} at byte {} does not match its hash (expected {:?}, got {})",
+                self.index,
+                offset,
+                expected,
+                actual
+            );
+            self.filled = 0;
+            return Err(UpdateError::ChunkMismatch {
+                index: self.index,
+                offset,
+            });
+        }
+        self.index += 1;
+        self.filled = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+/// Command line flags accepted by the updater.
+#[derive(Debug, Default)]
+pub struct CliArgs {
+    pub selftest: bool,
+    pub version: bool,
+    /// Print the update the next cycle would apply as JSON and exit.
+    pub plan: bool,
+    /// Forget failed attempts so a quarantined version is retried.
+    pub clear_quarantine: bool,
+    /// Wipe `download_base_dir`, then reinstall the latest version in one
+    /// cycle and exit.
+    pub recover: bool,
+    /// Directory to take updates from instead of the update server.
+    pub local_source: Option<PathBuf>,
+    /// Archive whose entries are listed before exiting.
+    pub inspect: Option<PathBuf>,
+    /// Follow each cycle on a single status line on stdout; logs written
+    /// to stdout go to stderr instead.
+    pub tail_status: bool,
+    /// Version whose update script environment is printed, secrets
+    /// redacted, before exiting.
+    pub show_script_env: Option<i32>,
+}
+
+impl CliArgs {
+    pub fn parse() -> Result<Self, String> {
+        Self::parse_from(std::env::args().skip(1))
+    }
+
+    fn parse_from(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut cli = CliArgs::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--selftest" => cli.selftest = true,
+                "--version" => cli.version = true,
+                "--plan" => cli.plan = true,
+                "--clear-quarantine" => cli.clear_quarantine = true,
+                "--recover" => cli.recover = true,
+                "--tail-status" => cli.tail_status = true,
+                "--local-source" => {
+                    let dir = args
+                        .next()
+                        .ok_or_else(|| "--local-source requires a directory".to_string())?;
+                    cli.local_source = Some(PathBuf::from(dir));
+                }
+                "--inspect" => {
+                    let archive = args
+                        .next()
+                        .ok_or_else(|| "--inspect requires an archive path".to_string())?;
+                    cli.inspect = Some(PathBuf::from(archive));
+                }
+                "--show-script-env" => {
+                    let version = args
+                        .next()
+                        .ok_or_else(|| "--show-script-env requires a version".to_string())?;
+                    cli.show_script_env = Some(version.parse().map_err(|_| {
+                        format!("--show-script-env: '{}' is not a version", version)
+                    })?);
+                }
+                other => return Err(format!("unknown argument '{}'", other)),
+            }
+        }
+        Ok(cli)
+    }
+}
+
+pub const USAGE: &str =
+    "usage: podbox_update [--version] [--selftest] [--plan] [--clear-quarantine] [--recover] [--tail-status] [--local-source <dir>] [--inspect <archive>] [--show-script-env <version>]";
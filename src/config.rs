@@ -1,28 +1,624 @@
+use crate::ab::AbSlots;
+use crate::crypto::EncryptionFormat;
+use crate::defaults;
+use crate::durable;
 use crate::error::UpdateError;
+use crate::extract::{EntryFilter, ExtractPolicy};
+use crate::hooks;
+use crate::script::IoniceClass;
+use crate::version_history;
 use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::CString;
 use std::fs;
-use std::path::PathBuf;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Where the value of a secret passed to the update script comes from.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretSource {
+    Inline(String),
+    File(PathBuf),
+    Env(String),
+}
+
+impl SecretSource {
+    pub fn resolve(&self) -> Result<String, UpdateError> {
+        match self {
+            SecretSource::Inline(value) => Ok(value.clone()),
+            SecretSource::File(path) => fs::read_to_string(path)
+                .map(|s| s.trim_end_matches(['\r', '\n']).to_string())
+                .map_err(|e| {
+                    UpdateError::ConfigError(format!(
+                        "Failed to read secret file {:?}: {}",
+                        path, e
+                    ))
+                }),
+            SecretSource::Env(name) => std::env::var(name).map_err(|e| {
+                UpdateError::ConfigError(format!(
+                    "Failed to read secret from environment variable '{}': {}",
+                    name, e
+                ))
+            }),
+        }
+    }
+}
+
+/// What happens when `current_version_command` fails or prints no version.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum VersionCommandFailure {
+    /// Warn and assume version 0, as for a missing version file.
+    #[default]
+    AssumeZero,
+    /// Skip the cycle.
+    Error,
+}
+
+/// How `current_version_command` output is read as a version number.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum VersionScheme {
+    /// A plain integer, as in the version file (`42`).
+    #[default]
+    Integer,
+    /// `major.minor.patch` (`1.2.3`), numbered
+    /// `major * 1_000_000 + minor * 1_000 + patch` to compare with the
+    /// versions the server offers; minor and patch must stay below 1000.
+    Semver,
+}
+
+/// What happens to updates while the installed version is 0, i.e. on first
+/// boot before any version was recorded.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FirstBootPolicy {
+    /// Install whatever the server offers, as for any other version.
+    #[default]
+    Apply,
+    /// Download and verify the offered version, then record it as installed
+    /// without extracting it or running its script: the factory image is
+    /// taken to be that version already.
+    #[serde(alias = "record-only")]
+    RecordOnly,
+    /// Don't update until a version was recorded by other means.
+    Skip,
+}
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub service_name: String,
     pub current_version_file: PathBuf,
+    /// Shell command printing the installed version, used instead of
+    /// `current_version_file` when set.
+    #[serde(default)]
+    pub current_version_command: Option<String>,
+    #[serde(default)]
+    pub version_command_failure: VersionCommandFailure,
+    /// How the `current_version_command` output is parsed.
+    #[serde(default)]
+    pub version_scheme: VersionScheme,
+    #[serde(default)]
+    pub first_boot_policy: FirstBootPolicy,
     pub update_check_api_url: String,
+    /// Warn, and add `certExpiresInDays` to status reports, once the update
+    /// server's certificate expires within this many days; 0 disables.
+    #[serde(default)]
+    pub cert_expiry_warn_days: u32,
+    /// Stable device identifier placing the device in a canary cohort;
+    /// `/etc/machine-id` (or the device token) when unset.
+    #[serde(default)]
+    pub device_id: Option<String>,
+    /// Hardware model of the device, sent to the check endpoint as `model`
+    /// and matched against the `compatibleModels` of an update.
+    #[serde(default)]
+    pub device_model: Option<String>,
     pub status_report_api_url: String,
     pub poll_interval_seconds: u64,
     pub download_base_dir: PathBuf,
     pub decryption_key_hex: String,
+    /// Further keys tried after `decryption_key_hex`, so payloads encrypted
+    /// with the old or the new key both decrypt while a key is rotated. With
+    /// `header_key_id` payloads the key is picked by its id.
+    #[serde(default)]
+    pub decryption_keys_hex: Vec<String>,
     pub update_script_name: String,
+    /// Directory of the extracted tree whose files are run in lexical order
+    /// (e.g. `10-pre-migrate.sh`, `20-migrate.sh`) instead of
+    /// `update_script_name`, when the archive has it. A failing step stops
+    /// the update, but only `protected_paths` and a replaced tree are rolled
+    /// back; changes earlier steps made elsewhere remain.
+    #[serde(default)]
+    pub update_scripts_dir: Option<String>,
+    /// Report "running update script" right before the script starts, not
+    /// only once it finished.
+    #[serde(default)]
+    pub report_script_start: bool,
+    /// Legacy secret passed as `DB_PASSWORD` when `script_secret_env` is empty.
+    #[serde(default)]
     pub db_password: String,
     pub device_token: String,
+    /// Header carrying `device_token` in API requests.
+    #[serde(default = "defaults::device_token_header")]
+    pub device_token_header: String,
+    /// Send `device_token` as `Authorization: Bearer <token>` instead.
+    #[serde(default)]
+    pub device_token_bearer: bool,
+    /// Shell command run before each cycle; a nonzero exit skips the cycle.
+    #[serde(default)]
+    pub precheck_command: Option<String>,
+    /// Shell command run before the update script; a nonzero exit (low
+    /// battery) defers the staged update to the next cycle.
+    #[serde(default)]
+    pub battery_precheck_command: Option<String>,
+    /// How long an operator-supplied probe or hook command (prechecks and
+    /// the like) may run before it is killed and counts as an error.
+    #[serde(default = "defaults::hook_timeout_seconds")]
+    pub hook_timeout_seconds: u64,
+    /// Shell command scanning the extracted update (e.g. for viruses) before
+    /// any of its scripts runs; `{path}` is replaced with the quoted path of
+    /// the extracted tree, which is still the staging directory when
+    /// extraction stages. A nonzero exit means the scan failed.
+    #[serde(default)]
+    pub scan_command: Option<String>,
+    /// How long `scan_command` may run before it is killed and counts as
+    /// failed.
+    #[serde(default = "defaults::scan_timeout_seconds")]
+    pub scan_timeout_seconds: u64,
+    /// Whether a failed scan aborts the update; otherwise it is only reported.
+    #[serde(default = "defaults::enabled")]
+    pub scan_failure_fatal: bool,
+    /// Memory (`MemAvailable`) needed to run the update script; the update
+    /// stays staged until a cycle finds enough. 0 disables the check.
+    #[serde(default)]
+    pub min_free_memory_bytes: u64,
+    /// Fail the update instead of deferring it when memory is short.
+    #[serde(default)]
+    pub insufficient_memory_fatal: bool,
+    /// Shell command printing JSON metrics (signal, carrier, interface)
+    /// attached to each status report under `network`.
+    #[serde(default)]
+    pub device_metrics_command: Option<String>,
+    /// While this file exists, cycles are skipped without checking for updates.
+    #[serde(default)]
+    pub pause_sentinel_path: Option<PathBuf>,
+    /// Unix socket taking operator commands, read at startup: `CANCEL`
+    /// aborts the update in progress as `SIGUSR1` does.
+    #[serde(default)]
+    pub control_socket_path: Option<PathBuf>,
+    /// Skip updates while `service_name` isn't installed and enabled.
+    #[serde(default)]
+    pub check_service_enabled: bool,
+    /// Probe for `check_service_enabled`, exiting 0 when the service is
+    /// enabled; `{service}` is replaced with `service_name`. Defaults to
+    /// `systemctl is-enabled --quiet {service}`.
+    #[serde(default)]
+    pub service_check_command: Option<String>,
+    /// When set, the check endpoint returns a manifest URL that is fetched separately.
+    #[serde(default)]
+    pub manifest_mode: bool,
+    /// Environment variables injected into the update script, by name.
+    #[serde(default)]
+    pub script_secret_env: BTreeMap<String, SecretSource>,
+    /// Upper bound on a single update cycle, including the download.
+    #[serde(default = "defaults::cycle_timeout_seconds")]
+    pub cycle_timeout_seconds: u64,
+    /// Downloads are AES-256-GCM encrypted with `decryption_key_hex`.
+    #[serde(default)]
+    pub encrypted_updates: bool,
+    #[serde(default)]
+    pub encryption_format: EncryptionFormat,
+    /// Longest gap between received chunks before a download is considered dead.
+    #[serde(default = "defaults::download_inactivity_timeout_seconds")]
+    pub download_inactivity_timeout_seconds: u64,
+    /// Versions below this floor are never installed, whatever the server says.
+    #[serde(default)]
+    pub minimum_version: i32,
+    /// Webhook notified when an update succeeds or fails, or the device token
+    /// is rejected.
+    #[serde(default)]
+    pub notify_webhook_url: Option<String>,
+    /// Payload template for the webhook; `{{service}}`, `{{from}}`, `{{to}}`,
+    /// `{{outcome}}` and `{{error}}` are substituted with JSON-escaped values.
+    #[serde(default)]
+    pub notify_payload_template: Option<String>,
+    /// Largest allowed uncompressed:compressed ratio of an archive entry; 0 disables the check.
+    #[serde(default = "defaults::max_compression_ratio")]
+    pub max_compression_ratio: u64,
+    /// Sync the clock once at startup before the first cycle.
+    #[serde(default = "defaults::enabled")]
+    pub enable_time_sync: bool,
+    /// Shell command used to sync the clock; defaults to restarting ntp via sudo.
+    #[serde(default)]
+    pub time_sync_command: Option<String>,
+    /// Negotiate gzip/brotli for check and status responses (never for downloads).
+    #[serde(default = "defaults::enabled")]
+    pub compress_api_responses: bool,
+    /// Re-check for updates during a download and switch to a newer version if one appears.
+    #[serde(default)]
+    pub abort_superseded_downloads: bool,
+    #[serde(default = "defaults::supersede_check_interval_seconds")]
+    pub supersede_check_interval_seconds: u64,
+    /// Extra attempts for a failed status report, with exponential backoff.
+    #[serde(default = "defaults::status_report_retries")]
+    pub status_report_retries: u32,
+    /// Failed status reports in a row after which reports are held instead
+    /// of sent for `status_breaker_cooldown_seconds`. 0 disables this.
+    #[serde(default = "defaults::status_breaker_threshold")]
+    pub status_breaker_threshold: u32,
+    #[serde(default = "defaults::status_breaker_cooldown_seconds")]
+    pub status_breaker_cooldown_seconds: u64,
+    /// Minimum time between two status reports. Progress reports arriving
+    /// sooner are held back, only the latest being sent at the end of the
+    /// cycle; final outcomes are always sent at once. 0 disables the limit.
+    #[serde(default)]
+    pub status_report_min_interval_seconds: u64,
+    /// Collect the status reports of a cycle and send them as one JSON array
+    /// when it ends, instead of one request each.
+    #[serde(default)]
+    pub batch_status_reports: bool,
+    /// Endpoint receiving batched reports; `status_report_api_url` if unset.
+    #[serde(default)]
+    pub status_batch_api_url: Option<String>,
+    /// Take updates from this directory (e.g. a USB drive) instead of the
+    /// server; status reports and notifications are skipped.
+    #[serde(default)]
+    pub local_source_dir: Option<PathBuf>,
+    /// Extra attempts at writing an archive entry after a transient IO error.
+    #[serde(default = "defaults::extract_io_retries")]
+    pub extract_io_retries: u32,
+    /// Size of the buffer archive entries are copied through.
+    #[serde(default = "defaults::extract_buffer_bytes")]
+    pub extract_buffer_bytes: usize,
+    /// How long an idle keep-alive connection to the server is kept; 0
+    /// disables connection reuse.
+    #[serde(default = "defaults::pool_idle_timeout_seconds")]
+    pub pool_idle_timeout_seconds: u64,
+    /// How extraction treats an existing tree at the target directory.
+    #[serde(default)]
+    pub extract_policy: ExtractPolicy,
+    /// Record extraction progress next to the target so an interrupted
+    /// extraction of the same archive resumes instead of starting over.
+    #[serde(default)]
+    pub resume_extraction: bool,
+    /// Extract the contents of the archive's single top-level directory
+    /// (e.g. `release-v1.2.3/`) directly into the target.
+    #[serde(default)]
+    pub strip_top_level_dir: bool,
+    /// fsync the extracted tree before the update script runs, and the
+    /// version file once it succeeded, so a reported update survives a power
+    /// cut.
+    #[serde(default = "defaults::enabled")]
+    pub durable_writes: bool,
+    /// After extracting, compare the size of the written files with the
+    /// sizes the archive declares.
+    #[serde(default)]
+    pub verify_extracted_size: bool,
+    /// Accepted difference for `verify_extracted_size`.
+    #[serde(default)]
+    pub extracted_size_tolerance_bytes: u64,
+    /// Refuse update scripts that have neither a `#!` line nor an ELF header.
+    #[serde(default = "defaults::enabled")]
+    pub require_script_shebang: bool,
+    /// Treat a download whose byte count differs from the advertised size as
+    /// incomplete instead of finished.
+    #[serde(default = "defaults::enabled")]
+    pub verify_download_size: bool,
+    /// When non-empty, only archive entries matching one of these globs are extracted.
+    #[serde(default)]
+    pub extract_include_globs: Vec<String>,
+    /// Archive entries matching one of these globs are never extracted.
+    #[serde(default)]
+    pub extract_exclude_globs: Vec<String>,
+    /// Keep the downloaded archive as `<name>.v<version>.zip` after extraction
+    /// instead of deleting it.
+    #[serde(default)]
+    pub keep_downloaded_archive: bool,
+    /// Artifacts advertised (`size`) at or below this many bytes are
+    /// downloaded, verified, decrypted and extracted in memory, so the
+    /// archive itself is never written to flash. Not used with
+    /// `keep_downloaded_archive` or `cache_dir`, which need it on disk.
+    /// 0 disables this.
+    #[serde(default)]
+    pub in_memory_threshold_bytes: u64,
+    /// Keep verified artifacts here, keyed by version and checksum, and
+    /// reuse them instead of downloading the same version again.
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+    /// Size limit of `cache_dir`; least recently used artifacts are evicted.
+    #[serde(default = "defaults::cache_max_bytes")]
+    pub cache_max_bytes: u64,
+    /// Fixed wait before the first update check after starting.
+    #[serde(default)]
+    pub startup_delay_seconds: u64,
+    /// Upper bound of a random wait added to `startup_delay_seconds`, so a
+    /// fleet booting together doesn't check in lockstep.
+    #[serde(default)]
+    pub startup_jitter_seconds: u64,
+    /// Exit cleanly after this many update cycles so a supervisor restarts
+    /// the process; 0 runs forever.
+    #[serde(default)]
+    pub max_cycles: u64,
+    /// Nice value (-20..=19) the update script runs with.
+    #[serde(default)]
+    pub script_nice: Option<i32>,
+    /// IO scheduling class the update script runs with.
+    #[serde(default)]
+    pub script_ionice_class: Option<IoniceClass>,
+    /// Bounds applied to a poll interval requested by the server.
+    #[serde(default = "defaults::min_poll_interval_seconds")]
+    pub min_poll_interval_seconds: u64,
+    #[serde(default = "defaults::max_poll_interval_seconds")]
+    pub max_poll_interval_seconds: u64,
+    /// Only an update script with this hex SHA-256 is executed.
+    #[serde(default)]
+    pub allowed_script_sha256: Option<String>,
+    /// Hex SHA-256 of every other script an archive may run (commit script,
+    /// files of `update_scripts_dir`), by path within the extracted tree.
+    /// Once any script is pinned, scripts without a pin are refused.
+    #[serde(default)]
+    pub allowed_scripts_sha256: BTreeMap<String, String>,
+    /// Keep the last failed status reports (payload, status, response body)
+    /// in `status_failures.jsonl` under `download_base_dir`.
+    #[serde(default)]
+    pub debug_capture_status_failures: bool,
+    /// Run the update script as `<interpreter> <script>` instead of executing
+    /// it directly.
+    #[serde(default)]
+    pub script_interpreter: Option<PathBuf>,
+    /// Reload the config file before a cycle when its mtime changed.
+    #[serde(default)]
+    pub watch_config: bool,
+    /// Endpoint the gzipped output of the update script is posted to after
+    /// each run, for the backend to keep.
+    #[serde(default)]
+    pub log_upload_api_url: Option<String>,
+    /// Most script output uploaded; earlier output is dropped beyond it.
+    #[serde(default = "defaults::log_upload_max_bytes")]
+    pub log_upload_max_bytes: usize,
+    /// Reboot the device after an update was installed.
+    #[serde(default)]
+    pub reboot_after_update: bool,
+    /// Shell command rebooting the device; defaults to
+    /// `sudo systemctl reboot`.
+    #[serde(default)]
+    pub reboot_command: Option<String>,
+    /// Shell command exiting 0 while the device is idle; the reboot waits for
+    /// it, up to `reboot_max_wait_seconds`.
+    #[serde(default)]
+    pub reboot_idle_command: Option<String>,
+    #[serde(default = "defaults::reboot_max_wait_seconds")]
+    pub reboot_max_wait_seconds: u64,
+    /// Refuse archives whose `build-info.json` names another version or a
+    /// build time before the installed build's.
+    #[serde(default)]
+    pub verify_build_info: bool,
+    /// Files or directories copied aside before the update script runs and
+    /// put back as they were when the update fails, for devices without A/B
+    /// slots whose critical files must survive a failed script.
+    #[serde(default)]
+    pub protected_paths: Vec<PathBuf>,
+    /// Script in the extracted tree run after the update script succeeded
+    /// and the service proved healthy (`post_update_restart_attempts`, or
+    /// `service_health_command` run once).
+    #[serde(default)]
+    pub commit_script_name: Option<String>,
+    /// Whether a failing commit script fails the whole update.
+    #[serde(default)]
+    pub commit_script_fatal: bool,
+    /// After a successful update, restart `service_name` and wait for
+    /// `service_health_command` to pass, trying up to this many restarts
+    /// before the update counts as failed and `protected_paths`, which must
+    /// be set, are restored. 0 leaves the service alone.
+    #[serde(default)]
+    pub post_update_restart_attempts: u32,
+    /// Probe exiting 0 once the restarted service is healthy; `{service}` is
+    /// replaced with `service_name`. Defaults to
+    /// `systemctl is-active --quiet {service}`.
+    #[serde(default)]
+    pub service_health_command: Option<String>,
+    /// How long the health probe is retried after each restart.
+    #[serde(default = "defaults::service_health_timeout_seconds")]
+    pub service_health_timeout_seconds: u64,
+    /// Include per-phase durations in the success status report.
+    #[serde(default)]
+    pub report_phase_timings: bool,
+    /// Sign status reports with HMAC-SHA256 under this secret, sent in
+    /// `x-signature` along with a timestamp and nonce against replay.
+    #[serde(default)]
+    pub hmac_secret: Option<String>,
+    /// Extra substrings (case-insensitive) marking a line of update script
+    /// stderr as the disk filling up, on top of `No space left on device`,
+    /// `Disk quota exceeded` and `ENOSPC`. Such failures are reported with
+    /// `failureCategory` `disk_full`.
+    #[serde(default)]
+    pub disk_full_patterns: Vec<String>,
+    /// Install into the inactive one of two A/B slots instead of
+    /// `download_base_dir`.
+    #[serde(default)]
+    pub ab_slots: Option<AbSlots>,
+    /// Extract updates straight into this directory, e.g. `/opt/myservice`,
+    /// and run the update script there instead of in a staging directory
+    /// under `download_base_dir`. The new tree is extracted next to it and
+    /// swapped in once complete; the replaced one is kept until the update
+    /// succeeded and put back otherwise.
+    #[serde(default)]
+    pub install_dir: Option<PathBuf>,
+    /// Append a `206` response that lacks `Content-Range` instead of
+    /// restarting the download.
+    #[serde(default)]
+    pub trust_range_without_content_range: bool,
+    /// Failed attempts after which a version is skipped until a newer one
+    /// appears; 0 retries forever.
+    #[serde(default = "defaults::max_attempts_per_version")]
+    pub max_attempts_per_version: u32,
+    /// Credential sent with artifact downloads only, in `download_token_header`.
+    /// The device token is never sent to download URLs, so pre-signed URLs
+    /// work without this.
+    #[serde(default)]
+    pub download_token: Option<String>,
+    /// Header carrying `download_token`, e.g. `authorization` with a
+    /// `Bearer ...` token.
+    #[serde(default = "defaults::download_token_header")]
+    pub download_token_header: String,
+    /// Hosts artifacts (and redirects while downloading them) may come from;
+    /// `*.example.com` matches subdomains. Empty allows any host.
+    #[serde(default)]
+    pub download_host_allowlist: Vec<String>,
+    /// Write logs to this file instead of stdout, rotating it by size.
+    #[serde(default)]
+    pub log_file: Option<PathBuf>,
+    /// Size at which `log_file` is rotated.
+    #[serde(default = "defaults::log_file_max_bytes")]
+    pub log_file_max_bytes: u64,
+    /// Rotated log files kept next to `log_file`.
+    #[serde(default = "defaults::log_file_keep")]
+    pub log_file_keep: usize,
+    /// OTLP/HTTP traces endpoint cycles are exported to as spans; needs a
+    /// build with the `otlp` feature.
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    /// Clamp `poll_interval_seconds` outside its sane bounds (with a
+    /// warning) instead of rejecting the config. Other durations are always
+    /// clamped.
+    #[serde(default)]
+    pub clamp_out_of_range_durations: bool,
+}
+
+/// System directories an update must never be swapped into place over.
+const PROTECTED_INSTALL_DIRS: &[&str] = &[
+    "/",
+    "/bin",
+    "/boot",
+    "/dev",
+    "/etc",
+    "/home",
+    "/lib",
+    "/lib64",
+    "/opt",
+    "/proc",
+    "/root",
+    "/run",
+    "/sbin",
+    "/srv",
+    "/sys",
+    "/tmp",
+    "/usr",
+    "/usr/bin",
+    "/usr/lib",
+    "/usr/local",
+    "/var",
+    "/var/lib",
+];
+
+/// Rejects an `install_dir` whose replacement would take the system or the
+/// updater's own files with it.
+fn check_install_dir(config: &Config) -> Result<(), UpdateError> {
+    let Some(dir) = &config.install_dir else {
+        return Ok(());
+    };
+    let invalid = |reason: &str| -> Result<(), UpdateError> {
+        Err(UpdateError::ConfigError(format!(
+            "install_dir {:?} {}",
+            dir, reason
+        )))
+    };
+    if !dir.is_absolute() {
+        return invalid("must be an absolute path");
+    }
+    if dir
+        .components()
+        .any(|c| !matches!(c, Component::RootDir | Component::Normal(_)))
+    {
+        return invalid("must not contain '.' or '..'");
+    }
+    if PROTECTED_INSTALL_DIRS.iter().any(|p| dir == Path::new(p)) {
+        return invalid("is a system directory");
+    }
+    if config.download_base_dir.starts_with(dir) || config.current_version_file.starts_with(dir) {
+        return invalid("must not hold download_base_dir or current_version_file");
+    }
+    if config.ab_slots.is_some() {
+        return invalid("cannot be combined with ab_slots");
+    }
+    if config.extract_policy == ExtractPolicy::MergeKeep {
+        return invalid("is always replaced whole, which extract_policy merge-keep contradicts");
+    }
+    Ok(())
+}
+
+fn is_writable(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is a valid NUL-terminated string.
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+fn check_header_name(field: &str, name: &str) -> Result<(), UpdateError> {
+    reqwest::header::HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+        UpdateError::ConfigError(format!("{} '{}' is not a valid header name", field, name))
+    })?;
+    Ok(())
+}
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+/// Lets `value` through when it lies within `min..=max`. Otherwise it is
+/// clamped with a warning when `clamp` is set, and rejected when not.
+fn check_bounds(
+    name: &str,
+    value: &mut u64,
+    min: u64,
+    max: u64,
+    clamp: bool,
+    warnings: &mut Vec<String>,
+) -> Result<(), UpdateError> {
+    if (min..=max).contains(value) {
+        return Ok(());
+    }
+    if !clamp {
+        return Err(UpdateError::ConfigError(format!(
+            "{} = {} is outside {}..={}; fix the value or set clamp_out_of_range_durations = true",
+            name, value, min, max
+        )));
+    }
+    let clamped = (*value).clamp(min, max);
+    warnings.push(format!(
+        "{} = {} is outside {}..={}, using {}",
+        name, value, min, max, clamped
+    ));
+    *value = clamped;
+    Ok(())
 }
 
 impl Config {
-    pub fn load(path: &str) -> Result<Self, UpdateError> {
+    /// Loads the config at `path`, along with warnings about out-of-range
+    /// values that were clamped, for the caller to log.
+    pub fn load(path: &str) -> Result<(Self, Vec<String>), UpdateError> {
         let config_str = fs::read_to_string(path).map_err(|e| {
             UpdateError::ConfigError(format!("Failed to read config file '{}': {}", path, e))
         })?;
-        let config: Config = toml::from_str(&config_str)
+        let (config, warnings) = Self::parse_with_warnings(&config_str)?;
+        config.ensure_directories()?;
+        config.check_install_dir_writable()?;
+
+        Ok((config, warnings))
+    }
+
+    /// Builds and validates a config from TOML text without touching the
+    /// filesystem, e.g. for tests; `load` adds `ensure_directories` on top.
+    #[cfg(test)]
+    pub fn parse(config_str: &str) -> Result<Self, UpdateError> {
+        Self::parse_with_warnings(config_str).map(|(config, _)| config)
+    }
+
+    fn parse_with_warnings(config_str: &str) -> Result<(Self, Vec<String>), UpdateError> {
+        let mut config: Config = toml::from_str(config_str)
             .map_err(|e| UpdateError::ConfigError(format!("Failed to parse TOML config: {}", e)))?;
 
         // Validate decryption key length (64 hex chars for 32 bytes)
@@ -32,25 +628,314 @@ impl Config {
                     .to_string(),
             ));
         }
-        // Ensure download_base_dir exists
-        if !config.download_base_dir.exists() {
-            fs::create_dir_all(&config.download_base_dir).map_err(|e| {
+        if let Some(i) = config
+            .decryption_keys_hex
+            .iter()
+            .position(|key| key.len() != 64)
+        {
+            return Err(UpdateError::ConfigError(format!(
+                "decryption_keys_hex[{}] must be 64 hex characters long for a 32-byte key.",
+                i
+            )));
+        }
+        if config.first_boot_policy == FirstBootPolicy::RecordOnly
+            && config.current_version_command.is_some()
+        {
+            return Err(UpdateError::ConfigError(
+                "first_boot_policy record_only needs current_version_file to record the version \
+                 in, not current_version_command"
+                    .to_string(),
+            ));
+        }
+        if let Some(path) = config.protected_paths.iter().find(|p| !p.is_absolute()) {
+            return Err(UpdateError::ConfigError(format!(
+                "protected_paths entry {:?} must be an absolute path",
+                path
+            )));
+        }
+        if config.post_update_restart_attempts > 0 && config.ab_slots.is_some() {
+            return Err(UpdateError::ConfigError(
+                "post_update_restart_attempts cannot be combined with ab_slots, whose update \
+                 only runs after a reboot"
+                    .to_string(),
+            ));
+        }
+        if config.post_update_restart_attempts > 0 && config.protected_paths.is_empty() {
+            return Err(UpdateError::ConfigError(
+                "post_update_restart_attempts needs protected_paths to roll back to when the \
+                 service doesn't come back"
+                    .to_string(),
+            ));
+        }
+        EntryFilter::new(&config)?;
+        check_install_dir(&config)?;
+        let warnings = config.enforce_bounds()?;
+        check_header_name("device_token_header", &config.device_token_header)?;
+        check_header_name("download_token_header", &config.download_token_header)?;
+
+        Ok((config, warnings))
+    }
+
+    /// Rejects (or clamps) a `poll_interval_seconds` that would busy-loop the
+    /// updater or effectively disable it, and clamps the other durations,
+    /// which configs written before their bounds existed may hold. Returns
+    /// a warning for each value clamped.
+    fn enforce_bounds(&mut self) -> Result<Vec<String>, UpdateError> {
+        let mut warnings = Vec::new();
+        check_bounds(
+            "poll_interval_seconds",
+            &mut self.poll_interval_seconds,
+            1,
+            7 * DAY,
+            self.clamp_out_of_range_durations,
+            &mut warnings,
+        )?;
+        for (name, value, min, max) in [
+            (
+                "cycle_timeout_seconds",
+                &mut self.cycle_timeout_seconds,
+                60,
+                7 * DAY,
+            ),
+            (
+                "download_inactivity_timeout_seconds",
+                &mut self.download_inactivity_timeout_seconds,
+                1,
+                DAY,
+            ),
+            (
+                "supersede_check_interval_seconds",
+                &mut self.supersede_check_interval_seconds,
+                1,
+                DAY,
+            ),
+            (
+                "pool_idle_timeout_seconds",
+                &mut self.pool_idle_timeout_seconds,
+                0,
+                DAY,
+            ),
+            (
+                "status_report_min_interval_seconds",
+                &mut self.status_report_min_interval_seconds,
+                0,
+                DAY,
+            ),
+            (
+                "status_breaker_cooldown_seconds",
+                &mut self.status_breaker_cooldown_seconds,
+                1,
+                DAY,
+            ),
+            (
+                "service_health_timeout_seconds",
+                &mut self.service_health_timeout_seconds,
+                1,
+                DAY,
+            ),
+            (
+                "reboot_max_wait_seconds",
+                &mut self.reboot_max_wait_seconds,
+                0,
+                7 * DAY,
+            ),
+            (
+                "hook_timeout_seconds",
+                &mut self.hook_timeout_seconds,
+                1,
+                DAY,
+            ),
+            (
+                "scan_timeout_seconds",
+                &mut self.scan_timeout_seconds,
+                1,
+                DAY,
+            ),
+            (
+                "startup_delay_seconds",
+                &mut self.startup_delay_seconds,
+                0,
+                DAY,
+            ),
+            (
+                "startup_jitter_seconds",
+                &mut self.startup_jitter_seconds,
+                0,
+                DAY,
+            ),
+            (
+                "min_poll_interval_seconds",
+                &mut self.min_poll_interval_seconds,
+                1,
+                7 * DAY,
+            ),
+            (
+                "max_poll_interval_seconds",
+                &mut self.max_poll_interval_seconds,
+                1,
+                30 * DAY,
+            ),
+        ] {
+            check_bounds(name, value, min, max, true, &mut warnings)?;
+        }
+
+        if self.min_poll_interval_seconds > self.max_poll_interval_seconds {
+            warnings.push(format!(
+                "min_poll_interval_seconds ({}) is above max_poll_interval_seconds ({}), using {}",
+                self.min_poll_interval_seconds,
+                self.max_poll_interval_seconds,
+                self.max_poll_interval_seconds
+            ));
+            self.min_poll_interval_seconds = self.max_poll_interval_seconds;
+        }
+        Ok(warnings)
+    }
+
+    /// Creates the directories the updater writes to.
+    pub fn ensure_directories(&self) -> Result<(), UpdateError> {
+        if !self.download_base_dir.exists() {
+            fs::create_dir_all(&self.download_base_dir).map_err(|e| {
                 UpdateError::FileSystemError(format!(
                     "Failed to create download base directory {:?}: {}",
-                    config.download_base_dir, e
+                    self.download_base_dir, e
                 ))
             })?;
         }
+        Ok(())
+    }
+
+    /// The swap creates the new tree next to `install_dir` and renames it
+    /// into place, so both it and its parent must be writable.
+    fn check_install_dir_writable(&self) -> Result<(), UpdateError> {
+        let Some(dir) = &self.install_dir else {
+            return Ok(());
+        };
+        let parent = dir.parent().unwrap_or(dir);
+        for path in [parent, dir.as_path()] {
+            if path.exists() && !is_writable(path) {
+                return Err(UpdateError::ConfigError(format!(
+                    "install_dir: {:?} is not writable",
+                    path
+                )));
+            }
+        }
+        if !parent.exists() {
+            return Err(UpdateError::ConfigError(format!(
+                "install_dir: parent directory {:?} does not exist",
+                parent
+            )));
+        }
+        Ok(())
+    }
+
+    /// Directory updates are extracted into and their script runs in, given
+    /// the artifact's name: `install_dir` when set, else a directory named
+    /// after the artifact under `download_base_dir`.
+    pub fn extraction_dir(&self, artifact_name: &str) -> PathBuf {
+        match &self.install_dir {
+            Some(dir) => dir.clone(),
+            None => self.download_base_dir.join(artifact_name),
+        }
+    }
+
+    /// Whether extraction builds the new tree aside and swaps it in, leaving
+    /// the existing one untouched until then.
+    pub fn extracts_via_staging(&self) -> bool {
+        self.extract_policy == ExtractPolicy::Clean || self.install_dir.is_some()
+    }
+
+    /// Resolves the secrets to pass to the update script as environment
+    /// variables, each on its own so one unreadable source doesn't hide how
+    /// the others fared.
+    pub fn script_secrets(&self) -> Vec<(String, Result<String, UpdateError>)> {
+        if self.script_secret_env.is_empty() {
+            if self.db_password.is_empty() {
+                return Vec::new();
+            }
+            return vec![("DB_PASSWORD".to_string(), Ok(self.db_password.clone()))];
+        }
+
+        self.script_secret_env
+            .iter()
+            .map(|(name, source)| (name.clone(), source.resolve()))
+            .collect()
+    }
+
+    /// `decryption_key_hex` followed by `decryption_keys_hex`, decoded.
+    pub fn get_decryption_keys(&self) -> Result<Vec<Vec<u8>>, UpdateError> {
+        std::iter::once(&self.decryption_key_hex)
+            .chain(&self.decryption_keys_hex)
+            .map(|key| hex::decode(key).map_err(UpdateError::from))
+            .collect()
+    }
+}
+
+/// The version in `current_version_command` output: the whole output, or
+/// else its first word that is a version, optionally prefixed with `v`
+/// (`myservice 42`, `v1.2.3`).
+fn parse_version_output(output: &str, scheme: VersionScheme) -> Option<i32> {
+    std::iter::once(output.trim())
+        .chain(output.split_whitespace())
+        .find_map(|word| parse_version_word(word.trim_start_matches('v'), scheme))
+}
 
-        Ok(config)
+fn parse_version_word(word: &str, scheme: VersionScheme) -> Option<i32> {
+    match scheme {
+        VersionScheme::Integer => word.parse().ok(),
+        VersionScheme::Semver => {
+            // Pre-release and build suffixes (`1.2.3-rc1+abc`) are ignored.
+            let core = word.split(['-', '+']).next()?;
+            let parts: Vec<i32> = core
+                .split('.')
+                .map(|part| part.parse().ok().filter(|n| *n >= 0))
+                .collect::<Option<_>>()?;
+            let [major, minor, patch] = parts[..] else {
+                return None;
+            };
+            if minor >= 1_000 || patch >= 1_000 {
+                return None;
+            }
+            major
+                .checked_mul(1_000_000)?
+                .checked_add(minor * 1_000 + patch)
+        }
     }
+}
 
-    pub fn get_decryption_key(&self) -> Result<Vec<u8>, UpdateError> {
-        hex::decode(&self.decryption_key_hex).map_err(UpdateError::from)
+/// Runs `current_version_command` under `hook_timeout_seconds`. This blocks
+/// until the command exits or is killed.
+fn version_from_command(config: &Config, command: &str) -> Result<i32, UpdateError> {
+    let version = hooks::run_output_command_with_timeout(
+        "current version",
+        command,
+        Duration::from_secs(config.hook_timeout_seconds),
+        &CancellationToken::new(),
+    )
+    .and_then(|output| {
+        parse_version_output(&output, config.version_scheme).ok_or_else(|| {
+            UpdateError::HookError(format!(
+                "current version command printed no version: '{}'",
+                output
+            ))
+        })
+    });
+    match (version, config.version_command_failure) {
+        (Ok(version), _) => Ok(version),
+        (Err(e), VersionCommandFailure::AssumeZero) => {
+            tracing::warn!("{}, assuming version 0.", e);
+            Ok(0)
+        }
+        (Err(e), VersionCommandFailure::Error) => {
+            Err(UpdateError::VersionCommandError(e.to_string()))
+        }
     }
 }
 
 pub fn get_current_version(config: &Config) -> Result<i32, UpdateError> {
+    if let Some(command) = &config.current_version_command {
+        let version = version_from_command(config, command)?;
+        return check_minimum_version(config, version);
+    }
     if !config.current_version_file.exists() {
         tracing::warn!(
             "Version file {:?} not found, assuming version 0.",
@@ -59,5 +944,145 @@ pub fn get_current_version(config: &Config) -> Result<i32, UpdateError> {
         return Ok(0); // Default to 0 if file doesn't exist
     }
     let version_str = fs::read_to_string(&config.current_version_file)?;
-    Ok(version_str.trim().parse()?)
+    let version = match version_str.trim().parse() {
+        Ok(version) => version,
+        Err(e) => recover_version_file(config, &version_str, e)?,
+    };
+    check_minimum_version(config, version)
+}
+
+/// Replaces a version file holding garbage, e.g. after an unclean shutdown,
+/// with the last version read from it intact. Without one, the device is
+/// treated as on first boot (version 0, see `first_boot_policy`).
+fn recover_version_file(
+    config: &Config,
+    content: &str,
+    error: std::num::ParseIntError,
+) -> Result<i32, UpdateError> {
+    let path = &config.current_version_file;
+    let Some(version) = version_history::last_known_good(&config.download_base_dir) else {
+        tracing::warn!(
+            "Version file {:?} is corrupt ({}: {:?}) and no earlier version is known, \
+             assuming version 0.",
+            path,
+            error,
+            content.chars().take(32).collect::<String>()
+        );
+        return Ok(0);
+    };
+    tracing::warn!(
+        "Version file {:?} is corrupt ({}: {:?}), restoring the last known good version {}",
+        path,
+        error,
+        content.chars().take(32).collect::<String>(),
+        version
+    );
+    record_current_version(config, version)?;
+    Ok(version)
+}
+
+/// Writes `version` to `current_version_file` through a temporary file, so
+/// a crash never leaves it half written, and adds it to the version history.
+pub fn record_current_version(config: &Config, version: i32) -> Result<(), UpdateError> {
+    let path = &config.current_version_file;
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, format!("{}\n", version))
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| {
+            UpdateError::FileIOError(format!("Failed to write version file {:?}: {}", path, e))
+        })?;
+    if config.durable_writes {
+        durable::sync_file_and_dir(path)?;
+    }
+    if let Err(e) = version_history::record(&config.download_base_dir, version) {
+        tracing::warn!("{}", e);
+    }
+    Ok(())
+}
+
+fn check_minimum_version(config: &Config, version: i32) -> Result<i32, UpdateError> {
+    if version < config.minimum_version {
+        return Err(UpdateError::DowngradeBlocked {
+            version,
+            minimum: config.minimum_version,
+        });
+    }
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MINIMAL_CONFIG;
+
+    #[test]
+    fn minimal_config_resolves_to_defaults() {
+        let config = Config::parse(MINIMAL_CONFIG).unwrap();
+
+        assert_eq!(config.poll_interval_seconds, 300);
+        assert_eq!(
+            config.cycle_timeout_seconds,
+            defaults::cycle_timeout_seconds()
+        );
+        assert_eq!(
+            config.download_inactivity_timeout_seconds,
+            defaults::download_inactivity_timeout_seconds()
+        );
+        assert_eq!(
+            config.max_compression_ratio,
+            defaults::max_compression_ratio()
+        );
+        assert_eq!(
+            config.status_report_retries,
+            defaults::status_report_retries()
+        );
+        assert_eq!(config.extract_io_retries, defaults::extract_io_retries());
+        assert_eq!(
+            config.pool_idle_timeout_seconds,
+            defaults::pool_idle_timeout_seconds()
+        );
+        assert_eq!(config.extract_policy, ExtractPolicy::Overwrite);
+        assert!(config.require_script_shebang);
+        assert!(config.verify_download_size);
+        assert!(!config.manifest_mode);
+        assert!(!config.report_script_start);
+        assert!(config.precheck_command.is_none());
+        assert!(config.script_secret_env.is_empty());
+        assert!(config.extract_include_globs.is_empty());
+        assert_eq!(config.minimum_version, 0);
+    }
+
+    #[test]
+    fn out_of_range_durations_are_clamped_but_poll_interval_rejected() {
+        let (config, warnings) =
+            Config::parse_with_warnings(&format!("{}cycle_timeout_seconds = 30\n", MINIMAL_CONFIG))
+                .unwrap();
+        assert_eq!(config.cycle_timeout_seconds, 60);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+
+        let busy_loop =
+            MINIMAL_CONFIG.replace("poll_interval_seconds = 300", "poll_interval_seconds = 0");
+        assert!(Config::parse(&busy_loop).is_err());
+        let clamped = Config::parse(&format!(
+            "{}clamp_out_of_range_durations = true\n",
+            busy_loop
+        ))
+        .unwrap();
+        assert_eq!(clamped.poll_interval_seconds, 1);
+    }
+
+    #[test]
+    fn version_output_is_parsed_per_scheme() {
+        use VersionScheme::{Integer, Semver};
+
+        assert_eq!(parse_version_output("myservice 42\n", Integer), Some(42));
+        assert_eq!(parse_version_output("v42", Integer), Some(42));
+        assert_eq!(parse_version_output("1.2.3", Integer), None);
+        assert_eq!(
+            parse_version_output("myservice v1.2.3-rc1", Semver),
+            Some(1_002_003)
+        );
+        assert_eq!(parse_version_output("1.1000.0", Semver), None);
+        assert_eq!(parse_version_output("42", Semver), None);
+    }
 }
@@ -0,0 +1,228 @@
+use crate::error::UpdateError;
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{fs, io, path::Path};
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Magic bytes opening a file in the `header` format.
+pub const HEADER_MAGIC: &[u8; 4] = b"PBXE";
+/// Magic bytes opening a file in the `header_key_id` format.
+pub const KEY_ID_HEADER_MAGIC: &[u8; 4] = b"PBXK";
+/// Length of the key id: the leading bytes of the SHA-256 of the key.
+pub const KEY_ID_LEN: usize = 8;
+
+/// How the nonce is framed around the AES-256-GCM ciphertext.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptionFormat {
+    /// `nonce (12) | ciphertext | tag (16)`
+    #[default]
+    NoncePrefix,
+    /// `ciphertext | tag (16) | nonce (12)`
+    NonceSuffix,
+    /// `magic "PBXE" (4) | nonce (12) | ciphertext | tag (16)`
+    Header,
+    /// `magic "PBXK" (4) | key id (8) | nonce (12) | ciphertext | tag (16)`
+    HeaderKeyId,
+}
+
+impl EncryptionFormat {
+    fn magic(self) -> Option<&'static [u8; 4]> {
+        match self {
+            EncryptionFormat::Header => Some(HEADER_MAGIC),
+            EncryptionFormat::HeaderKeyId => Some(KEY_ID_HEADER_MAGIC),
+            _ => None,
+        }
+    }
+
+    /// Bytes in front of the nonce.
+    fn header_len(self) -> usize {
+        match self {
+            EncryptionFormat::Header => HEADER_MAGIC.len(),
+            EncryptionFormat::HeaderKeyId => KEY_ID_HEADER_MAGIC.len() + KEY_ID_LEN,
+            _ => 0,
+        }
+    }
+
+    /// Length of the file prefix needed by `check_key_id`, if the format
+    /// carries a key id at all.
+    pub fn key_id_prefix_len(self) -> Option<usize> {
+        (self == EncryptionFormat::HeaderKeyId).then(|| self.header_len())
+    }
+}
+
+/// Identifies `key` without revealing it: the first `KEY_ID_LEN` bytes of its
+/// SHA-256.
+pub fn key_id(key: &[u8]) -> [u8; KEY_ID_LEN] {
+    let digest = Sha256::digest(key);
+    let mut id = [0u8; KEY_ID_LEN];
+    id.copy_from_slice(&digest[..KEY_ID_LEN]);
+    id
+}
+
+/// Picks the key among `keys` whose id is in the header at the start of
+/// `data`, so a device provisioned without the right key can bail out before
+/// downloading the whole payload. Formats without a key id yield `None`.
+pub fn check_key_id<'k>(
+    data: &[u8],
+    keys: &'k [Vec<u8>],
+    format: EncryptionFormat,
+) -> Result<Option<&'k [u8]>, UpdateError> {
+    let Some(prefix_len) = format.key_id_prefix_len() else {
+        return Ok(None);
+    };
+    if data.len() < prefix_len || !data.starts_with(KEY_ID_HEADER_MAGIC) {
+        return Err(UpdateError::DecryptionError(format!(
+            "payload framing does not match encryption_format {:?} (header magic missing)",
+            format
+        )));
+    }
+
+    let actual = &data[KEY_ID_HEADER_MAGIC.len()..prefix_len];
+    if let Some(key) = keys.iter().find(|key| key_id(key) == actual) {
+        return Ok(Some(key.as_slice()));
+    }
+    Err(UpdateError::DecryptionError(format!(
+        "key id mismatch: payload is for key {}, configured keys are {}",
+        hex::encode(actual),
+        keys.iter()
+            .map(|key| hex::encode(key_id(key)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )))
+}
+
+/// Splits an encrypted payload into nonce and `ciphertext | tag` according to
+/// `format`, rejecting payloads whose framing obviously doesn't match.
+fn split_payload(data: &[u8], format: EncryptionFormat) -> Result<(&[u8], &[u8]), UpdateError> {
+    let has_magic = data.starts_with(HEADER_MAGIC) || data.starts_with(KEY_ID_HEADER_MAGIC);
+    let framing_matches = match format.magic() {
+        Some(magic) => data.starts_with(magic),
+        None => !has_magic,
+    };
+    if !framing_matches {
+        return Err(UpdateError::DecryptionError(format!(
+            "payload framing does not match encryption_format {:?} (header magic {})",
+            format,
+            if has_magic { "present" } else { "missing" }
+        )));
+    }
+
+    let min_len = format.header_len() + NONCE_LEN + TAG_LEN;
+    if data.len() < min_len {
+        return Err(UpdateError::DecryptionError(format!(
+            "payload of {} bytes is too short for encryption_format {:?}",
+            data.len(),
+            format
+        )));
+    }
+
+    Ok(match format {
+        EncryptionFormat::NonceSuffix => {
+            let (ciphertext, nonce) = data.split_at(data.len() - NONCE_LEN);
+            (nonce, ciphertext)
+        }
+        _ => data[format.header_len()..].split_at(NONCE_LEN),
+    })
+}
+
+/// Decrypts the AES-256-GCM payload at `input` into `output`, with the key
+/// the header names or else each of `keys` in turn.
+///
+/// The whole payload is held in memory since GCM authenticates it as a unit.
+pub fn decrypt_update(
+    input: &Path,
+    output: &Path,
+    keys: &[Vec<u8>],
+    format: EncryptionFormat,
+) -> Result<(), UpdateError> {
+    let data = fs::read(input).map_err(|e| {
+        UpdateError::FileIOError(format!("Failed to read encrypted file {:?}: {}", input, e))
+    })?;
+    let plaintext = decrypt_payload(&data, keys, format)?;
+    fs::write(output, plaintext).map_err(|e| {
+        UpdateError::FileIOError(format!(
+            "Failed to write decrypted file {:?}: {}",
+            output, e
+        ))
+    })?;
+    tracing::debug!("Decrypted {:?} into {:?}", input, output);
+    Ok(())
+}
+
+/// Decrypts an AES-256-GCM payload held in memory, like `decrypt_update`.
+pub fn decrypt_payload(
+    data: &[u8],
+    keys: &[Vec<u8>],
+    format: EncryptionFormat,
+) -> Result<Vec<u8>, UpdateError> {
+    let candidates: Vec<&[u8]> = match check_key_id(data, keys, format)? {
+        Some(key) => vec![key],
+        None => keys.iter().map(Vec::as_slice).collect(),
+    };
+    let (nonce, ciphertext) = split_payload(data, format)?;
+
+    let mut plaintext = None;
+    for (i, key) in candidates.iter().enumerate() {
+        let cipher = Aes256Gcm::new_from_slice(key)
+            .map_err(|e| UpdateError::DecryptionError(format!("Invalid decryption key: {}", e)))?;
+        if let Ok(decrypted) = cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+            if candidates.len() > 1 {
+                tracing::debug!("Payload decrypted with configured key #{}", i + 1);
+            }
+            plaintext = Some(decrypted);
+            break;
+        }
+    }
+    let Some(plaintext) = plaintext else {
+        return Err(UpdateError::DecryptionError(if candidates.len() > 1 {
+            format!(
+                "none of the {} configured keys can decrypt the payload (encryption_format {:?})",
+                candidates.len(),
+                format
+            )
+        } else {
+            format!(
+                "authentication failed for encryption_format {:?} (wrong key or framing?)",
+                format
+            )
+        }));
+    };
+    Ok(plaintext)
+}
+
+/// Checks that the SHA-256 of the file at `path` matches `expected_hex`.
+pub fn verify_sha256(path: &Path, expected_hex: &str) -> Result<(), UpdateError> {
+    let mut file = fs::File::open(path).map_err(|e| {
+        UpdateError::FileIOError(format!("Failed to open {:?} for hashing: {}", path, e))
+    })?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .map_err(|e| UpdateError::FileIOError(format!("Failed to hash {:?}: {}", path, e)))?;
+    check_digest(&hasher.finalize(), expected_hex)?;
+    tracing::debug!("Checksum of {:?} verified", path);
+    Ok(())
+}
+
+/// Checks that the SHA-256 of `data` matches `expected_hex`.
+pub fn verify_sha256_bytes(data: &[u8], expected_hex: &str) -> Result<(), UpdateError> {
+    check_digest(&Sha256::digest(data), expected_hex)?;
+    tracing::debug!("Checksum of {} bytes in memory verified", data.len());
+    Ok(())
+}
+
+fn check_digest(digest: &[u8], expected_hex: &str) -> Result<(), UpdateError> {
+    let actual = hex::encode(digest);
+    if !actual.eq_ignore_ascii_case(expected_hex.trim()) {
+        return Err(UpdateError::ChecksumError(format!(
+            "expected {}, got {}",
+            expected_hex, actual
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! Defaults of optional `Config` fields, so configs written before a field
+//! existed keep loading unchanged.
+
+pub fn enabled() -> bool {
+    true
+}
+
+pub fn cycle_timeout_seconds() -> u64 {
+    4 * 60 * 60
+}
+
+pub fn download_inactivity_timeout_seconds() -> u64 {
+    60
+}
+
+pub fn max_compression_ratio() -> u64 {
+    200
+}
+
+pub fn supersede_check_interval_seconds() -> u64 {
+    600
+}
+
+pub fn status_report_retries() -> u32 {
+    3
+}
+
+pub fn status_breaker_threshold() -> u32 {
+    5
+}
+
+pub fn status_breaker_cooldown_seconds() -> u64 {
+    10 * 60
+}
+
+pub fn extract_io_retries() -> u32 {
+    2
+}
+
+pub fn extract_buffer_bytes() -> usize {
+    64 * 1024
+}
+
+pub fn pool_idle_timeout_seconds() -> u64 {
+    90
+}
+
+pub fn min_poll_interval_seconds() -> u64 {
+    60
+}
+
+pub fn max_poll_interval_seconds() -> u64 {
+    24 * 60 * 60
+}
+
+pub fn reboot_max_wait_seconds() -> u64 {
+    4 * 60 * 60
+}
+
+pub fn max_attempts_per_version() -> u32 {
+    3
+}
+
+pub fn device_token_header() -> String {
+    "device-token".to_string()
+}
+
+pub fn download_token_header() -> String {
+    "authorization".to_string()
+}
+
+pub fn log_file_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+pub fn log_file_keep() -> usize {
+    5
+}
+
+pub fn cache_max_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+pub fn log_upload_max_bytes() -> usize {
+    1024 * 1024
+}
+
+pub fn hook_timeout_seconds() -> u64 {
+    2 * 60
+}
+
+pub fn scan_timeout_seconds() -> u64 {
+    30 * 60
+}
+
+pub fn service_health_timeout_seconds() -> u64 {
+    60
+}
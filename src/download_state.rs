@@ -0,0 +1,95 @@
+use crate::error::UpdateError;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Sidecar stored next to a partial download (`<file>.state`) describing what
+/// has been durably written, so a resume can be validated before appending.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DownloadState {
+    pub url: String,
+    pub total_size: Option<u64>,
+    pub etag: Option<String>,
+    pub bytes_committed: u64,
+}
+
+impl DownloadState {
+    pub fn path_for(download_path: &Path) -> PathBuf {
+        let mut name = download_path.as_os_str().to_owned();
+        name.push(".state");
+        PathBuf::from(name)
+    }
+
+    /// Loads the sidecar for `download_path`, returning `None` when it is
+    /// missing or unreadable.
+    pub async fn load(download_path: &Path) -> Option<Self> {
+        let path = Self::path_for(download_path);
+        let data = tokio::fs::read(&path).await.ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                tracing::warn!("Ignoring corrupt download state {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Writes the sidecar atomically (temp file + rename).
+    pub async fn save(&self, download_path: &Path) -> Result<(), UpdateError> {
+        let path = Self::path_for(download_path);
+        let tmp_path = path.with_extension("state.tmp");
+        let data = serde_json::to_vec(self).map_err(|e| {
+            UpdateError::FileIOError(format!("Failed to serialize download state: {}", e))
+        })?;
+        tokio::fs::write(&tmp_path, data).await.map_err(|e| {
+            UpdateError::FileIOError(format!(
+                "Failed to write download state {:?}: {}",
+                tmp_path, e
+            ))
+        })?;
+        tokio::fs::rename(&tmp_path, &path).await.map_err(|e| {
+            UpdateError::FileIOError(format!(
+                "Failed to replace download state {:?}: {}",
+                path, e
+            ))
+        })
+    }
+
+    pub async fn remove(download_path: &Path) {
+        let path = Self::path_for(download_path);
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("Failed to remove download state {:?}: {}", path, e);
+            }
+        }
+    }
+
+    /// Explains why a partial file of `file_len` bytes can't be resumed
+    /// against the server's current `total_size`/`etag`, if it can't.
+    pub fn resume_mismatch(
+        &self,
+        url: &str,
+        total_size: Option<u64>,
+        etag: Option<&str>,
+        file_len: u64,
+    ) -> Option<String> {
+        if self.url != url {
+            return Some(format!("URL changed from {}", self.url));
+        }
+        if self.total_size != total_size {
+            return Some(format!(
+                "size changed from {:?} to {:?}",
+                self.total_size, total_size
+            ));
+        }
+        if self.etag.as_deref() != etag {
+            return Some(format!("ETag changed from {:?} to {:?}", self.etag, etag));
+        }
+        if self.bytes_committed != file_len {
+            return Some(format!(
+                "file holds {} bytes but {} were committed",
+                file_len, self.bytes_committed
+            ));
+        }
+        None
+    }
+}
@@ -0,0 +1,65 @@
+use crate::error::UpdateError;
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+fn sync_path(path: &Path) -> io::Result<()> {
+    // Directories can only be opened for reading, which is all fsync needs.
+    fs::File::open(path)?.sync_all()
+}
+
+/// Flushes `path` to disk, and its directory so the entry naming it
+/// survives a power cut as well.
+pub fn sync_file_and_dir(path: &Path) -> Result<(), UpdateError> {
+    let mut paths = vec![path];
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        paths.push(dir);
+    }
+    for path in paths {
+        sync_path(path).map_err(|e| {
+            UpdateError::FileSystemError(format!("Failed to fsync {:?}: {}", path, e))
+        })?;
+    }
+    Ok(())
+}
+
+/// Flushes the files extracted into `root`, then every directory between
+/// them and `root`, then the directory holding `root`.
+pub fn sync_extracted(root: &Path, files: &[(PathBuf, u64)]) -> Result<(), UpdateError> {
+    let mut dirs = BTreeSet::new();
+    for (file, _) in files {
+        sync_path(file).map_err(|e| {
+            UpdateError::FileSystemError(format!(
+                "Failed to fsync extracted file {:?}: {}",
+                file, e
+            ))
+        })?;
+        for dir in file.ancestors().skip(1) {
+            if !dir.starts_with(root) || !dirs.insert(dir.to_path_buf()) {
+                break;
+            }
+        }
+    }
+    dirs.insert(root.to_path_buf());
+    // Deepest first, so a directory is only durable after what it holds.
+    for dir in dirs.iter().rev() {
+        sync_path(dir).map_err(|e| {
+            UpdateError::FileSystemError(format!("Failed to fsync directory {:?}: {}", dir, e))
+        })?;
+    }
+    if let Some(parent) = root.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        sync_path(parent).map_err(|e| {
+            UpdateError::FileSystemError(format!("Failed to fsync directory {:?}: {}", parent, e))
+        })?;
+    }
+    Ok(())
+}
+
+/// Flushes everything written since the last sync on every filesystem,
+/// including whatever the update script changed.
+pub fn sync_all_filesystems() {
+    // SAFETY: sync has no preconditions.
+    unsafe { libc::sync() };
+}
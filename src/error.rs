@@ -10,26 +10,61 @@ pub enum UpdateError {
     TokenReadError(String),
     #[error("Invalid version format in version file: {0}")]
     VersionFormatError(#[from] std::num::ParseIntError),
+    #[error("Current version unknown: {0}")]
+    VersionCommandError(String),
     #[error("API client error: {0}")]
     ApiClientError(#[from] reqwest::Error),
     #[error("API request failed: {status} - {message}")]
-    ApiRequestFailed { status: reqwest::StatusCode, message: String },
+    ApiRequestFailed {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+    #[error("Device token rejected: {status} - {message}")]
+    Unauthorized {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+    #[error("Server throttled the request: {status} (retry after {retry_after_seconds:?} s)")]
+    Throttled {
+        status: reqwest::StatusCode,
+        retry_after_seconds: Option<u64>,
+    },
+    #[error("Downgrade blocked: version {version} is below minimum_version {minimum}")]
+    DowngradeBlocked { version: i32, minimum: i32 },
     #[error("No update available or service up-to-date")]
     NoUpdateAvailable,
     #[error("Download error: {0}")]
     DownloadError(String),
     #[error("Timeout error")]
     TimeoutError,
+    #[error("Cancelled by operator")]
+    Cancelled,
+    #[error("Incomplete download: received {received} of {expected} bytes")]
+    IncompleteDownload { received: u64, expected: u64 },
+    #[error("Chunk {index} at byte {offset} failed verification")]
+    ChunkMismatch { index: u64, offset: u64 },
     #[error("Head error: {0}")]
     HeadError(String),
     #[error("Decryption error: {0}")]
     DecryptionError(String),
     #[error("Encryption error (internal): {0}")]
     EncryptionError(String), // Should not happen for decryption but good for aes_gcm::Error
+    #[error("Checksum mismatch: {0}")]
+    ChecksumError(String),
+    #[error("Stale build detected: {0}")]
+    StaleBuild(String),
     #[error("Archive extraction error: {0}")]
     ArchiveError(String),
     #[error("Update script execution failed: {0}")]
     ScriptError(String),
+    #[error("Update script ran out of disk space: {0}")]
+    ScriptDiskFull(String),
+    #[error("Update script not allowed: {0}")]
+    ScriptNotAllowed(String),
+    #[error("Service unhealthy after update: {0}")]
+    ServiceUnhealthy(String),
+    #[error("Hook command failed: {0}")]
+    HookError(String),
     #[error("Filesystem error: {0}")]
     FileSystemError(String),
     #[error("Hex decoding error for key: {0}")]
@@ -45,4 +80,4 @@ impl From<aes_gcm::Error> for UpdateError {
     fn from(err: aes_gcm::Error) -> Self {
         UpdateError::DecryptionError(err.to_string())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,944 @@
+use crate::config::Config;
+use crate::durable;
+use crate::error::UpdateError;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+
+/// What happens to an existing tree at the extraction target.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExtractPolicy {
+    /// Extract on top of the existing tree, replacing files.
+    #[default]
+    Overwrite,
+    /// Extract into a staging directory and swap it in only once extraction
+    /// succeeded, so no file of the previous tree survives.
+    Clean,
+    /// Extract on top of the existing tree, keeping files that already exist.
+    MergeKeep,
+}
+
+/// Selects the archive entries to extract from `extract_include_globs` and
+/// `extract_exclude_globs`, matched against the entry's relative path.
+pub struct EntryFilter {
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+}
+
+impl EntryFilter {
+    pub fn new(cfg: &Config) -> Result<Self, UpdateError> {
+        let compile = |globs: &[String]| {
+            globs
+                .iter()
+                .map(|g| {
+                    glob::Pattern::new(g).map_err(|e| {
+                        UpdateError::ConfigError(format!("Invalid extraction glob '{}': {}", g, e))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(EntryFilter {
+            include: compile(&cfg.extract_include_globs)?,
+            exclude: compile(&cfg.extract_exclude_globs)?,
+        })
+    }
+
+    fn accepts(&self, path: &Path) -> bool {
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        let matches = |patterns: &[glob::Pattern]| {
+            patterns
+                .iter()
+                .any(|pattern| pattern.matches_path_with(path, options))
+        };
+        (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude)
+    }
+}
+
+/// Metadata of a single archive entry, read without extracting it.
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    pub name: String,
+    /// Path relative to the extraction root, or `None` when the name would
+    /// escape it.
+    pub enclosed_path: Option<PathBuf>,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    pub is_dir: bool,
+    pub unix_mode: Option<u32>,
+    pub last_modified: Option<zip::DateTime>,
+}
+
+impl ArchiveEntry {
+    fn from_zip<R: io::Read>(file: &zip::read::ZipFile<'_, R>) -> Self {
+        ArchiveEntry {
+            name: file.name().to_string(),
+            enclosed_path: file.enclosed_name(),
+            compressed_size: file.compressed_size(),
+            uncompressed_size: file.size(),
+            is_dir: file.is_dir(),
+            unix_mode: file.unix_mode(),
+            last_modified: file.last_modified(),
+        }
+    }
+}
+
+/// Where an update archive is read from: the file it was downloaded (and
+/// decrypted) to, or memory for artifacts below `in_memory_threshold_bytes`.
+#[derive(Clone)]
+pub enum ArchiveSource {
+    File(PathBuf),
+    Memory(Arc<[u8]>),
+}
+
+impl ArchiveSource {
+    fn len(&self) -> io::Result<u64> {
+        match self {
+            ArchiveSource::File(p) => fs::metadata(p).map(|m| m.len()),
+            ArchiveSource::Memory(data) => Ok(data.len() as u64),
+        }
+    }
+}
+
+trait ReadSeek: io::Read + io::Seek {}
+
+impl<T: io::Read + io::Seek> ReadSeek for T {}
+
+type Archive = zip::ZipArchive<Box<dyn ReadSeek>>;
+
+fn open_archive(source: &ArchiveSource) -> Result<Archive, UpdateError> {
+    let reader: Box<dyn ReadSeek> = match source {
+        ArchiveSource::File(p) => Box::new(fs::File::open(p).map_err(|e| {
+            UpdateError::FileSystemError(format!("Failed to open zipped files: {}", e))
+        })?),
+        ArchiveSource::Memory(data) => Box::new(io::Cursor::new(Arc::clone(data))),
+    };
+
+    zip::ZipArchive::new(reader)
+        .map_err(|e| UpdateError::ArchiveError(format!("Failed to extract zipped files: {}", e)))
+}
+
+/// Lists the entries of the archive at `p` without extracting anything.
+pub fn list_archive_entries(p: &Path) -> Result<Vec<ArchiveEntry>, UpdateError> {
+    let mut archive = open_archive(&ArchiveSource::File(p.to_path_buf()))?;
+    (0..archive.len())
+        .map(|i| {
+            let file = archive.by_index_raw(i).map_err(|e| {
+                UpdateError::ArchiveError(format!("Failed to read archive entry {}: {}", i, e))
+            })?;
+            Ok(ArchiveEntry::from_zip(&file))
+        })
+        .collect()
+}
+
+/// Reads the file `name` at the root of the extracted tree straight from the
+/// archive, honoring `strip_top_level_dir`. `None` when there is none.
+pub fn read_archive_file(
+    cfg: &Config,
+    source: &ArchiveSource,
+    name: &str,
+) -> Result<Option<Vec<u8>>, UpdateError> {
+    let mut archive = open_archive(source)?;
+    let path = if cfg.strip_top_level_dir {
+        single_top_level_dir(&mut archive)?.join(name)
+    } else {
+        PathBuf::from(name)
+    };
+    let Some(name) = path.to_str() else {
+        return Ok(None);
+    };
+    let mut file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => {
+            return Err(UpdateError::ArchiveError(entry_failure(name, "read", e)));
+        }
+    };
+    let mut data = Vec::new();
+    io::Read::read_to_end(&mut file, &mut data)
+        .map_err(|e| UpdateError::ArchiveError(entry_failure(name, "read", e)))?;
+    Ok(Some(data))
+}
+
+/// Entries smaller than this are not subject to the compression-ratio check,
+/// since tiny highly-compressible files are common and harmless.
+const RATIO_CHECK_MIN_SIZE: u64 = 1024 * 1024;
+
+/// Rejects entries whose uncompressed:compressed ratio exceeds
+/// `max_compression_ratio`, the signature of a zip bomb.
+fn check_compression_ratio(cfg: &Config, entry: &ArchiveEntry) -> Result<(), UpdateError> {
+    let size = entry.uncompressed_size;
+    if cfg.max_compression_ratio == 0 || entry.is_dir || size < RATIO_CHECK_MIN_SIZE {
+        return Ok(());
+    }
+
+    let compressed = entry.compressed_size.max(1);
+    if size / compressed > cfg.max_compression_ratio {
+        return Err(UpdateError::ArchiveError(entry_failure(
+            &entry.name,
+            "compression ratio check",
+            format!(
+                "expands {} -> {} bytes, exceeding max_compression_ratio {}",
+                compressed, size, cfg.max_compression_ratio
+            ),
+        )));
+    }
+    Ok(())
+}
+
+fn entry_mtime(entry: &ArchiveEntry) -> Option<filetime::FileTime> {
+    let mtime = time::OffsetDateTime::try_from(entry.last_modified?).ok()?;
+    Some(filetime::FileTime::from_unix_time(
+        mtime.unix_timestamp(),
+        0,
+    ))
+}
+
+/// Sets the extracted file's mtime from the archive entry. Entries without a
+/// valid timestamp keep the extraction time.
+fn apply_mtime(entry: &ArchiveEntry, out_path: &Path) {
+    let Some(mtime) = entry_mtime(entry) else {
+        return;
+    };
+    if let Err(e) = filetime::set_file_mtime(out_path, mtime) {
+        tracing::warn!("Failed to set mtime of {:?}: {}", out_path, e);
+    }
+}
+
+/// Describes a failed extraction step of one entry, so the culprit is
+/// obvious in archives with hundreds of files.
+fn entry_failure(name: &str, op: &str, e: impl std::fmt::Display) -> String {
+    format!("entry '{}': {} failed: {}", name, op, e)
+}
+
+fn create_dir(entry: &ArchiveEntry, path: &Path) -> Result<(), UpdateError> {
+    fs::create_dir_all(path).map_err(|e| {
+        UpdateError::FileSystemError(entry_failure(
+            &entry.name,
+            &format!("create directory {:?}", path),
+            e,
+        ))
+    })
+}
+
+/// Entries written between two updates of the progress file.
+const PROGRESS_SAVE_INTERVAL: usize = 32;
+
+/// How far an interrupted extraction into a directory got, kept next to it
+/// as `<dir>.extract-progress` while `resume_extraction` is set.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct ExtractProgress {
+    archive_bytes: u64,
+    entries: usize,
+    /// Entries before this index were completely extracted.
+    completed: usize,
+}
+
+impl ExtractProgress {
+    fn path(o: &Path) -> PathBuf {
+        let mut path = o.as_os_str().to_owned();
+        path.push(".extract-progress");
+        PathBuf::from(path)
+    }
+
+    /// Progress recorded for extracting an archive of `archive_bytes` with
+    /// `entries` into `o`, or `None` when there is none or it describes a
+    /// different archive.
+    fn load(o: &Path, archive_bytes: u64, entries: usize) -> Option<Self> {
+        let data = fs::read(Self::path(o)).ok()?;
+        let progress: ExtractProgress = serde_json::from_slice(&data).ok()?;
+        if progress.archive_bytes != archive_bytes
+            || progress.entries != entries
+            || progress.completed > entries
+        {
+            tracing::info!(
+                "Extraction progress for {:?} does not match the archive, extracting everything",
+                o
+            );
+            return None;
+        }
+        Some(progress)
+    }
+
+    fn save(&self, o: &Path) {
+        let path = Self::path(o);
+        let result = serde_json::to_vec(self)
+            .map_err(io::Error::from)
+            .and_then(|data| fs::write(&path, data));
+        if let Err(e) = result {
+            tracing::warn!("Failed to save extraction progress {:?}: {}", path, e);
+        }
+    }
+
+    fn remove(o: &Path) {
+        let path = Self::path(o);
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                tracing::warn!("Failed to remove extraction progress {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Progress of a previous, interrupted extraction of `source` into `o`.
+fn resumable_progress(cfg: &Config, source: &ArchiveSource, o: &Path) -> Option<ExtractProgress> {
+    if !cfg.resume_extraction {
+        return None;
+    }
+    let archive_bytes = source.len().ok()?;
+    let entries = open_archive(source).ok()?.len();
+    ExtractProgress::load(o, archive_bytes, entries)
+}
+
+/// Whether a file extracted earlier still has the entry's size and mtime.
+fn already_extracted(entry: &ArchiveEntry, out_path: &Path) -> bool {
+    let Ok(meta) = out_path.symlink_metadata() else {
+        return false;
+    };
+    meta.is_file()
+        && meta.len() == entry.uncompressed_size
+        && entry_mtime(entry).is_none_or(|mtime| {
+            filetime::FileTime::from_last_modification_time(&meta).unix_seconds()
+                == mtime.unix_seconds()
+        })
+}
+
+/// The directory every entry of the archive lives under, for
+/// `strip_top_level_dir`. Archives with several top-level entries, or a
+/// single top-level file, are rejected rather than guessed at.
+fn single_top_level_dir(archive: &mut Archive) -> Result<PathBuf, UpdateError> {
+    let mut tops: Vec<PathBuf> = Vec::new();
+    let mut top_is_file = false;
+    for i in 0..archive.len() {
+        let entry = archive
+            .by_index_raw(i)
+            .map(|file| ArchiveEntry::from_zip(&file))
+            .map_err(|e| UpdateError::ArchiveError(entry_failure(&format!("#{}", i), "read", e)))?;
+        let Some(path) = &entry.enclosed_path else {
+            continue;
+        };
+        let Some(first) = path.components().next() else {
+            continue;
+        };
+        let top = PathBuf::from(first.as_os_str());
+        if !entry.is_dir && path.components().count() == 1 {
+            top_is_file = true;
+        }
+        if !tops.contains(&top) {
+            tops.push(top);
+        }
+    }
+
+    match tops.as_slice() {
+        [top] if !top_is_file => Ok(top.clone()),
+        [top] => Err(UpdateError::ArchiveError(format!(
+            "strip_top_level_dir is set but the only top-level entry {:?} is a file",
+            top
+        ))),
+        [] => Err(UpdateError::ArchiveError(
+            "strip_top_level_dir is set but the archive is empty".to_string(),
+        )),
+        _ => Err(UpdateError::ArchiveError(format!(
+            "strip_top_level_dir is set but the archive has {} top-level entries: {}",
+            tops.len(),
+            tops.iter()
+                .map(|top| top.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+/// Pause before re-attempting an entry whose write failed.
+const EXTRACT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Errors from the decompressor itself mean the archive is bad; retrying
+/// would only hide the corruption.
+fn is_decompress_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidInput
+    )
+}
+
+/// Write errors worth another attempt: interruptions, timeouts and the
+/// sporadic EIO of flaky storage. A full disk or a permission problem won't
+/// go away by retrying.
+fn is_transient_io_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    ) || e.raw_os_error() == Some(libc::EIO)
+}
+
+/// Writes the contents of entry `i` to `out_path`, replacing any partial
+/// output left by a previous attempt.
+fn write_entry(
+    archive: &mut Archive,
+    i: usize,
+    out_path: &Path,
+    buf: &mut [u8],
+) -> Result<(), io::Error> {
+    let mut file = archive
+        .by_index(i)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut out_file = fs::File::create(out_path)?;
+    loop {
+        let n = match io::Read::read(&mut file, buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        io::Write::write_all(&mut out_file, &buf[..n])?;
+    }
+}
+
+fn write_entry_with_retries(
+    cfg: &Config,
+    archive: &mut Archive,
+    i: usize,
+    entry: &ArchiveEntry,
+    out_path: &Path,
+    buf: &mut [u8],
+) -> Result<(), UpdateError> {
+    let mut attempt = 0;
+    loop {
+        match write_entry(archive, i, out_path, buf) {
+            Ok(()) => return Ok(()),
+            Err(e) if is_transient_io_error(&e) && attempt < cfg.extract_io_retries => {
+                attempt += 1;
+                tracing::warn!(
+                    "{}, retrying ({}/{})",
+                    entry_failure(&entry.name, "write", &e),
+                    attempt,
+                    cfg.extract_io_retries
+                );
+                thread::sleep(EXTRACT_RETRY_DELAY);
+            }
+            Err(e) if is_decompress_error(&e) => {
+                return Err(UpdateError::ArchiveError(entry_failure(
+                    &entry.name,
+                    "decompress",
+                    e,
+                )))
+            }
+            Err(e) => {
+                return Err(UpdateError::FileSystemError(entry_failure(
+                    &entry.name,
+                    &format!("write to {:?}", out_path),
+                    e,
+                )))
+            }
+        }
+    }
+}
+
+/// Inodes kept free beyond one per archive entry.
+const INODE_MARGIN: u64 = 64;
+
+/// Fails early when the filesystem holding `o` can't fit `entries` more
+/// files. Filesystems that don't report inode counts are not checked.
+fn check_free_inodes(o: &Path, entries: usize) -> Result<(), UpdateError> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Some(existing) = o.ancestors().find(|p| p.exists()) else {
+        return Ok(());
+    };
+    let Ok(c_path) = std::ffi::CString::new(existing.as_os_str().as_bytes()) else {
+        return Ok(());
+    };
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is NUL-terminated and `stat` is only read on success.
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        tracing::debug!(
+            "statvfs of {:?} failed, skipping inode check: {}",
+            existing,
+            io::Error::last_os_error()
+        );
+        return Ok(());
+    }
+    let stat = unsafe { stat.assume_init() };
+    if stat.f_files == 0 {
+        return Ok(());
+    }
+
+    let need = entries as u64 + INODE_MARGIN;
+    // `fsfilcnt_t` is only 32 bits wide on some targets.
+    #[allow(clippy::unnecessary_cast)]
+    let have = stat.f_favail as u64;
+    if have < need {
+        return Err(UpdateError::FileSystemError(format!(
+            "insufficient inodes: need {}, have {}",
+            need, have
+        )));
+    }
+    Ok(())
+}
+
+/// Extracts the archive `source` into `o`.
+///
+/// Extraction is blocking file IO; code on the async runtime must run it on
+/// the blocking pool (`spawn_blocking`) so the reactor stays responsive.
+/// Entry data is copied through a single `extract_buffer_bytes` buffer.
+///
+/// With `resume_extraction`, an interrupted extraction of the same archive
+/// picks up where it stopped instead of starting over. `cancel` is checked
+/// between entries. With `durable_writes`, the tree is on disk when this
+/// returns.
+///
+/// A staged extraction leaves the new tree next to `o` until it is swapped
+/// in with `Extraction::swap_in`.
+pub fn unzip_update(
+    cfg: &Config,
+    source: &ArchiveSource,
+    o: &Path,
+    cancel: &CancellationToken,
+) -> Result<Extraction, UpdateError> {
+    if !cfg.extracts_via_staging() {
+        extract_into(cfg, source, o, cancel)?;
+        return Ok(Extraction {
+            dir: o.to_path_buf(),
+            staging: None,
+            durable: cfg.durable_writes,
+        });
+    }
+
+    // The previous tree stays untouched until the new one is complete.
+    let mut staging = o.as_os_str().to_owned();
+    staging.push(".staging");
+    let staging = PathBuf::from(staging);
+    if resumable_progress(cfg, source, &staging).is_none() {
+        ExtractProgress::remove(&staging);
+        remove_dir_if_exists(&staging)?;
+    }
+    if let Err(e) = extract_into(cfg, source, &staging, cancel) {
+        // A partial staging tree is only worth keeping to resume from.
+        let resumable = cfg.resume_extraction && !matches!(e, UpdateError::ArchiveError(_));
+        if !resumable {
+            if let Err(cleanup) = remove_dir_if_exists(&staging) {
+                tracing::warn!("{}", cleanup);
+            }
+        }
+        return Err(e);
+    }
+    Ok(Extraction {
+        dir: o.to_path_buf(),
+        staging: Some(staging),
+        durable: cfg.durable_writes,
+    })
+}
+
+/// A tree `unzip_update` extracted, either in place or staged next to its
+/// target. Swapping in and discarding are blocking file IO, like extraction.
+pub struct Extraction {
+    dir: PathBuf,
+    staging: Option<PathBuf>,
+    durable: bool,
+}
+
+impl Extraction {
+    /// Where the new tree is: the staging directory until it is swapped in.
+    pub fn path(&self) -> &Path {
+        self.staging.as_deref().unwrap_or(&self.dir)
+    }
+
+    /// Moves a staged tree into place, returning the tree it replaced, kept
+    /// aside until the update is known good or rolled back.
+    pub fn swap_in(self) -> Result<Option<ReplacedTree>, UpdateError> {
+        let Some(staging) = self.staging else {
+            return Ok(None);
+        };
+        let replaced = ReplacedTree::set_aside(&self.dir, self.durable)?;
+        if let Err(e) = fs::rename(&staging, &self.dir) {
+            let e = UpdateError::FileSystemError(format!(
+                "Failed to move {:?} into place at {:?}: {}",
+                staging, self.dir, e
+            ));
+            if let Err(restore) = replaced.restore() {
+                tracing::error!("{}", restore);
+            }
+            return Err(e);
+        }
+        if self.durable {
+            durable::sync_file_and_dir(&self.dir)?;
+        }
+        tracing::debug!("replaced {:?} with a clean extraction", self.dir);
+        Ok(Some(replaced))
+    }
+
+    /// Removes a staged tree that won't be installed. A tree extracted in
+    /// place is left to the caller.
+    pub fn discard_staged(self) {
+        if let Some(staging) = self.staging {
+            if let Err(e) = remove_dir_if_exists(&staging) {
+                tracing::warn!("{}", e);
+            }
+        }
+    }
+}
+
+/// The tree a staged extraction replaced, renamed to `<dir>.previous` so a
+/// failed update can be undone. Restoring and discarding are blocking file
+/// IO, like extraction.
+pub struct ReplacedTree {
+    dir: PathBuf,
+    previous: PathBuf,
+    durable: bool,
+}
+
+impl ReplacedTree {
+    fn set_aside(dir: &Path, durable: bool) -> Result<Self, UpdateError> {
+        let mut previous = dir.as_os_str().to_owned();
+        previous.push(".previous");
+        let replaced = ReplacedTree {
+            dir: dir.to_path_buf(),
+            previous: PathBuf::from(previous),
+            durable,
+        };
+        // Without `dir`, a `.previous` left by an interrupted swap is still
+        // the last good tree.
+        if dir.exists() {
+            remove_dir_if_exists(&replaced.previous)?;
+            fs::rename(dir, &replaced.previous).map_err(|e| {
+                UpdateError::FileSystemError(format!("Failed to move {:?} aside: {}", dir, e))
+            })?;
+        }
+        Ok(replaced)
+    }
+
+    /// Puts the previous tree back in place of the new one, or just removes
+    /// the new one when there was none before.
+    pub fn restore(self) -> Result<(), UpdateError> {
+        remove_dir_if_exists(&self.dir)?;
+        if self.previous.exists() {
+            fs::rename(&self.previous, &self.dir).map_err(|e| {
+                UpdateError::FileSystemError(format!(
+                    "Failed to restore {:?} from {:?}: {}",
+                    self.dir, self.previous, e
+                ))
+            })?;
+            if self.durable {
+                durable::sync_file_and_dir(&self.dir)?;
+            }
+        }
+        tracing::info!("restored the previous tree at {:?}", self.dir);
+        Ok(())
+    }
+
+    /// Removes the previous tree once the new one is kept.
+    pub fn discard(self) {
+        if let Err(e) = remove_dir_if_exists(&self.previous) {
+            tracing::warn!("{}", e);
+        }
+    }
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<(), UpdateError> {
+    match fs::remove_dir_all(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(UpdateError::FileSystemError(
+            format!("Failed to remove {:?}: {}", path, e),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn extract_into(
+    cfg: &Config,
+    source: &ArchiveSource,
+    o: &Path,
+    cancel: &CancellationToken,
+) -> Result<(), UpdateError> {
+    let mut archive = open_archive(source)?;
+
+    tracing::debug!("archive len {}", archive.len());
+    check_free_inodes(o, archive.len())?;
+
+    let resume_from = resumable_progress(cfg, source, o).map_or(0, |progress| progress.completed);
+    if resume_from > 0 {
+        tracing::info!(
+            "Resuming extraction into {:?} after entry {} of {}",
+            o,
+            resume_from,
+            archive.len()
+        );
+    }
+    let mut progress = cfg.resume_extraction.then(|| ExtractProgress {
+        archive_bytes: source.len().unwrap_or(0),
+        entries: archive.len(),
+        completed: resume_from,
+    });
+
+    let result = extract_entries(cfg, &mut archive, o, resume_from, &mut progress, cancel)
+        .and_then(|files| {
+            verify_extracted_size(cfg, &files)?;
+            if cfg.durable_writes {
+                durable::sync_extracted(o, &files)?;
+            }
+            Ok(())
+        });
+    match &result {
+        Ok(()) => ExtractProgress::remove(o),
+        // Progress through a corrupt archive is worthless for the next one.
+        Err(UpdateError::ArchiveError(_)) => ExtractProgress::remove(o),
+        Err(_) => {
+            if let Some(progress) = &progress {
+                progress.save(o);
+            }
+        }
+    }
+    result?;
+
+    tracing::debug!("unzipping done");
+
+    Ok(())
+}
+
+fn extract_entries(
+    cfg: &Config,
+    archive: &mut Archive,
+    o: &Path,
+    resume_from: usize,
+    progress: &mut Option<ExtractProgress>,
+    cancel: &CancellationToken,
+) -> Result<Vec<(PathBuf, u64)>, UpdateError> {
+    let filter = EntryFilter::new(cfg)?;
+    let mut files = Vec::new();
+    let mut buf = vec![0; cfg.extract_buffer_bytes.max(1)];
+    let top_level_dir = if cfg.strip_top_level_dir {
+        let top = single_top_level_dir(archive)?;
+        tracing::debug!("stripping top-level directory {:?}", top);
+        Some(top)
+    } else {
+        None
+    };
+
+    for i in 0..archive.len() {
+        if cancel.is_cancelled() {
+            return Err(UpdateError::Cancelled);
+        }
+        if let Some(progress) = progress.as_mut() {
+            if i > progress.completed {
+                progress.completed = i;
+                if i % PROGRESS_SAVE_INTERVAL == 0 {
+                    progress.save(o);
+                }
+            }
+        }
+
+        let entry = archive
+            .by_index_raw(i)
+            .map(|file| ArchiveEntry::from_zip(&file))
+            .map_err(|e| UpdateError::ArchiveError(entry_failure(&format!("#{}", i), "read", e)))?;
+
+        let relative_path = match (&entry.enclosed_path, &top_level_dir) {
+            (Some(path), Some(top)) => match path.strip_prefix(top) {
+                Ok(stripped) if stripped.as_os_str().is_empty() => continue,
+                Ok(stripped) => Some(stripped.to_path_buf()),
+                Err(_) => continue,
+            },
+            (path, None) => path.clone(),
+            (None, Some(_)) => None,
+        };
+        let out_path = match &relative_path {
+            Some(path) if filter.accepts(path) => {
+                let mut p = PathBuf::from(o);
+                p.push(path);
+                p
+            }
+            Some(_) => {
+                tracing::debug!("skipping filtered entry {}", entry.name);
+                continue;
+            }
+            None => continue,
+        };
+        check_compression_ratio(cfg, &entry)?;
+
+        if cfg.extract_policy == ExtractPolicy::MergeKeep
+            && !entry.is_dir
+            && out_path.symlink_metadata().is_ok()
+        {
+            tracing::debug!("keeping existing {:?}", out_path);
+            continue;
+        }
+
+        if i < resume_from && !entry.is_dir && already_extracted(&entry, &out_path) {
+            tracing::debug!("already extracted {:?}", out_path);
+            files.push((out_path, entry.uncompressed_size));
+            continue;
+        }
+
+        if entry.is_dir {
+            create_dir(&entry, &out_path)?;
+        } else {
+            if let Some(p) = out_path.parent() {
+                if !p.exists() {
+                    create_dir(&entry, p)?;
+                }
+            }
+            write_entry_with_retries(cfg, archive, i, &entry, &out_path, &mut buf)?;
+            apply_mtime(&entry, &out_path);
+            files.push((out_path.clone(), entry.uncompressed_size));
+        }
+
+        // Get and Set permissions
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            if let Some(mode) = entry.unix_mode {
+                fs::set_permissions(&out_path, fs::Permissions::from_mode(mode)).map_err(|e| {
+                    UpdateError::FileSystemError(entry_failure(
+                        &entry.name,
+                        &format!("set permissions {:o}", mode),
+                        e,
+                    ))
+                })?;
+            }
+        }
+    }
+    if let Some(progress) = progress.as_mut() {
+        progress.completed = archive.len();
+    }
+    Ok(files)
+}
+
+/// Compares the total on-disk size of the extracted `files` with the total
+/// the archive declared for them, catching storage that truncated a write
+/// without reporting an error. Differences up to
+/// `extracted_size_tolerance_bytes` are accepted.
+fn verify_extracted_size(cfg: &Config, files: &[(PathBuf, u64)]) -> Result<(), UpdateError> {
+    if !cfg.verify_extracted_size {
+        return Ok(());
+    }
+
+    let declared: u64 = files.iter().map(|(_, size)| size).sum();
+    let mut on_disk: u64 = 0;
+    for (path, _) in files {
+        let meta = path.symlink_metadata().map_err(|e| {
+            UpdateError::FileSystemError(format!("Failed to stat extracted file {:?}: {}", path, e))
+        })?;
+        on_disk += meta.len();
+    }
+
+    if declared.abs_diff(on_disk) > cfg.extracted_size_tolerance_bytes {
+        return Err(UpdateError::FileSystemError(format!(
+            "extracted files take {} bytes but the archive declares {} (tolerance {})",
+            on_disk, declared, cfg.extracted_size_tolerance_bytes
+        )));
+    }
+    tracing::debug!("extracted size verified: {} bytes", on_disk);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::io::Write;
+
+    #[test]
+    fn permanent_write_error_is_not_retried() {
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        zip.start_file("version.txt", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"42\n").unwrap();
+        let data = zip.finish().unwrap().into_inner();
+        let mut archive = open_archive(&ArchiveSource::Memory(data.into())).unwrap();
+        let entry = ArchiveEntry::from_zip(&archive.by_index(0).unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        // The parent directory is missing, which no retry fixes.
+        let out_path = dir.path().join("missing/version.txt");
+        let cfg = test_support::config("extract_io_retries = 3");
+
+        let started = std::time::Instant::now();
+        let result =
+            write_entry_with_retries(&cfg, &mut archive, 0, &entry, &out_path, &mut [0; 1024]);
+
+        assert!(
+            matches!(result, Err(UpdateError::FileSystemError(_))),
+            "{:?}",
+            result
+        );
+        assert!(started.elapsed() < EXTRACT_RETRY_DELAY);
+        assert!(!is_transient_io_error(&io::Error::from_raw_os_error(
+            libc::ENOSPC
+        )));
+        assert!(is_transient_io_error(&io::Error::from_raw_os_error(
+            libc::EIO
+        )));
+    }
+
+    #[test]
+    fn extraction_keeps_archive_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("update.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        let modified = zip::DateTime::from_date_and_time(2021, 3, 4, 5, 6, 8).unwrap();
+        zip.start_file(
+            "app/version.txt",
+            zip::write::SimpleFileOptions::default().last_modified_time(modified),
+        )
+        .unwrap();
+        zip.write_all(b"42\n").unwrap();
+        zip.finish().unwrap();
+
+        let out = dir.path().join("tree");
+        unzip_update(
+            &test_support::config(""),
+            &ArchiveSource::File(archive),
+            &out,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+
+        let meta = fs::metadata(out.join("app/version.txt")).unwrap();
+        // 2021-03-04 05:06:08 UTC
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&meta).unix_seconds(),
+            1_614_834_368
+        );
+    }
+
+    #[test]
+    fn clean_extraction_can_be_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("update.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        zip.start_file("new.txt", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"2\n").unwrap();
+        zip.finish().unwrap();
+        let out = dir.path().join("tree");
+        fs::create_dir(&out).unwrap();
+        fs::write(out.join("old.txt"), "1\n").unwrap();
+
+        let extraction = unzip_update(
+            &test_support::config("extract_policy = \"clean\""),
+            &ArchiveSource::File(archive),
+            &out,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert!(extraction.path().join("new.txt").exists());
+        assert!(out.join("old.txt").exists());
+
+        let replaced = extraction.swap_in().unwrap().unwrap();
+        assert!(out.join("new.txt").exists());
+        assert!(!out.join("old.txt").exists());
+
+        replaced.restore().unwrap();
+        assert!(out.join("old.txt").exists());
+        assert!(!out.join("new.txt").exists());
+        assert!(!dir.path().join("tree.previous").exists());
+    }
+}
@@ -0,0 +1,164 @@
+use crate::cancel;
+use crate::config::Config;
+use crate::error::UpdateError;
+use std::{
+    io::Read,
+    os::unix::process::CommandExt,
+    process::{Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+/// How often a command run with a timeout is checked for having exited.
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Runs an operator-supplied shell command and returns its trimmed stdout,
+/// failing when it exits nonzero.
+pub fn run_output_command(name: &str, command: &str) -> Result<String, UpdateError> {
+    tracing::debug!("Running {} command: {}", name, command);
+
+    let output = Command::new("/bin/sh")
+        .args(["-c", command])
+        .output()
+        .map_err(|e| {
+            UpdateError::HookError(format!("Failed to execute {} command: {}", name, e))
+        })?;
+
+    if !output.status.success() {
+        return Err(UpdateError::HookError(format!(
+            "{} command exited with status {:?}: {}",
+            name,
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// `run_gate_command_with_timeout` with `hook_timeout_seconds`, on the
+/// blocking pool. The command is killed as well when this future is
+/// dropped, e.g. by the cycle timeout.
+pub async fn run_gate_hook(
+    cfg: &Config,
+    name: &str,
+    command: &str,
+    cancel: &CancellationToken,
+) -> Result<bool, UpdateError> {
+    let (name, command) = (name.to_string(), command.to_string());
+    let timeout = Duration::from_secs(cfg.hook_timeout_seconds);
+    cancel::run_blocking("hook", cancel, move |cancel| {
+        run_gate_command_with_timeout(&name, &command, timeout, cancel)
+    })
+    .await
+}
+
+/// Runs an operator-supplied shell command used as a gate.
+///
+/// Returns `Ok(true)` when the command exits with status 0, `Ok(false)` when it
+/// exits nonzero (a veto), and an error when the command could not be run. A
+/// command still running after `timeout` is killed, along with whatever it
+/// started, and reported as an error. It is killed as well once `cancel`
+/// fires, failing with `Cancelled`.
+///
+/// This blocks until the command exits; code on the async runtime must run
+/// it on the blocking pool.
+pub fn run_gate_command_with_timeout(
+    name: &str,
+    command: &str,
+    timeout: Duration,
+    cancel: &CancellationToken,
+) -> Result<bool, UpdateError> {
+    let (status, stdout, stderr) = run_with_timeout(name, command, timeout, cancel)?;
+    if status.success() {
+        return Ok(true);
+    }
+    tracing::debug!(
+        "{} command exited with status {:?}. STDOUT:\n{}\nSTDERR:\n{}",
+        name,
+        status.code(),
+        stdout,
+        stderr
+    );
+    Ok(false)
+}
+
+/// Like `run_output_command`, but killed after `timeout` or once `cancel`
+/// fires as `run_gate_command_with_timeout` is. Blocks as well.
+pub fn run_output_command_with_timeout(
+    name: &str,
+    command: &str,
+    timeout: Duration,
+    cancel: &CancellationToken,
+) -> Result<String, UpdateError> {
+    let (status, stdout, stderr) = run_with_timeout(name, command, timeout, cancel)?;
+    if !status.success() {
+        return Err(UpdateError::HookError(format!(
+            "{} command exited with status {:?}: {}",
+            name,
+            status.code(),
+            stderr.trim()
+        )));
+    }
+    Ok(stdout.trim().to_string())
+}
+
+/// Runs `command`, killing its process group after `timeout` or once
+/// `cancel` fires, and returns its exit status, stdout and stderr.
+fn run_with_timeout(
+    name: &str,
+    command: &str,
+    timeout: Duration,
+    cancel: &CancellationToken,
+) -> Result<(ExitStatus, String, String), UpdateError> {
+    tracing::debug!("Running {} command: {}", name, command);
+
+    let mut child = Command::new("/bin/sh")
+        .args(["-c", command])
+        .process_group(0)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            UpdateError::HookError(format!("Failed to execute {} command: {}", name, e))
+        })?;
+    // Drained while waiting, so a chatty command can't block on a full pipe.
+    let drain = |mut pipe: Box<dyn Read + Send>| {
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            pipe.read_to_end(&mut output).ok();
+            String::from_utf8_lossy(&output).into_owned()
+        })
+    };
+    let stdout = drain(Box::new(child.stdout.take().unwrap()));
+    let stderr = drain(Box::new(child.stderr.take().unwrap()));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        let status = child.try_wait().map_err(|e| {
+            UpdateError::HookError(format!("Failed to wait for {} command: {}", name, e))
+        })?;
+        if let Some(status) = status {
+            break status;
+        }
+        let timed_out = Instant::now() >= deadline;
+        if timed_out || cancel.is_cancelled() {
+            // SAFETY: kill has no memory-safety preconditions; the negative
+            // pid addresses the command's own process group.
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            child.wait().ok();
+            if !timed_out {
+                return Err(UpdateError::Cancelled);
+            }
+            return Err(UpdateError::HookError(format!(
+                "{} command timed out after {} seconds",
+                name,
+                timeout.as_secs()
+            )));
+        }
+        thread::sleep(TIMEOUT_POLL_INTERVAL);
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    Ok((status, stdout, stderr))
+}
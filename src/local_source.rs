@@ -0,0 +1,104 @@
+use crate::api_client::UpdateInfo;
+use crate::error::UpdateError;
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Metadata file describing the archive in a local update source.
+pub const METADATA_FILE_NAME: &str = "update.json";
+
+#[derive(Deserialize, Debug)]
+struct LocalMetadata {
+    #[serde(rename = "versionCode")]
+    version_code: i32,
+    /// Archive file name, relative to the source directory.
+    file: String,
+    #[serde(default)]
+    checksum: Option<String>,
+    #[serde(default, rename = "releaseNotes")]
+    release_notes: Option<String>,
+    #[serde(default, rename = "compatibleModels")]
+    compatible_models: Vec<String>,
+}
+
+/// Looks for an update in `dir` (e.g. a mounted USB drive), returning its
+/// description and archive path, or `None` when the directory holds none.
+pub fn find_update(dir: &Path) -> Result<Option<(UpdateInfo, PathBuf)>, UpdateError> {
+    let metadata_path = dir.join(METADATA_FILE_NAME);
+    if !metadata_path.exists() {
+        return Ok(None);
+    }
+
+    let data = fs::read_to_string(&metadata_path).map_err(|e| {
+        UpdateError::FileSystemError(format!(
+            "Failed to read local update metadata {:?}: {}",
+            metadata_path, e
+        ))
+    })?;
+    let metadata: LocalMetadata = serde_json::from_str(&data).map_err(|e| {
+        UpdateError::FileSystemError(format!(
+            "Invalid local update metadata {:?}: {}",
+            metadata_path, e
+        ))
+    })?;
+
+    // A plain name: no directories, `..` or absolute path leading out of `dir`.
+    if Path::new(&metadata.file).file_name() != Some(metadata.file.as_ref()) {
+        return Err(UpdateError::FileSystemError(format!(
+            "Local update archive name '{}' must be a plain file name",
+            metadata.file
+        )));
+    }
+    let archive_path = dir.join(&metadata.file);
+    if !archive_path.is_file() {
+        return Err(UpdateError::FileSystemError(format!(
+            "Local update archive {:?} not found",
+            archive_path
+        )));
+    }
+
+    let update_info = UpdateInfo {
+        version_code: metadata.version_code,
+        file_url: archive_path.to_string_lossy().into_owned(),
+        checksum: metadata.checksum,
+        chunk_hashes: None,
+        size: None,
+        release_notes: metadata.release_notes,
+        poll_interval_seconds: None,
+        retry_after_seconds: None,
+        reboot_required: None,
+        compatible_models: metadata.compatible_models,
+        canary_percent: None,
+    };
+    Ok(Some((update_info, archive_path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_name_must_stay_in_the_source_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("update.zip"), b"zip").unwrap();
+        for file in ["../update.zip", "/etc/passwd", "..", ".", "sub/update.zip"] {
+            fs::write(
+                dir.path().join(METADATA_FILE_NAME),
+                serde_json::json!({ "versionCode": 2, "file": file }).to_string(),
+            )
+            .unwrap();
+            assert!(find_update(dir.path()).is_err(), "{} was accepted", file);
+        }
+
+        fs::write(
+            dir.path().join(METADATA_FILE_NAME),
+            r#"{"versionCode": 2, "file": "update.zip"}"#,
+        )
+        .unwrap();
+        let (update_info, archive) = find_update(dir.path()).unwrap().unwrap();
+        assert_eq!(update_info.version_code, 2);
+        assert_eq!(archive, dir.path().join("update.zip"));
+    }
+}
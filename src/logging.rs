@@ -0,0 +1,58 @@
+use crate::config::Config;
+use crate::error::UpdateError;
+use crate::telemetry::Telemetry;
+use rolling_file::{BasicRollingFileAppender, RollingConditionBasic};
+use std::{fs, sync::Mutex};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, prelude::*};
+
+/// Installs the global tracing subscriber. Logs go to `log_file` with
+/// size-based rotation when `config` sets one, and to stdout otherwise, or
+/// to stderr when stdout shows the `--tail-status` line.
+/// Spans are also exported when `otlp_endpoint` is set; the returned
+/// `Telemetry` must be kept alive for that.
+///
+/// Called once at startup; a reloaded config does not move the log.
+pub fn init(config: Option<&Config>, tail_status: bool) -> Result<Telemetry, UpdateError> {
+    let log_file = config.and_then(|c| c.log_file.as_ref().map(|path| (c, path)));
+    let writer = match log_file {
+        Some((config, path)) => {
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                fs::create_dir_all(dir).map_err(|e| {
+                    UpdateError::FileSystemError(format!(
+                        "Failed to create log directory {:?}: {}",
+                        dir, e
+                    ))
+                })?;
+            }
+            // Unbuffered, so the tail of the log survives the process being killed.
+            let appender = BasicRollingFileAppender::new_with_buffer_capacity(
+                path,
+                RollingConditionBasic::new().max_size(config.log_file_max_bytes),
+                config.log_file_keep,
+                0,
+            )
+            .map_err(|e| {
+                UpdateError::FileSystemError(format!("Failed to open log file {:?}: {}", path, e))
+            })?;
+            BoxMakeWriter::new(Mutex::new(appender))
+        }
+        None if tail_status => BoxMakeWriter::new(std::io::stderr),
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+
+    let (telemetry, otlp_layer) = Telemetry::init(config)?;
+    tracing_subscriber::registry()
+        .with(otlp_layer)
+        .with(
+            tracing_subscriber::EnvFilter::from_default_env()
+                .add_directive("embedded_updater=info".parse().unwrap()),
+        )
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_timer(tracing_subscriber::fmt::time::UtcTime::rfc_3339())
+                .with_ansi(log_file.is_none())
+                .with_writer(writer),
+        )
+        .init();
+    Ok(telemetry)
+}
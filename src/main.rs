@@ -1,299 +1,2109 @@
+mod ab;
 mod api_client;
+mod build_info;
+mod cache;
+mod canary;
+mod cancel;
+mod cert_expiry;
+mod chunks;
+mod cli;
 mod config;
+mod crypto;
+mod defaults;
+mod download_state;
+mod durable;
 mod error;
-use api_client::ApiClient;
-use config::{get_current_version, Config};
+mod extract;
+mod hooks;
+mod local_source;
+mod logging;
+mod meminfo;
+mod outcome;
+mod plan;
+mod quarantine;
+mod random;
+mod reboot;
+mod recover;
+mod retry;
+pub mod script;
+mod selftest;
+mod shutdown;
+mod signing;
+mod snapshot;
+mod status_line;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod timings;
+mod version_history;
+mod watchdog;
+use api_client::{ApiClient, StatusReportPayload, UpdateInfo};
+use cache::ArtifactCache;
+use cancel::OperatorCancel;
+use cli::CliArgs;
+use config::{get_current_version, record_current_version, Config, FirstBootPolicy};
+use download_state::DownloadState;
 use error::UpdateError;
+use extract::{ArchiveSource, ReplacedTree};
+use outcome::{CycleOutcome, CycleReport};
+use quarantine::FailureRecord;
+use shutdown::FlushOnTerminate;
 use std::{
-    env, fs, io,
-    os::unix::fs::PermissionsExt,
+    env, fs,
     path::{Path, PathBuf},
     process::Command,
+    sync::atomic::{AtomicBool, Ordering},
 };
+use timings::PhaseTimings;
 use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
 
-fn unzip_update(p: &Path, o: &Path) -> Result<(), UpdateError> {
-    let f = fs::File::open(p)
-        .map_err(|e| UpdateError::FileSystemError(format!("Failed to open zipped files: {}", e)))?;
-
-    let mut archive = zip::ZipArchive::new(f)
-        .map_err(|e| UpdateError::ArchiveError(format!("Failed to extract zipped files: {}", e)))?;
+fn probe_download_dir(dir: &Path) -> Result<(), UpdateError> {
+    let probe_path = dir.join(".write_probe");
+    fs::write(&probe_path, b"probe")
+        .and_then(|_| fs::remove_file(&probe_path))
+        .map_err(|e| {
+            UpdateError::FileSystemError(format!(
+                "download directory {:?} is not writable (filesystem read-only?): {}",
+                dir, e
+            ))
+        })
+}
 
-    tracing::debug!("archive len {}", archive.len());
+/// Removes leftovers of an aborted cycle. Partially downloaded archives are
+/// kept so the next cycle can resume them.
+fn cleanup_in_progress_files(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::warn!("Failed to list {:?} for cleanup: {}", dir, e);
+            return;
+        }
+    };
 
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i).map_err(|e| {
-            UpdateError::ArchiveError(format!("Failed to extract zipped files: {}", e))
-        })?;
-        let out_path = match file.enclosed_name() {
-            Some(path) => {
-                let mut p = PathBuf::from(o);
-                p.push(path);
-                p
-            }
-            None => continue,
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_temp = path.file_name().is_some_and(|n| n == ".write_probe")
+            || path.extension().is_some_and(|ext| ext == "tmp");
+        if !is_temp {
+            continue;
+        }
+        let result = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
         };
+        match result {
+            Ok(_) => tracing::debug!("Removed in-progress file {:?}", path),
+            Err(e) => tracing::warn!("Failed to remove in-progress file {:?}: {}", path, e),
+        }
+    }
+}
 
-        if file.is_dir() {
-            fs::create_dir_all(&out_path).unwrap();
-        } else {
-            if let Some(p) = out_path.parent() {
-                if !p.exists() {
-                    fs::create_dir_all(p).unwrap();
-                }
-            }
-            let mut out_file = fs::File::create(&out_path).unwrap();
-            io::copy(&mut file, &mut out_file).unwrap();
+/// Poll interval after a regular cycle: the server's request clamped to
+/// `min_poll_interval_seconds..=max_poll_interval_seconds`, or the
+/// configured one.
+fn next_poll_interval(cfg: &Config, requested: Option<u64>) -> u64 {
+    match requested {
+        Some(seconds) => seconds.clamp(
+            cfg.min_poll_interval_seconds,
+            cfg.max_poll_interval_seconds
+                .max(cfg.min_poll_interval_seconds),
+        ),
+        None => cfg.poll_interval_seconds,
+    }
+}
+
+/// Poll interval used while the server rejects the device token.
+const UNAUTHORIZED_POLL_INTERVAL_SECONDS: u64 = 60 * 60;
+
+/// Name of the artifact, taken from the last path segment of its URL (query
+/// and fragment ignored). Falls back to a name derived from the version when
+/// the URL has no usable segment, and rejects names that could escape
+/// `download_base_dir`.
+fn artifact_name(update_info: &UpdateInfo) -> Result<String, UpdateError> {
+    let path = match reqwest::Url::parse(&update_info.file_url) {
+        Ok(url) => url.path().to_string(),
+        // Local sources use plain filesystem paths.
+        Err(_) => update_info
+            .file_url
+            .split(['?', '#'])
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+    };
+    let segment = path.rsplit('/').next().unwrap_or_default();
+
+    if segment.is_empty() || segment == "." {
+        return Ok(format!("update-{}", update_info.version_code));
+    }
+    if segment == ".." || segment.contains(['\\', '\0']) {
+        return Err(UpdateError::FileSystemError(format!(
+            "Refusing artifact name {:?} from file URL {}",
+            segment, update_info.file_url
+        )));
+    }
+    Ok(segment.to_string())
+}
+
+/// Probe run by `check_service_enabled` when no `service_check_command` is set.
+const DEFAULT_SERVICE_CHECK_COMMAND: &str = "systemctl is-enabled --quiet {service}";
+
+/// Whether the service being updated is installed and enabled on this device,
+/// according to `service_check_command`, run off the reactor and killed after
+/// `hook_timeout_seconds`. Always true unless `check_service_enabled` is set;
+/// a probe that can't run or times out counts as inactive.
+async fn target_service_active(
+    cfg: &Config,
+    cancel: &CancellationToken,
+) -> Result<bool, UpdateError> {
+    if !cfg.check_service_enabled {
+        return Ok(true);
+    }
+    let command = cfg
+        .service_check_command
+        .as_deref()
+        .unwrap_or(DEFAULT_SERVICE_CHECK_COMMAND)
+        .replace("{service}", &cfg.service_name);
+    match hooks::run_gate_hook(cfg, "service check", &command, cancel).await {
+        Err(UpdateError::Cancelled) => Err(UpdateError::Cancelled),
+        Err(e) => {
+            tracing::warn!("{}", e);
+            Ok(false)
         }
+        Ok(active) => Ok(active),
+    }
+}
 
-        // Get and Set permissions
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
+/// Whether the last cycle found the target service inactive, so the skip is
+/// only reported when that changes rather than on every poll.
+static TARGET_SERVICE_INACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the artifact of `update_info` is small enough to be kept in
+/// memory instead of the download directory (`in_memory_threshold_bytes`).
+fn keeps_in_memory(cfg: &Config, update_info: &UpdateInfo) -> bool {
+    cfg.in_memory_threshold_bytes > 0
+        && !cfg.keep_downloaded_archive
+        && cfg.cache_dir.is_none()
+        && update_info
+            .size
+            .is_some_and(|size| size <= cfg.in_memory_threshold_bytes)
+}
 
-            if let Some(mode) = file.unix_mode() {
-                fs::set_permissions(&out_path, fs::Permissions::from_mode(mode)).unwrap();
+/// Downloads the artifact of `update_info`, into `memory` when given and to
+/// `download_path` otherwise. When `abort_superseded_downloads` is
+/// enabled, the server is re-checked periodically meanwhile and the download is
+/// abandoned as soon as a strictly newer version shows up, which is returned.
+async fn download_superseding(
+    cfg: &Config,
+    api: &ApiClient,
+    update_info: &UpdateInfo,
+    download_path: &Path,
+    memory: Option<&mut Vec<u8>>,
+) -> Result<Option<UpdateInfo>, UpdateError> {
+    let chunks = update_info.chunk_hashes.as_ref();
+    let download = async {
+        match memory {
+            Some(memory) => {
+                *memory = api
+                    .download_to_memory(
+                        &update_info.file_url,
+                        chunks,
+                        cfg.in_memory_threshold_bytes,
+                    )
+                    .await?;
+                Ok(())
+            }
+            None => {
+                api.download_update(&update_info.file_url, download_path, chunks)
+                    .await
             }
         }
+    };
+    if !cfg.abort_superseded_downloads {
+        return download.await.map(|_| None);
     }
 
-    tracing::debug!("unzipping done");
+    tokio::pin!(download);
+    let period = Duration::from_secs(cfg.supersede_check_interval_seconds.max(1));
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        tokio::select! {
+            result = &mut download => return result.map(|_| None),
+            _ = ticker.tick() => match api.check_for_updates().await {
+                Ok(latest) if latest.version_code > update_info.version_code => {
+                    return Ok(Some(latest));
+                }
+                Ok(_) => {}
+                Err(e) => tracing::debug!("supersede check failed: {}", e),
+            },
+        }
+    }
+}
 
+/// Checks the key id in the header of an encrypted artifact against the
+/// configured key before downloading the rest, for formats that carry one.
+async fn check_key_id_early(
+    cfg: &Config,
+    api: &ApiClient,
+    update_info: &UpdateInfo,
+) -> Result<(), UpdateError> {
+    if !cfg.encrypted_updates {
+        return Ok(());
+    }
+    let Some(prefix_len) = cfg.encryption_format.key_id_prefix_len() else {
+        return Ok(());
+    };
+    let prefix = api.fetch_prefix(&update_info.file_url, prefix_len).await?;
+    crypto::check_key_id(&prefix, &cfg.get_decryption_keys()?, cfg.encryption_format)?;
     Ok(())
 }
 
-pub fn run_update_script(
+/// Reports an update stopped by the operator. Unlike a failure this doesn't
+/// count towards quarantining the version.
+async fn report_cancelled(api: &ApiClient, current_version: i32, update_info: &UpdateInfo) {
+    tracing::warn!(
+        "update to version {} cancelled by operator",
+        update_info.version_code
+    );
+    api.send_status(transition_status(
+        current_version,
+        update_info,
+        "cancelled by operator".to_string(),
+    ))
+    .await
+    .ok();
+}
+
+/// The decrypted copy of the download at `download_path`, if `archive` is one.
+fn decrypted_copy<'a>(download_path: &Path, archive: &'a ArchiveSource) -> Option<&'a Path> {
+    match archive {
+        ArchiveSource::File(path) if path != download_path => Some(path.as_path()),
+        _ => None,
+    }
+}
+
+/// Removes the downloaded artifact once it has been extracted, or keeps it
+/// under a versioned name when `keep_downloaded_archive` is set. A decrypted
+/// copy at `archive` is always removed. An artifact kept in memory left
+/// nothing on disk.
+async fn dispose_archive(
+    cfg: &Config,
+    update_info: &UpdateInfo,
+    downloaded: &ArchiveSource,
+    archive: &ArchiveSource,
+) {
+    let ArchiveSource::File(download_path) = downloaded else {
+        return;
+    };
+    if let Some(archive_path) = decrypted_copy(download_path, archive) {
+        if let Err(e) = fs::remove_file(archive_path) {
+            tracing::warn!(
+                "Failed to remove decrypted archive {:?}: {}",
+                archive_path,
+                e
+            );
+        }
+    }
+    DownloadState::remove(download_path).await;
+
+    if cfg.keep_downloaded_archive {
+        let kept_path = download_path.with_extension(format!("v{}.zip", update_info.version_code));
+        match fs::rename(download_path, &kept_path) {
+            Ok(()) => tracing::info!("Kept downloaded archive as {:?}", kept_path),
+            Err(e) => tracing::warn!("Failed to keep downloaded archive {:?}: {}", kept_path, e),
+        }
+    } else if let Err(e) = fs::remove_file(download_path) {
+        tracing::warn!(
+            "Failed to remove downloaded archive {:?}: {}",
+            download_path,
+            e
+        );
+    }
+}
+
+/// Counts a failed attempt at `version`, quarantining it once it reaches
+/// `max_attempts_per_version`.
+async fn record_version_failure(cfg: &Config, api: &ApiClient, current_version: i32, version: i32) {
+    let record = match FailureRecord::record_failure(&cfg.download_base_dir, version) {
+        Ok(record) => record,
+        Err(e) => {
+            tracing::warn!("{}", e);
+            return;
+        }
+    };
+    if record.failures == cfg.max_attempts_per_version {
+        let message = format!(
+            "version {} quarantined after {} failures",
+            version, record.failures
+        );
+        tracing::error!("{}", message);
+        api.report_status(current_version, message).await.ok();
+    }
+}
+
+/// Makes what the update script wrote durable, then the version file it
+/// updated, so the new version is never recorded on disk ahead of the tree.
+/// Syncing can take long on slow storage, so it runs on the blocking pool.
+async fn sync_installed_version(
+    cfg: &Config,
+    cancel: &CancellationToken,
+) -> Result<(), UpdateError> {
+    let version_file = cfg
+        .current_version_command
+        .is_none()
+        .then(|| cfg.current_version_file.clone());
+    cancel::run_blocking("sync", cancel, move |_| {
+        durable::sync_all_filesystems();
+        match version_file {
+            Some(path) if path.exists() => durable::sync_file_and_dir(&path),
+            _ => Ok(()),
+        }
+    })
+    .await
+}
+
+/// Removes a staged tree rejected before it was swapped in, on the blocking
+/// pool.
+async fn discard_staged_tree(extraction: extract::Extraction) {
+    cancel::run_blocking("cleanup", &CancellationToken::new(), move |_| {
+        extraction.discard_staged();
+        Ok(())
+    })
+    .await
+    .ok();
+}
+
+/// Puts back the tree a staged extraction replaced, on the blocking pool, once
+/// the update it was replaced for is abandoned. Returns whether it did.
+async fn restore_replaced_tree(replaced: Option<ReplacedTree>) -> bool {
+    let Some(replaced) = replaced else {
+        return false;
+    };
+    // Not tied to the cycle: a cancelled update is rolled back all the same.
+    let restored = cancel::run_blocking("rollback", &CancellationToken::new(), move |_| {
+        replaced.restore()
+    })
+    .await;
+    if let Err(e) = &restored {
+        tracing::error!("{}", e);
+    }
+    restored.is_ok()
+}
+
+/// Puts back the `protected_paths` snapshot of an update the updater died
+/// in, e.g. on power loss while its script ran, before anything else runs.
+async fn restore_leftover_snapshot(cfg: &Config, api: &ApiClient) {
+    let restored = {
+        let cfg = cfg.clone();
+        cancel::run_blocking("snapshot", &CancellationToken::new(), move |_| {
+            let Some(snapshot) = snapshot::Snapshot::leftover(&cfg)? else {
+                return Ok(None);
+            };
+            tracing::warn!("Found the snapshot of an interrupted update, restoring it");
+            let restored = snapshot.restore();
+            snapshot.discard();
+            Ok(Some((get_current_version(&cfg).unwrap_or(0), restored)))
+        })
+        .await
+    };
+    let (current_version, restored) = match restored {
+        Ok(Some(restored)) => restored,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return;
+        }
+    };
+    let restored: Vec<_> = restored.iter().map(|p| p.display().to_string()).collect();
+    api.report_status(
+        current_version,
+        format!(
+            "interrupted update found, restored protected paths: {}",
+            restored.join(", ")
+        ),
+    )
+    .await
+    .ok();
+}
+
+/// Status report about moving from `current_version` to `update_info`.
+fn transition_status(
+    current_version: i32,
+    update_info: &UpdateInfo,
+    message: String,
+) -> StatusReportPayload {
+    StatusReportPayload::new(current_version, message)
+        .with_transition(current_version, update_info.version_code)
+        .terminal()
+}
+
+/// Runs `scan_command` over the extracted tree at `dir` on the blocking pool.
+/// The scanner is killed when `cancel` fires or this future is dropped, e.g.
+/// by the cycle timeout.
+async fn scan_extracted(
+    cfg: &Config,
+    command: &str,
+    dir: &Path,
+    cancel: &CancellationToken,
+) -> Result<(), UpdateError> {
+    let quoted = format!("'{}'", dir.to_string_lossy().replace('\'', r"'\''"));
+    let command = command.replace("{path}", &quoted);
+    tracing::info!("Scanning {:?} before running its scripts", dir);
+    let timeout = Duration::from_secs(cfg.scan_timeout_seconds);
+    let result = cancel::run_blocking("scan", cancel, move |cancel| {
+        hooks::run_gate_command_with_timeout("scan", &command, timeout, cancel)
+    })
+    .await;
+    match result {
+        Ok(true) => Ok(()),
+        Err(UpdateError::Cancelled) => Err(UpdateError::Cancelled),
+        Ok(false) => Err(UpdateError::HookError(
+            "scan failed: scan command exited nonzero".to_string(),
+        )),
+        Err(e) => Err(UpdateError::HookError(format!("scan failed: {}", e))),
+    }
+}
+
+/// Runs one update script on the blocking pool. It is stopped when `cancel`
+/// fires or this future is dropped, e.g. by the cycle timeout.
+async fn run_update_script(
     cfg: &Config,
     script_path: &Path,
-    working_dir: &Path, // The script should run from within its extracted directory
+    dir: &Path,
+    versions: (i32, i32),
+    log: &script::ScriptLog,
+    cancel: &CancellationToken,
 ) -> Result<(), UpdateError> {
-    tracing::info!(
-        "Running update script {:?} in working directory {:?}",
-        script_path,
-        working_dir
+    let (cfg, script_path, dir, log) = (
+        cfg.clone(),
+        script_path.to_path_buf(),
+        dir.to_path_buf(),
+        log.clone(),
     );
+    cancel::run_blocking("update script", cancel, move |cancel| {
+        script::run_update_script(&cfg, &script_path, &dir, versions, &log, cancel)
+    })
+    .await
+}
 
-    if !script_path.exists() {
-        return Err(UpdateError::ScriptError(format!(
-            "Update script not found at {:?}",
-            script_path
-        )));
+/// Runs the update scripts of the tree at `dir` in order, stopping at the
+/// first failure. With several scripts, each step is reported. The steps
+/// that did run are not undone here; the caller only restores what it
+/// snapshotted or set aside.
+async fn run_update_scripts(
+    cfg: &Config,
+    api: &ApiClient,
+    dir: &Path,
+    (from, to): (i32, i32),
+    log: &script::ScriptLog,
+    cancel: &CancellationToken,
+) -> Result<(), UpdateError> {
+    let scripts = script::update_scripts(cfg, dir)?;
+    if let [script_path] = scripts.as_slice() {
+        return run_update_script(cfg, script_path, dir, (from, to), log, cancel).await;
     }
 
-    // Make script executable (e.g., chmod +x) - specific to Unix-like systems
-    let metadata = std::fs::metadata(script_path).map_err(|e| {
-        UpdateError::FileSystemError(format!(
-            "Failed to get metadata for script {:?}: {}",
-            script_path, e
-        ))
-    })?;
-    let mut permissions = metadata.permissions();
-    permissions.set_mode(permissions.mode() | 0o755); // Add execute permissions for user, group, others (ugo+x)
-    std::fs::set_permissions(script_path, permissions).map_err(|e| {
-        UpdateError::FileSystemError(format!(
-            "Failed to set executable permission on script {:?}: {}",
-            script_path, e
+    for (step, script_path) in scripts.iter().enumerate() {
+        let name = script_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        tracing::info!("Update step {}/{}: {}", step + 1, scripts.len(), name);
+        let result = run_update_script(cfg, script_path, dir, (from, to), log, cancel).await;
+        let message = match &result {
+            Ok(()) => format!(
+                "update {} step {}/{} ({}) succeeded",
+                to,
+                step + 1,
+                scripts.len(),
+                name
+            ),
+            Err(e) => format!(
+                "update {} step {}/{} ({}) failed: {}",
+                to,
+                step + 1,
+                scripts.len(),
+                name,
+                e
+            ),
+        };
+        api.report_status(from, message).await.ok();
+        result?;
+    }
+    Ok(())
+}
+
+/// Removes a download that won't be installed, with its resume state and the
+/// decrypted copy at `archive`. An artifact kept in memory left nothing on disk.
+async fn discard_download(
+    downloaded: &ArchiveSource,
+    archive: &ArchiveSource,
+) -> Result<(), UpdateError> {
+    let ArchiveSource::File(download_path) = downloaded else {
+        return Ok(());
+    };
+    if let Some(archive_path) = decrypted_copy(download_path, archive) {
+        fs::remove_file(archive_path).ok();
+    }
+    fs::remove_file(download_path)?;
+    DownloadState::remove(download_path).await;
+    Ok(())
+}
+
+/// `first_boot_policy = "record_only"`: records the downloaded version as
+/// the one installed by the factory image, without touching the device.
+async fn record_first_boot_version(
+    cfg: &Config,
+    api: &ApiClient,
+    update_info: &UpdateInfo,
+    downloaded: &ArchiveSource,
+) -> Result<CycleOutcome, UpdateError> {
+    let version = update_info.version_code;
+    if let Err(e) = record_current_version(cfg, version) {
+        tracing::error!("first boot: {}", e);
+        api.send_status(transition_status(
+            0,
+            update_info,
+            format!("first boot: recording version {} failed: {}", version, e),
         ))
-    })?;
+        .await
+        .ok();
+        return Ok(CycleOutcome::InstallFailed {
+            version,
+            reason: e.to_string(),
+        });
+    }
+    dispose_archive(cfg, update_info, downloaded, downloaded).await;
+    tracing::info!(
+        "first boot: recorded version {} without running its update script",
+        version
+    );
+    api.send_status(transition_status(
+        0,
+        update_info,
+        format!(
+            "first boot: recorded version {} without running its update script",
+            version
+        ),
+    ))
+    .await
+    .ok();
+    Ok(CycleOutcome::Recorded { version })
+}
+
+/// Decrypts, extracts and runs the update script of a downloaded artifact,
+/// reporting the outcome.
+async fn apply_update(
+    cfg: &Config,
+    api: &ApiClient,
+    current_version: i32,
+    update_info: &UpdateInfo,
+    downloaded: ArchiveSource,
+    timings: &mut PhaseTimings,
+    cancel: &CancellationToken,
+) -> Result<CycleOutcome, UpdateError> {
+    let install_failed = |e: UpdateError| CycleOutcome::InstallFailed {
+        version: update_info.version_code,
+        reason: e.to_string(),
+    };
+    if let Some(expected) = &update_info.checksum {
+        timings.enter("verify");
+        let verified = {
+            let (downloaded, expected) = (downloaded.clone(), expected.clone());
+            cancel::run_blocking("verification", cancel, move |_| match &downloaded {
+                ArchiveSource::File(path) => crypto::verify_sha256(path, &expected),
+                ArchiveSource::Memory(data) => crypto::verify_sha256_bytes(data, &expected),
+            })
+            .await
+        };
+        if let Err(e) = verified {
+            tracing::error!("error in verifying file: {}", e);
+            api.notify(
+                current_version,
+                update_info.version_code,
+                "failed",
+                Some(&e.to_string()),
+            );
+            api.send_status(transition_status(
+                current_version,
+                update_info,
+                format!("update {} failed: {}", update_info.version_code, e),
+            ))
+            .await
+            .ok();
+            discard_download(&downloaded, &downloaded).await?;
+            return Ok(install_failed(e));
+        }
+        if let (Some(cache), ArchiveSource::File(path)) =
+            (ArtifactCache::from_config(cfg), &downloaded)
+        {
+            let (update_info, path) = (update_info.clone(), path.clone());
+            cancel::run_blocking("cache", cancel, move |_| {
+                cache.store(&update_info, &path);
+                Ok(())
+            })
+            .await?;
+        }
+    }
 
-    tracing::info!("Set executable permission on {:?}", script_path);
+    if current_version == 0 && cfg.first_boot_policy == FirstBootPolicy::RecordOnly {
+        return record_first_boot_version(cfg, api, update_info, &downloaded).await;
+    }
 
-    let output = Command::new(script_path)
-        .env("DB_PASSWORD", &cfg.db_password)
-        .current_dir(working_dir) // Run the script from its own directory
-        .output()
-        .map_err(|e| {
-            UpdateError::ScriptError(format!(
-                "Failed to execute update script {:?}: {}",
-                script_path, e
+    let archive = if cfg.encrypted_updates {
+        timings.enter("decrypt");
+        let decrypted = {
+            let (downloaded, format) = (downloaded.clone(), cfg.encryption_format);
+            let keys = cfg.get_decryption_keys();
+            cancel::run_blocking("decryption", cancel, move |_| {
+                keys.and_then(|keys| match &downloaded {
+                    ArchiveSource::File(path) => {
+                        let decrypted_path = path.with_extension("zip.dec");
+                        crypto::decrypt_update(path, &decrypted_path, &keys, format)
+                            .map(|_| ArchiveSource::File(decrypted_path))
+                    }
+                    ArchiveSource::Memory(data) => crypto::decrypt_payload(data, &keys, format)
+                        .map(|plaintext| ArchiveSource::Memory(plaintext.into())),
+                })
+            })
+            .await
+        };
+        match decrypted {
+            Ok(archive) => archive,
+            Err(e) => {
+                tracing::error!("error in decrypting file: {}", e);
+                api.notify(
+                    current_version,
+                    update_info.version_code,
+                    "failed",
+                    Some(&e.to_string()),
+                );
+                api.send_status(transition_status(
+                    current_version,
+                    update_info,
+                    format!("update {} failed: {}", update_info.version_code, e),
+                ))
+                .await
+                .ok();
+                discard_download(&downloaded, &downloaded).await?;
+                return Ok(install_failed(e));
+            }
+        }
+    } else {
+        downloaded.clone()
+    };
+
+    let build_info = if cfg.verify_build_info {
+        match build_info::BuildInfo::from_archive(cfg, &archive).and_then(|info| {
+            info.check(&cfg.download_base_dir, update_info.version_code)
+                .map(|_| info)
+        }) {
+            Ok(info) => Some(info),
+            Err(e) => {
+                tracing::error!("{}", e);
+                api.notify(
+                    current_version,
+                    update_info.version_code,
+                    "failed",
+                    Some(&e.to_string()),
+                );
+                api.send_status(transition_status(
+                    current_version,
+                    update_info,
+                    format!("update {} rejected: {}", update_info.version_code, e),
+                ))
+                .await
+                .ok();
+                discard_download(&downloaded, &archive).await?;
+                record_version_failure(cfg, api, current_version, update_info.version_code).await;
+                return Ok(CycleOutcome::Rejected {
+                    version: update_info.version_code,
+                    reason: e.to_string(),
+                });
+            }
+        }
+    } else {
+        None
+    };
+
+    timings.enter("extract");
+    let (out_extracted_path, target_slot) = match &cfg.ab_slots {
+        Some(slots) => match slots.inactive_slot() {
+            Ok(slot) => {
+                tracing::info!("Installing into inactive slot {}", slot.name());
+                (slots.dir(slot).to_path_buf(), Some(slot))
+            }
+            Err(e) => {
+                tracing::error!("cannot determine the inactive slot: {}", e);
+                api.send_status(transition_status(
+                    current_version,
+                    update_info,
+                    format!("update {} failed: {}", update_info.version_code, e),
+                ))
+                .await
+                .ok();
+                return Ok(install_failed(e));
+            }
+        },
+        None => (cfg.extraction_dir(&artifact_name(update_info)?), None),
+    };
+    let extraction = {
+        let (cfg, archive, out) = (cfg.clone(), archive.clone(), out_extracted_path.clone());
+        cancel::run_blocking("extraction", cancel, move |cancel| {
+            extract::unzip_update(&cfg, &archive, &out, cancel)
+        })
+        .await
+    };
+    let extraction = match extraction {
+        Ok(extraction) => extraction,
+        Err(UpdateError::Cancelled) => {
+            discard_download(&downloaded, &archive).await?;
+            report_cancelled(api, current_version, update_info).await;
+            return Ok(CycleOutcome::Cancelled {
+                version: update_info.version_code,
+            });
+        }
+        Err(e) => {
+            api.notify(
+                current_version,
+                update_info.version_code,
+                "failed",
+                Some(&e.to_string()),
+            );
+            match &e {
+                UpdateError::ArchiveError(m) => {
+                    tracing::error!("error in unzipping file: {}", m);
+                    discard_download(&downloaded, &downloaded).await?;
+                    // A staged extraction never touched the existing tree, and a
+                    // slot directory is kept for the next attempt.
+                    if !cfg.extracts_via_staging() && target_slot.is_none() {
+                        fs::remove_dir_all(&out_extracted_path)?;
+                    }
+                }
+                _ => {
+                    tracing::error!("unknown error in extracting files ");
+                }
+            }
+            return Ok(install_failed(e));
+        }
+    };
+
+    tracing::debug!("file is extracted successfully");
+    api.report_status(
+        current_version,
+        format!(
+            "file {} is extracted successfully",
+            update_info.version_code
+        ),
+    )
+    .await
+    .ok();
+
+    // A staged tree is scanned before it replaces anything.
+    if let Some(command) = &cfg.scan_command {
+        let scanned = scan_extracted(cfg, command, extraction.path(), cancel).await;
+        if let Err(UpdateError::Cancelled) = scanned {
+            discard_staged_tree(extraction).await;
+            discard_download(&downloaded, &archive).await?;
+            report_cancelled(api, current_version, update_info).await;
+            return Ok(CycleOutcome::Cancelled {
+                version: update_info.version_code,
+            });
+        }
+        if let Err(e) = scanned {
+            if !cfg.scan_failure_fatal {
+                tracing::warn!("{}, continuing as scan_failure_fatal is off", e);
+                api.report_status(current_version, format!("{} (ignored)", e))
+                    .await
+                    .ok();
+            } else {
+                tracing::error!("{}", e);
+                api.notify(
+                    current_version,
+                    update_info.version_code,
+                    "failed",
+                    Some(&e.to_string()),
+                );
+                api.send_status(transition_status(
+                    current_version,
+                    update_info,
+                    format!("update {} failed: {}", update_info.version_code, e),
+                ))
+                .await
+                .ok();
+                discard_download(&downloaded, &archive).await?;
+                // Only a tree of our own is removed; a staged one never
+                // replaced the install_dir or slot it was extracted for.
+                if cfg.extracts_via_staging() {
+                    discard_staged_tree(extraction).await;
+                } else if cfg.install_dir.is_none() && target_slot.is_none() {
+                    if let Err(e) = fs::remove_dir_all(&out_extracted_path) {
+                        tracing::warn!("Failed to remove {:?}: {}", out_extracted_path, e);
+                    }
+                }
+                return Ok(install_failed(e));
+            }
+        }
+    }
+
+    // Until the update is known good, a staged extraction keeps the tree it
+    // replaced and puts it back when the update is abandoned.
+    let swapped = cancel::run_blocking("extraction", cancel, move |_| extraction.swap_in()).await;
+    let replaced = match swapped {
+        Ok(replaced) => replaced,
+        Err(e) => {
+            tracing::error!("{}", e);
+            api.send_status(transition_status(
+                current_version,
+                update_info,
+                format!("update {} failed: {}", update_info.version_code, e),
             ))
-        })?;
+            .await
+            .ok();
+            return Ok(install_failed(e));
+        }
+    };
 
-    if output.status.success() {
-        tracing::info!(
-            "Update script executed successfully. STDOUT:\n{}",
-            String::from_utf8_lossy(&output.stdout)
+    if let Some(cmd) = &cfg.battery_precheck_command {
+        match hooks::run_gate_hook(cfg, "battery precheck", cmd, cancel).await {
+            Ok(true) => {}
+            Err(UpdateError::Cancelled) => {
+                restore_replaced_tree(replaced).await;
+                discard_download(&downloaded, &archive).await?;
+                report_cancelled(api, current_version, update_info).await;
+                return Ok(CycleOutcome::Cancelled {
+                    version: update_info.version_code,
+                });
+            }
+            Ok(false) => {
+                tracing::info!(
+                    "deferred: low battery, keeping version {} staged",
+                    update_info.version_code
+                );
+                restore_replaced_tree(replaced).await;
+                api.report_status(current_version, "deferred: low battery".to_string())
+                    .await
+                    .ok();
+                return Ok(CycleOutcome::Deferred("low battery".to_string()));
+            }
+            Err(e) => {
+                tracing::error!(
+                    "battery precheck error, keeping version {} staged: {}",
+                    update_info.version_code,
+                    e
+                );
+                restore_replaced_tree(replaced).await;
+                api.report_status(current_version, format!("battery precheck error: {}", e))
+                    .await
+                    .ok();
+                return Ok(CycleOutcome::HookFailed {
+                    hook: "battery precheck".to_string(),
+                    reason: e.to_string(),
+                });
+            }
+        }
+    }
+
+    if cfg.min_free_memory_bytes > 0 {
+        match meminfo::available_bytes() {
+            Ok(available) if available < cfg.min_free_memory_bytes => {
+                let message = format!(
+                    "insufficient free memory: {} bytes available, {} required",
+                    available, cfg.min_free_memory_bytes
+                );
+                restore_replaced_tree(replaced).await;
+                if cfg.insufficient_memory_fatal {
+                    tracing::error!("{}", message);
+                    api.notify(
+                        current_version,
+                        update_info.version_code,
+                        "failed",
+                        Some(&message),
+                    );
+                    api.send_status(transition_status(
+                        current_version,
+                        update_info,
+                        format!("update {} failed: {}", update_info.version_code, message),
+                    ))
+                    .await
+                    .ok();
+                    return Ok(CycleOutcome::InstallFailed {
+                        version: update_info.version_code,
+                        reason: message,
+                    });
+                }
+                tracing::info!(
+                    "deferred: {}, keeping version {} staged",
+                    message,
+                    update_info.version_code
+                );
+                api.report_status(current_version, format!("deferred: {}", message))
+                    .await
+                    .ok();
+                return Ok(CycleOutcome::Deferred(message));
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Cannot check free memory, running the script anyway: {}", e),
+        }
+    }
+
+    // A deferred update keeps its archive so the next cycle needn't download it again.
+    dispose_archive(cfg, update_info, &downloaded, &archive).await;
+
+    timings.enter("script");
+    if cfg.report_script_start {
+        api.report_status(
+            current_version,
+            format!(
+                "running update script for version {}",
+                update_info.version_code
+            ),
+        )
+        .await
+        .ok();
+    }
+    let snapshot = {
+        let cfg = cfg.clone();
+        cancel::run_blocking("snapshot", cancel, move |_| snapshot::Snapshot::take(&cfg)).await
+    };
+    let snapshot = match snapshot {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            tracing::error!("{}", e);
+            restore_replaced_tree(replaced).await;
+            api.send_status(transition_status(
+                current_version,
+                update_info,
+                format!("update {} failed: {}", update_info.version_code, e),
+            ))
+            .await
+            .ok();
+            return Ok(install_failed(e));
+        }
+    };
+    let versions = (current_version, update_info.version_code);
+    let script_log = script::ScriptLog::for_upload(cfg);
+    let mut outcome =
+        run_update_scripts(cfg, api, &out_extracted_path, versions, &script_log, cancel).await;
+    if let Some(log) = script_log.gzipped() {
+        api.upload_script_log(update_info.version_code, outcome.is_ok(), log)
+            .await;
+    }
+    if let (Ok(()), Some(slots), Some(slot)) = (&outcome, &cfg.ab_slots, target_slot) {
+        outcome = slots.set_active(slot);
+    }
+    // The commit script only finalizes an update whose service is healthy.
+    if let (Ok(()), true) = (&outcome, cfg.post_update_restart_attempts > 0) {
+        outcome = watchdog::restart_until_healthy(cfg, api, current_version, cancel).await;
+    } else if let (Ok(()), Some(_), Some(_)) = (
+        &outcome,
+        &cfg.commit_script_name,
+        &cfg.service_health_command,
+    ) {
+        outcome = watchdog::check_healthy(cfg, cancel).await;
+    }
+    if let (Ok(()), Some(commit_script_name)) = (&outcome, &cfg.commit_script_name) {
+        let commit_path = out_extracted_path.join(commit_script_name);
+        let committed = {
+            let (cfg, dir) = (cfg.clone(), out_extracted_path.clone());
+            cancel::run_blocking("commit script", cancel, move |cancel| {
+                script::run_commit_script(&cfg, &commit_path, &dir, versions, cancel)
+            })
+            .await
+        };
+        if let Err(e) = committed {
+            tracing::error!("commit script failed: {}", e);
+            api.report_status(
+                current_version,
+                format!(
+                    "commit script of update {} failed: {}",
+                    update_info.version_code, e
+                ),
+            )
+            .await
+            .ok();
+            // A refused commit script means a tampered archive, never a glitch.
+            if cfg.commit_script_fatal || matches!(e, UpdateError::ScriptNotAllowed(_)) {
+                outcome = Err(e);
+            }
+        }
+    }
+    if let (Ok(()), true) = (&outcome, cfg.durable_writes) {
+        outcome = sync_installed_version(cfg, cancel).await;
+    }
+    let mut rolled_back = false;
+    if outcome.is_err() {
+        rolled_back = restore_replaced_tree(replaced).await;
+    } else if let Some(replaced) = replaced {
+        cancel::run_blocking("cleanup", &CancellationToken::new(), move |_| {
+            replaced.discard();
+            Ok(())
+        })
+        .await
+        .ok();
+    }
+    if let Some(snapshot) = snapshot {
+        let failed = outcome.is_err();
+        // Not tied to the cycle, like the tree rollback.
+        let restored = cancel::run_blocking("snapshot", &CancellationToken::new(), move |_| {
+            let restored = if failed {
+                snapshot.restore()
+            } else {
+                Vec::new()
+            };
+            snapshot.discard();
+            Ok(restored)
+        })
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("{}", e);
+            Vec::new()
+        });
+        if failed {
+            let restored: Vec<_> = restored.iter().map(|p| p.display().to_string()).collect();
+            api.report_status(
+                current_version,
+                format!(
+                    "update {} failed, restored protected paths: {}",
+                    update_info.version_code,
+                    restored.join(", ")
+                ),
+            )
+            .await
+            .ok();
+            rolled_back |= !restored.is_empty();
+        }
+    }
+    if rolled_back && matches!(outcome, Err(UpdateError::ServiceUnhealthy(_))) {
+        watchdog::restart_after_rollback(cfg, api, current_version).await;
+    }
+    timings.finish();
+    if let Err(UpdateError::Cancelled) = outcome {
+        report_cancelled(api, current_version, update_info).await;
+        Ok(CycleOutcome::Cancelled {
+            version: update_info.version_code,
+        })
+    } else if let Err(e) = outcome {
+        let not_allowed = matches!(e, UpdateError::ScriptNotAllowed(_));
+        let verdict = if not_allowed { "rejected" } else { "failed" };
+        api.notify(
+            current_version,
+            update_info.version_code,
+            verdict,
+            Some(&e.to_string()),
         );
-        if !output.stderr.is_empty() {
-            tracing::warn!(
-                "Update script STDERR (though successful):\n{}",
-                String::from_utf8_lossy(&output.stderr)
-            );
+        api.send_status(
+            transition_status(
+                current_version,
+                update_info,
+                format!("update {} {}: {}", update_info.version_code, verdict, e),
+            )
+            .with_failure_category(&e),
+        )
+        .await
+        .ok();
+        record_version_failure(cfg, api, current_version, update_info.version_code).await;
+        if not_allowed {
+            return Ok(CycleOutcome::Rejected {
+                version: update_info.version_code,
+                reason: e.to_string(),
+            });
         }
-        Ok(())
+        Ok(CycleOutcome::ScriptFailed {
+            version: update_info.version_code,
+            reason: e.to_string(),
+        })
     } else {
-        let error_message = format!(
-            "Update script failed with status: {:?}.\nSTDOUT:\n{}\nSTDERR:\n{}",
-            output.status.code(),
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr)
-        );
-        tracing::error!("{}", error_message);
-        Err(UpdateError::ScriptError(error_message))
+        if let Err(e) = FailureRecord::clear(&cfg.download_base_dir) {
+            tracing::warn!("{}", e);
+        }
+        if let Err(e) = version_history::record(&cfg.download_base_dir, update_info.version_code) {
+            tracing::warn!("{}", e);
+        }
+        if let Some(info) = build_info {
+            if let Err(e) = info.record_installed(&cfg.download_base_dir) {
+                tracing::warn!("{}", e);
+            }
+        }
+        api.notify(current_version, update_info.version_code, "succeeded", None);
+        api.send_status(
+            transition_status(
+                current_version,
+                update_info,
+                format!(
+                    "updated successfully from {} to {}",
+                    current_version, update_info.version_code
+                ),
+            )
+            .with_release_notes(update_info.release_notes.as_deref())
+            .with_phase_timings(cfg.report_phase_timings.then(|| timings.as_millis())),
+        )
+        .await
+        .ok();
+        Ok(CycleOutcome::Updated {
+            from: current_version,
+            to: update_info.version_code,
+        })
     }
 }
 
+/// Runs one update cycle in an `update_cycle` span carrying the offered
+/// version, the last phase entered and the outcome.
+#[tracing::instrument(
+    name = "update_cycle",
+    skip_all,
+    fields(
+        current_version = current_version,
+        version = tracing::field::Empty,
+        phase = tracing::field::Empty,
+        outcome = tracing::field::Empty,
+    )
+)]
 async fn run_update_cycle(
-    cfg: &mut Config,
+    cfg: &Config,
     api: &ApiClient,
     current_version: i32,
-) -> Result<(), UpdateError> {
+    timings: &mut PhaseTimings,
+    cancel: &CancellationToken,
+    force: bool,
+) -> Result<CycleReport, UpdateError> {
+    let result = update_cycle(cfg, api, current_version, timings, cancel, force).await;
+    let span = tracing::Span::current();
+    match &result {
+        Ok(report) => span.record("outcome", tracing::field::display(&report.outcome)),
+        Err(e) => span.record("outcome", tracing::field::display(e)),
+    };
+    result
+}
+
+async fn update_cycle(
+    cfg: &Config,
+    api: &ApiClient,
+    current_version: i32,
+    timings: &mut PhaseTimings,
+    cancel: &CancellationToken,
+    force: bool,
+) -> Result<CycleReport, UpdateError> {
     //TODO: handle error in finding current version
+    let mut next_poll = cfg.poll_interval_seconds;
 
-    match api.check_for_updates().await {
-        Ok(update_info) => {
-            tracing::info!(
-                "New version available: {}, URL: {}\nCurrent version: {}",
-                update_info.version_code,
-                update_info.file_url,
-                current_version
-            );
-            if update_info.version_code > current_version {
-                let file_name = update_info.file_url.split('/').last().unwrap();
-                let mut download_path = PathBuf::from(&cfg.download_base_dir);
-                download_path.push(format!("{}.zip", file_name));
+    if let Some(sentinel) = &cfg.pause_sentinel_path {
+        if sentinel.exists() {
+            tracing::info!("updates paused by sentinel {:?}", sentinel);
+            return Ok(CycleReport::new(
+                CycleOutcome::Deferred(format!("paused by sentinel {:?}", sentinel)),
+                next_poll,
+            ));
+        }
+    }
 
-                match api
-                    .download_update(&update_info.file_url, &download_path)
+    if let Some(cmd) = &cfg.precheck_command {
+        match hooks::run_gate_hook(cfg, "precheck", cmd, cancel).await {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::info!("precheck vetoed this cycle");
+                api.report_status(current_version, "precheck vetoed this cycle".to_string())
+                    .await
+                    .ok();
+                return Ok(CycleReport::new(
+                    CycleOutcome::Deferred("vetoed by precheck".to_string()),
+                    next_poll,
+                ));
+            }
+            Err(e) => {
+                tracing::error!("precheck error, skipping cycle: {}", e);
+                api.report_status(current_version, format!("precheck error: {}", e))
                     .await
-                {
-                    Ok(_) => {
+                    .ok();
+                return Ok(CycleReport::new(
+                    CycleOutcome::HookFailed {
+                        hook: "precheck".to_string(),
+                        reason: e.to_string(),
+                    },
+                    next_poll,
+                ));
+            }
+        }
+    }
+
+    if let Err(e) = probe_download_dir(&cfg.download_base_dir) {
+        tracing::error!("{}", e);
+        api.report_status(current_version, e.to_string()).await.ok();
+        return Err(e);
+    }
+
+    if current_version == 0 && cfg.first_boot_policy == FirstBootPolicy::Skip {
+        tracing::info!("first boot: no version recorded, skipping updates");
+        return Ok(CycleReport::new(
+            CycleOutcome::Deferred("first boot, no version recorded".to_string()),
+            next_poll,
+        ));
+    }
+
+    timings.enter("check");
+    let mut local_archive = None;
+    let mut update_info = if let Some(dir) = &cfg.local_source_dir {
+        match local_source::find_update(dir)? {
+            Some((update_info, archive_path)) => {
+                local_archive = Some(archive_path);
+                update_info
+            }
+            None => {
+                tracing::info!("No update found in local source {:?}", dir);
+                return Ok(CycleReport::new(CycleOutcome::UpToDate, next_poll));
+            }
+        }
+    } else {
+        match api.check_for_updates().await {
+            Ok(update_info) => {
+                next_poll = next_poll_interval(cfg, update_info.requested_poll_seconds());
+                update_info
+            }
+            Err(UpdateError::Throttled {
+                status,
+                retry_after_seconds,
+            }) => {
+                next_poll = next_poll_interval(cfg, retry_after_seconds);
+                tracing::warn!(
+                    "Update check throttled ({}), next check in {} seconds",
+                    status,
+                    next_poll
+                );
+                return Ok(CycleReport::new(
+                    CycleOutcome::Deferred(format!("update check throttled ({})", status)),
+                    next_poll,
+                ));
+            }
+            Err(e @ UpdateError::Unauthorized { .. }) => {
+                // Retrying soon with the same token is pointless.
+                tracing::error!(
+                    "{}; check the device token provisioning, next check in {} seconds",
+                    e,
+                    UNAUTHORIZED_POLL_INTERVAL_SECONDS
+                );
+                // A status report would carry the same rejected token.
+                api.notify(
+                    current_version,
+                    current_version,
+                    "unauthorized",
+                    Some("device token rejected"),
+                );
+                return Ok(CycleReport::new(
+                    CycleOutcome::CheckFailed(e.to_string()),
+                    UNAUTHORIZED_POLL_INTERVAL_SECONDS,
+                ));
+            }
+            Err(e) => {
+                tracing::warn!("update error: {}", e);
+                return Ok(CycleReport::new(
+                    CycleOutcome::CheckFailed(e.to_string()),
+                    next_poll,
+                ));
+            }
+        }
+    };
+    tracing::Span::current().record("version", update_info.version_code);
+    tracing::info!(
+        "New version available: {}, URL: {}\nCurrent version: {}",
+        update_info.version_code,
+        update_info.file_url,
+        current_version
+    );
+    tracing::debug!(
+        "Update details: size {:?}, checksum {:?}, release notes: {:?}",
+        update_info.size,
+        update_info.checksum,
+        update_info.release_notes
+    );
+
+    if update_info.version_code < cfg.minimum_version {
+        let e = UpdateError::DowngradeBlocked {
+            version: update_info.version_code,
+            minimum: cfg.minimum_version,
+        };
+        tracing::error!("{}", e);
+        api.report_status(current_version, e.to_string()).await.ok();
+        return Ok(CycleReport::new(
+            CycleOutcome::Rejected {
+                version: update_info.version_code,
+                reason: e.to_string(),
+            },
+            next_poll,
+        ));
+    }
+    if !update_info.supports_model(cfg.device_model.as_deref()) {
+        let reason = format!(
+            "incompatible model: {} not in {:?}",
+            cfg.device_model.as_deref().unwrap_or("unknown"),
+            update_info.compatible_models
+        );
+        tracing::warn!("Skipping version {}: {}", update_info.version_code, reason);
+        api.report_status(
+            current_version,
+            format!("update {} skipped: {}", update_info.version_code, reason),
+        )
+        .await
+        .ok();
+        return Ok(CycleReport::new(
+            CycleOutcome::Rejected {
+                version: update_info.version_code,
+                reason,
+            },
+            next_poll,
+        ));
+    }
+    // `force` only re-downloads the installed version; never a downgrade.
+    if update_info.version_code < current_version
+        || (update_info.version_code == current_version && !force)
+    {
+        tracing::info!("No new update available or service is up-to-date.");
+        return Ok(CycleReport::new(CycleOutcome::UpToDate, next_poll));
+    }
+    if update_info.version_code == current_version {
+        tracing::info!(
+            "Reinstalling version {}, which is already installed",
+            update_info.version_code
+        );
+    }
+    if let Some(percent) = update_info.canary_percent {
+        let bucket = canary::device_bucket(cfg);
+        if bucket >= u64::from(percent) {
+            let reason = format!(
+                "held back by canary gate (bucket {}, canary {}%)",
+                bucket, percent
+            );
+            tracing::info!("Version {} {}", update_info.version_code, reason);
+            api.report_status(
+                current_version,
+                format!("update {} {}", update_info.version_code, reason),
+            )
+            .await
+            .ok();
+            return Ok(CycleReport::new(CycleOutcome::Deferred(reason), next_poll));
+        }
+    }
+    if FailureRecord::load(&cfg.download_base_dir).is_some_and(|record| {
+        record.is_quarantined(update_info.version_code, cfg.max_attempts_per_version)
+    }) {
+        tracing::info!(
+            "Version {} is quarantined, waiting for a newer one",
+            update_info.version_code
+        );
+        return Ok(CycleReport::new(
+            CycleOutcome::Rejected {
+                version: update_info.version_code,
+                reason: "quarantined".to_string(),
+            },
+            next_poll,
+        ));
+    }
+
+    let active = match target_service_active(cfg, cancel).await {
+        Ok(active) => active,
+        // Only cancellation gets here; a failing probe counts as inactive.
+        Err(_) => {
+            report_cancelled(api, current_version, &update_info).await;
+            return Ok(CycleReport::new(
+                CycleOutcome::Cancelled {
+                    version: update_info.version_code,
+                },
+                next_poll,
+            ));
+        }
+    };
+    let was_inactive = TARGET_SERVICE_INACTIVE.swap(!active, Ordering::Relaxed);
+    if !active {
+        tracing::info!("target service not active, skipping");
+        if !was_inactive {
+            api.report_status(
+                current_version,
+                "target service not active, skipping".to_string(),
+            )
+            .await
+            .ok();
+        }
+        return Ok(CycleReport::new(
+            CycleOutcome::Deferred("target service not active".to_string()),
+            next_poll,
+        ));
+    }
+    if was_inactive {
+        tracing::info!("target service active again");
+        api.report_status(current_version, "target service active again".to_string())
+            .await
+            .ok();
+    }
+
+    timings.enter("download");
+    let (download_path, in_memory) = loop {
+        let mut download_path = PathBuf::from(&cfg.download_base_dir);
+        download_path.push(format!("{}.zip", artifact_name(&update_info)?));
+
+        if let Some(archive_path) = &local_archive {
+            // Work on a copy so failures never touch the source medium.
+            fs::copy(archive_path, &download_path).map_err(|e| {
+                UpdateError::FileSystemError(format!(
+                    "Failed to copy local update {:?}: {}",
+                    archive_path, e
+                ))
+            })?;
+            break (download_path, None);
+        }
+        if let Some(cache) = ArtifactCache::from_config(cfg) {
+            let (info, path) = (update_info.clone(), download_path.clone());
+            let hit = cancel::run_blocking("cache", cancel, move |_| Ok(cache.fetch(&info, &path)))
+                .await?;
+            if hit {
+                // Progress of an earlier download of this version is moot.
+                DownloadState::remove(&download_path).await;
+                break (download_path, None);
+            }
+        }
+
+        let mut memory = keeps_in_memory(cfg, &update_info).then(Vec::new);
+        let download = async {
+            check_key_id_early(cfg, api, &update_info).await?;
+            download_superseding(cfg, api, &update_info, &download_path, memory.as_mut()).await
+        };
+        let result = tokio::select! {
+            result = download => result,
+            _ = cancel.cancelled() => Err(UpdateError::Cancelled),
+        };
+        match result {
+            Ok(None) => break (download_path, memory),
+            Ok(Some(newer)) => {
+                let message = format!(
+                    "download of version {} superseded by version {}",
+                    update_info.version_code, newer.version_code
+                );
+                tracing::info!("{}", message);
+                api.report_status(current_version, message).await.ok();
+                if memory.is_none() {
+                    if let Err(e) = fs::remove_file(&download_path) {
+                        tracing::warn!("Failed to remove superseded download: {}", e);
+                    }
+                }
+                DownloadState::remove(&download_path).await;
+                tracing::Span::current().record("version", newer.version_code);
+                update_info = newer;
+            }
+            Err(UpdateError::Cancelled) => {
+                match fs::remove_file(&download_path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        tracing::warn!("Failed to remove cancelled download: {}", e)
+                    }
+                    _ => {}
+                }
+                DownloadState::remove(&download_path).await;
+                report_cancelled(api, current_version, &update_info).await;
+                return Ok(CycleReport::new(
+                    CycleOutcome::Cancelled {
+                        version: update_info.version_code,
+                    },
+                    next_poll,
+                ));
+            }
+            Err(e) => {
+                match &e {
+                    UpdateError::TimeoutError
+                    | UpdateError::IncompleteDownload { .. }
+                    | UpdateError::ChunkMismatch { .. } => {
+                        next_poll = 1;
+                    }
+                    UpdateError::DecryptionError(_) => {
                         api.report_status(
                             current_version,
-                            format!(
-                                "version {} downloaded successfully",
-                                update_info.version_code
-                            ),
+                            format!("update {} rejected: {}", update_info.version_code, e),
                         )
                         .await
                         .ok();
-
-                        tracing::debug!("file is downloaded successfully");
-                        let mut out_extracted_path = PathBuf::from(&cfg.download_base_dir);
-                        out_extracted_path.push(file_name);
-                        if let Err(e) = unzip_update(&download_path, &out_extracted_path) {
-                            match &e {
-                                UpdateError::ArchiveError(m) => {
-                                    tracing::error!("error in unzipping file: {}", m);
-                                    fs::remove_file(&download_path)?;
-                                    fs::remove_dir_all(&out_extracted_path)?;
-                                }
-                                _ => {
-                                    tracing::error!("unknown error in extracting files ");
-                                }
-                            }
-                        } else {
-                            tracing::debug!("file is extracted successfully");
-                            api.report_status(
-                                current_version,
-                                format!(
-                                    "file {} is extracted successfully",
-                                    update_info.version_code
-                                ),
-                            )
-                            .await
-                            .ok();
-
-                            let script_path = out_extracted_path.join(&cfg.update_script_name);
-                            if let Err(UpdateError::ScriptError(e)) =
-                                run_update_script(&cfg, &script_path, &out_extracted_path)
-                            {
-                                api.report_status(
-                                    current_version,
-                                    format!("update {} failed: {}", update_info.version_code, e),
-                                )
-                                .await
-                                .ok();
-                            } else {
-                                api.report_status(
-                                    current_version,
-                                    format!(
-                                        "updated successfully from {} to {}",
-                                        current_version, update_info.version_code
-                                    ),
-                                )
-                                .await
-                                .ok();
-                            }
-                        }
-                        cfg.poll_interval_seconds = 300;
-                    }
-                    Err(e) => {
-                        match &e {
-                            UpdateError::TimeoutError => {
-                                cfg.poll_interval_seconds = 1;
-                            }
-                            _ => {
-                                cfg.poll_interval_seconds = 300;
-                            }
-                        }
-                        tracing::error!("error in downloading file: {}", e);
                     }
+                    _ => {}
                 }
-            } else {
-                tracing::info!("No new update available or service is up-to-date.");
+                tracing::error!("error in downloading file: {}", e);
+                return Ok(CycleReport::new(
+                    CycleOutcome::DownloadFailed {
+                        version: update_info.version_code,
+                        reason: e.to_string(),
+                    },
+                    next_poll,
+                ));
             }
         }
-        Err(e) => {
-            tracing::warn!("update error: {}", e);
-        }
-    }
+    };
 
-    Ok(())
+    api.report_status(
+        current_version,
+        format!(
+            "version {} downloaded successfully",
+            update_info.version_code
+        ),
+    )
+    .await
+    .ok();
+    tracing::debug!("file is downloaded successfully");
+
+    let downloaded = match in_memory {
+        Some(data) => ArchiveSource::Memory(data.into()),
+        None => ArchiveSource::File(download_path),
+    };
+    let outcome = apply_update(
+        cfg,
+        api,
+        current_version,
+        &update_info,
+        downloaded,
+        timings,
+        cancel,
+    )
+    .await?;
+    Ok(CycleReport::new(
+        outcome,
+        next_poll_interval(cfg, update_info.requested_poll_seconds()),
+    ))
 }
 
-fn reset_ntp_service() -> Result<(), UpdateError> {
-    let _ = Command::new("/usr/bin/sudo")
-        .args(["/usr/bin/systemctl", "restart", "ntp"])
+fn reset_ntp_service(cfg: &Config) -> Result<(), UpdateError> {
+    let mut command = match &cfg.time_sync_command {
+        Some(cmd) => {
+            let mut command = Command::new("/bin/sh");
+            command.args(["-c", cmd]);
+            command
+        }
+        None => {
+            let mut command = Command::new("/usr/bin/sudo");
+            command.args(["/usr/bin/systemctl", "restart", "ntp"]);
+            command
+        }
+    };
+    let _ = command
         .output()
         .map_err(|e| UpdateError::ScriptError(format!("Failed to restart ntp service: {}", e)))?;
     Ok(())
 }
 
+/// Loads the configuration, applying command line overrides.
+fn load_config(config_path: &str, args: &CliArgs) -> Result<(Config, Vec<String>), UpdateError> {
+    let (mut config, warnings) = Config::load(config_path)?;
+    if args.local_source.is_some() {
+        config.local_source_dir = args.local_source.clone();
+    }
+    Ok((config, warnings))
+}
+
+fn config_modified(config_path: &str) -> Option<std::time::SystemTime> {
+    fs::metadata(config_path).and_then(|m| m.modified()).ok()
+}
+
+/// Prints build information as stable `key=value` lines.
+fn print_version() {
+    let build_date = env!("BUILD_TIMESTAMP")
+        .parse::<i64>()
+        .ok()
+        .and_then(|ts| time::OffsetDateTime::from_unix_timestamp(ts).ok())
+        .and_then(|dt| {
+            dt.format(&time::format_description::well_known::Rfc3339)
+                .ok()
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("version={}", env!("CARGO_PKG_VERSION"));
+    println!("git_hash={}", env!("GIT_HASH"));
+    println!("build_date={}", build_date);
+}
+
+/// `--show-script-env`: prints the variables an update to `version` would
+/// add to the update script's environment, secrets only as `[set]` or
+/// `[unset]`. Returns whether every secret could be resolved.
+fn show_script_env(cfg: &Config, version: i32) -> bool {
+    let current_version = get_current_version(cfg).unwrap_or(0);
+    // The extraction directory is named after the artifact, which is only
+    // known once the server offers it.
+    let working_dir = cfg
+        .ab_slots
+        .as_ref()
+        .and_then(|slots| {
+            let slot = slots.inactive_slot().ok()?;
+            Some(slots.dir(slot).to_path_buf())
+        })
+        .unwrap_or_else(|| cfg.extraction_dir("<artifact>"));
+
+    let mut all_set = true;
+    for (name, value) in script::build_script_env(cfg, current_version, version, &working_dir) {
+        match value {
+            script::ScriptEnvValue::Plain(value) => println!("{}={}", name, value),
+            script::ScriptEnvValue::Secret(Ok(_)) => println!("{}=[set]", name),
+            script::ScriptEnvValue::Secret(Err(e)) => {
+                all_set = false;
+                println!("{}=[unset]", name);
+                eprintln!("{}: {}", name, e);
+            }
+        }
+    }
+    all_set
+}
+
+/// `--recover`: wipes `download_base_dir` and the failure record, then runs
+/// one cycle that downloads and installs the latest version even if it is
+/// already installed; an older one is still refused. Progress is reported as
+/// `recovery ...` statuses. Returns the exit status: 0 once the version was
+/// installed, else that of the cycle's outcome but never 0.
+async fn recover(cfg: &Config, api: &ApiClient, operator_cancel: &OperatorCancel) -> i32 {
+    let current_version = get_current_version(cfg).unwrap_or(cfg.minimum_version);
+    tracing::warn!("Recovery requested, clearing {:?}", cfg.download_base_dir);
+    let removed = match recover::wipe_download_dir(cfg) {
+        Ok(removed) => removed,
+        Err(e) => {
+            tracing::error!("Recovery failed: {}", e);
+            api.report_status(current_version, format!("recovery failed: {}", e))
+                .await
+                .ok();
+            return 1;
+        }
+    };
+    api.report_status(
+        current_version,
+        format!("recovery started: removed {} local entries", removed),
+    )
+    .await
+    .ok();
+
+    let mut timings = PhaseTimings::default();
+    let cancel = operator_cancel.next_cycle();
+    let result = match tokio::time::timeout(
+        Duration::from_secs(cfg.cycle_timeout_seconds),
+        run_update_cycle(cfg, api, current_version, &mut timings, &cancel, true),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => {
+            cleanup_in_progress_files(&cfg.download_base_dir);
+            Err(UpdateError::TimeoutError)
+        }
+    };
+    timings.finish();
+
+    let installed = get_current_version(cfg).unwrap_or(current_version);
+    let (exit_code, message) = match &result {
+        Ok(CycleReport {
+            outcome: CycleOutcome::Updated { to, .. },
+            ..
+        }) => (0, format!("recovery finished: version {} installed", to)),
+        Ok(report) => (
+            report.outcome.exit_code().max(1),
+            format!("recovery failed: {}", report.outcome),
+        ),
+        Err(e) => (1, format!("recovery failed: {}", e)),
+    };
+    let recovered = exit_code == 0;
+    if recovered {
+        tracing::info!("{}", message);
+    } else {
+        tracing::error!("{}", message);
+    }
+    status_line::finished(&message);
+    api.send_status(StatusReportPayload::new(installed, message).terminal())
+        .await
+        .ok();
+    api.flush_status_reports().await.ok();
+    if recovered && cfg.reboot_after_update {
+        reboot::reboot_after_update(cfg, api, installed).await;
+    }
+    exit_code
+}
+
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive("embedded_updater=info".parse().unwrap()),
-        )
-        .with_timer(tracing_subscriber::fmt::time::UtcTime::rfc_3339())
-        .init();
+    let args = match CliArgs::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if args.version {
+        print_version();
+        return;
+    }
+
+    if let Some(archive) = &args.inspect {
+        match extract::list_archive_entries(archive) {
+            Ok(entries) => {
+                for entry in entries {
+                    println!(
+                        "{}\t{}\t{}\t{}\t{}",
+                        entry.name,
+                        entry.compressed_size,
+                        entry.uncompressed_size,
+                        if entry.is_dir { "dir" } else { "file" },
+                        entry
+                            .unix_mode
+                            .map(|mode| format!("{:o}", mode))
+                            .unwrap_or_else(|| "-".to_string())
+                    );
+                }
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    tracing::info!("Embedded Updater starting...");
     let config_path =
         env::var("PODBOX_UPDATE_CONF").unwrap_or("/etc/podbox_update/config.toml".to_string()); // Or get from command line arguments
-    let mut config = match Config::load(&config_path) {
-        Ok(c) => c,
+    let mut config_mtime = config_modified(&config_path);
+    let loaded = load_config(&config_path, &args);
+
+    // The log destination comes from the config, so it is read before any
+    // logging happens; load errors are reported once logging is up.
+    let _telemetry = match logging::init(loaded.as_ref().ok().map(|(c, _)| c), args.tail_status) {
+        Ok(telemetry) => telemetry,
+        Err(e) => {
+            eprintln!("Failed to set up logging: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    tracing::info!("Embedded Updater starting...");
+    let (mut config, warnings) = match loaded {
+        Ok(loaded) => loaded,
         Err(e) => {
             tracing::error!("Failed to load configuration: {}", e);
+            if args.selftest {
+                println!("[FAIL] configuration: {}", e);
+                std::process::exit(1);
+            }
             return;
         }
     };
     tracing::info!("Configuration loaded: {:?}", config.service_name);
+    for warning in &warnings {
+        tracing::warn!("{}", warning);
+    }
 
     let token = config.device_token.clone();
 
-    let api_client = ApiClient::new(config.clone(), token);
+    let mut api_client = ApiClient::new(config.clone(), token);
 
-    loop {
-        if let Err(e) = reset_ntp_service() {
-            tracing::warn!("ntp reset error: {}", e);
+    if args.clear_quarantine {
+        match FailureRecord::clear(&config.download_base_dir) {
+            Ok(()) => {
+                println!("quarantine cleared");
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
         }
+    }
+
+    if let Some(version) = args.show_script_env {
+        let passed = show_script_env(&config, version);
+        std::process::exit(if passed { 0 } else { 1 });
+    }
 
+    if args.plan {
         let current_version = get_current_version(&config).unwrap_or(0);
+        match plan::build(&config, &api_client, current_version).await {
+            Ok(plan) => {
+                println!("{}", serde_json::to_string_pretty(&plan).unwrap());
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if args.selftest {
+        println!("[PASS] configuration");
+        let passed = selftest::run(&config, &api_client).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    if config.enable_time_sync {
+        if let Err(e) = reset_ntp_service(&config) {
+            tracing::warn!("ntp reset error: {}", e);
+        }
+    }
+
+    restore_leftover_snapshot(&config, &api_client).await;
+
+    let operator_cancel = OperatorCancel::listen(config.control_socket_path.as_deref());
+    if args.tail_status {
+        status_line::enable();
+    }
+
+    let mut flush_on_terminate: Option<FlushOnTerminate> = None;
+    if args.recover {
+        if config.batch_status_reports {
+            flush_on_terminate
+                .get_or_insert_with(FlushOnTerminate::listen)
+                .watch(&api_client);
+        }
+        std::process::exit(recover(&config, &api_client, &operator_cancel).await);
+    }
+
+    // Devices powered up together would otherwise all check at once. SIGTERM
+    // still ends the process while it waits.
+    let startup_wait = config.startup_delay_seconds + random::up_to(config.startup_jitter_seconds);
+    if startup_wait > 0 {
+        tracing::info!(
+            "Waiting {} seconds before the first update check",
+            startup_wait
+        );
+        tokio::time::sleep(Duration::from_secs(startup_wait)).await;
+    }
+
+    let mut cycles: u64 = 0;
+    loop {
+        if config.watch_config {
+            let mtime = config_modified(&config_path);
+            if mtime != config_mtime {
+                config_mtime = mtime;
+                match load_config(&config_path, &args) {
+                    Ok((c, warnings)) => {
+                        tracing::info!("Configuration file changed, reloaded it");
+                        for warning in &warnings {
+                            tracing::warn!("{}", warning);
+                        }
+                        config = c;
+                        api_client = ApiClient::new(config.clone(), config.device_token.clone())
+                            .with_pending_status_of(&api_client);
+                    }
+                    Err(e) => {
+                        tracing::warn!("Ignoring changed configuration, keeping the old one: {}", e)
+                    }
+                }
+            }
+        }
+
+        if config.batch_status_reports {
+            flush_on_terminate
+                .get_or_insert_with(FlushOnTerminate::listen)
+                .watch(&api_client);
+        }
+
+        // current_version_command may run for up to hook_timeout_seconds.
+        let version = {
+            let config = config.clone();
+            tokio::task::spawn_blocking(move || get_current_version(&config))
+                .await
+                .unwrap_or_else(|e| {
+                    Err(UpdateError::VersionCommandError(format!(
+                        "current version task failed: {}",
+                        e
+                    )))
+                })
+        };
+        let current_version = match version {
+            Ok(version) => version,
+            Err(UpdateError::DowngradeBlocked { version, minimum }) => {
+                tracing::error!(
+                    "Current version {} is below minimum_version {}, using the floor instead",
+                    version,
+                    minimum
+                );
+                minimum
+            }
+            Err(e @ UpdateError::VersionCommandError(_)) => {
+                tracing::error!(
+                    "{}, skipping this cycle. Sleeping for {} seconds.",
+                    e,
+                    config.poll_interval_seconds
+                );
+                tokio::time::sleep(Duration::from_secs(config.poll_interval_seconds)).await;
+                continue;
+            }
+            Err(_) => 0,
+        };
         tracing::info!("Current service version: {}", current_version);
 
         tracing::info!("Starting update check cycle...");
-        if let Err(e) = run_update_cycle(&mut config, &api_client, current_version).await {
-            tracing::error!("Update cycle ended with error: {}", e);
-            // Decide on error recovery strategy here. For now, we just log and continue.
+        let cycle_timeout = Duration::from_secs(config.cycle_timeout_seconds);
+        let mut timings = PhaseTimings::default();
+        let cancel = operator_cancel.next_cycle();
+        let poll_interval = Duration::from_secs(config.poll_interval_seconds);
+        let mut installed = None;
+        // What reaching max_cycles exits with.
+        let exit_code;
+        let next_poll = match tokio::time::timeout(
+            cycle_timeout,
+            run_update_cycle(
+                &config,
+                &api_client,
+                current_version,
+                &mut timings,
+                &cancel,
+                false,
+            ),
+        )
+        .await
+        {
+            Ok(Ok(report)) => {
+                if report.outcome.is_failure() {
+                    tracing::warn!("Update cycle outcome: {}", report.outcome);
+                } else {
+                    tracing::info!("Update cycle outcome: {}", report.outcome);
+                }
+                status_line::finished(&report.outcome);
+                exit_code = report.outcome.exit_code();
+                if let CycleOutcome::Updated { to, .. } = report.outcome {
+                    installed = Some(to);
+                }
+                report.next_poll
+            }
+            Ok(Err(e)) => {
+                tracing::error!("Update cycle ended with error: {}", e);
+                status_line::finished(format!("error: {}", e));
+                exit_code = 1;
+                poll_interval
+            }
+            Err(_) => {
+                tracing::error!(
+                    "Update cycle did not finish within {} seconds, aborting it",
+                    config.cycle_timeout_seconds
+                );
+                status_line::finished("timed out");
+                cleanup_in_progress_files(&config.download_base_dir);
+                exit_code = 1;
+                poll_interval
+            }
+        };
+
+        timings.finish();
+        if timings.did_work() {
+            tracing::info!("Update cycle phase timings: {}", timings.summary());
+        }
+        api_client.flush_status_reports().await.ok();
+        // A throttling server is not contacted again before it asked to be.
+        let next_poll = match api_client.throttle_remaining() {
+            Some(remaining) if remaining > next_poll => {
+                tracing::warn!(
+                    "Server asked to back off, next check in {} seconds",
+                    remaining.as_secs()
+                );
+                remaining
+            }
+            _ => next_poll,
+        };
+        if let (Some(version), true) = (installed, config.reboot_after_update) {
+            if reboot::reboot_after_update(&config, &api_client, version).await {
+                // Another cycle could start installing while the system shuts
+                // down; wait to be stopped instead.
+                tracing::info!("Reboot issued, no further update cycles");
+                std::future::pending::<()>().await;
+            }
+        }
+
+        // Only checked between cycles, so an update is never cut short.
+        cycles += 1;
+        if config.max_cycles != 0 && cycles >= config.max_cycles {
+            tracing::info!("reached max_cycles, exiting for supervisor restart");
+            std::process::exit(exit_code);
         }
 
         tracing::info!(
             "Update check cycle finished. Sleeping for {} seconds.",
-            config.poll_interval_seconds
+            next_poll.as_secs()
+        );
+        tokio::time::sleep(next_poll).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn process_alive(pid: libc::pid_t) -> bool {
+        // SAFETY: signal 0 only checks that the process exists.
+        unsafe { libc::kill(pid, 0) == 0 }
+    }
+
+    #[test]
+    fn server_requested_delay_is_clamped() {
+        let cfg = test_support::config(
+            "min_poll_interval_seconds = 60\nmax_poll_interval_seconds = 3600",
+        );
+        let update_info: UpdateInfo = serde_json::from_str(
+            r#"{"versionCode": 2, "fileUrl": "u", "pollIntervalSeconds": 120,
+                "retryAfterSeconds": 7200}"#,
+        )
+        .unwrap();
+
+        assert_eq!(update_info.requested_poll_seconds(), Some(7200));
+        assert_eq!(
+            next_poll_interval(&cfg, update_info.requested_poll_seconds()),
+            3600
+        );
+        assert_eq!(next_poll_interval(&cfg, Some(1)), 60);
+        assert_eq!(next_poll_interval(&cfg, None), cfg.poll_interval_seconds);
+    }
+
+    #[tokio::test]
+    async fn precheck_error_fails_the_cycle_instead_of_deferring_it() {
+        let cfg = test_support::config(
+            "precheck_command = \"sleep 5\"\nhook_timeout_seconds = 1\nbatch_status_reports = true",
+        );
+        let api = ApiClient::new(cfg.clone(), String::new());
+
+        let report = update_cycle(
+            &cfg,
+            &api,
+            1,
+            &mut PhaseTimings::default(),
+            &CancellationToken::new(),
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert!(
+            matches!(report.outcome, CycleOutcome::HookFailed { .. }),
+            "{}",
+            report.outcome
         );
-        tokio::time::sleep(Duration::from_secs(config.poll_interval_seconds)).await;
+        assert_eq!(report.outcome.exit_code(), 1);
+    }
+
+    #[tokio::test]
+    async fn cycle_timeout_stops_a_hanging_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let script_path = dir.path().join("update.sh");
+        fs::write(&script_path, "#!/bin/sh\necho $$ > pid\nexec sleep 1000\n").unwrap();
+        let cfg = test_support::config("");
+
+        let result = tokio::time::timeout(
+            Duration::from_millis(500),
+            run_update_script(
+                &cfg,
+                &script_path,
+                dir.path(),
+                (1, 2),
+                &script::ScriptLog::default(),
+                &CancellationToken::new(),
+            ),
+        )
+        .await;
+
+        assert!(result.is_err(), "the script should still be running");
+        let pid: libc::pid_t = fs::read_to_string(dir.path().join("pid"))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while process_alive(pid) && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(!process_alive(pid), "script {} outlived the timeout", pid);
     }
 }
@@ -0,0 +1,28 @@
+use crate::error::UpdateError;
+use std::fs;
+
+const MEMINFO_PATH: &str = "/proc/meminfo";
+
+/// Memory available for starting new programs without swapping, as the
+/// kernel estimates it (`MemAvailable` in `/proc/meminfo`).
+pub fn available_bytes() -> Result<u64, UpdateError> {
+    let meminfo = fs::read_to_string(MEMINFO_PATH)
+        .map_err(|e| UpdateError::FileIOError(format!("Failed to read {}: {}", MEMINFO_PATH, e)))?;
+    parse_mem_available(&meminfo).ok_or_else(|| {
+        UpdateError::FileIOError(format!("No MemAvailable line in {}", MEMINFO_PATH))
+    })
+}
+
+/// `MemAvailable:   123456 kB` -> bytes.
+fn parse_mem_available(meminfo: &str) -> Option<u64> {
+    let line = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?;
+    let mut words = line.split_whitespace();
+    let value: u64 = words.next()?.parse().ok()?;
+    match words.next() {
+        Some("kB") => value.checked_mul(1024),
+        None => Some(value),
+        Some(_) => None,
+    }
+}
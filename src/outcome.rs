@@ -0,0 +1,155 @@
+use std::fmt;
+use tokio::time::Duration;
+
+/// What an update cycle did. Failures listed here were already reported to
+/// the server; errors the cycle couldn't handle are returned as `UpdateError`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CycleOutcome {
+    /// Nothing newer than the installed version is offered.
+    UpToDate,
+    /// The update was installed.
+    Updated {
+        from: i32,
+        to: i32,
+    },
+    /// The version offered on first boot was recorded as installed without
+    /// running its script (`first_boot_policy = "record_only"`).
+    Recorded {
+        version: i32,
+    },
+    /// The cycle stood down before installing (paused, vetoed by a precheck,
+    /// throttled, low battery...); a later cycle may go ahead.
+    Deferred(String),
+    /// The offered version won't be installed, being below
+    /// `minimum_version`, built for another model or quarantined.
+    Rejected {
+        version: i32,
+        reason: String,
+    },
+    /// The update check failed.
+    CheckFailed(String),
+    DownloadFailed {
+        version: i32,
+        reason: String,
+    },
+    /// Verifying, decrypting or extracting the artifact failed.
+    InstallFailed {
+        version: i32,
+        reason: String,
+    },
+    /// The update or commit script failed.
+    ScriptFailed {
+        version: i32,
+        reason: String,
+    },
+    /// A precheck or probe command could not give an answer: it failed to
+    /// start or timed out. Unlike a veto this is a fault on the device.
+    HookFailed {
+        hook: String,
+        reason: String,
+    },
+    /// An operator cancelled the update (`SIGUSR1` or `CANCEL`).
+    Cancelled {
+        version: i32,
+    },
+}
+
+impl CycleOutcome {
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            CycleOutcome::CheckFailed(_)
+                | CycleOutcome::DownloadFailed { .. }
+                | CycleOutcome::InstallFailed { .. }
+                | CycleOutcome::ScriptFailed { .. }
+                | CycleOutcome::HookFailed { .. }
+        )
+    }
+
+    /// Process exit status for a run ending with this outcome: 0 when
+    /// nothing went wrong, 1 for a failure, 3 for a rejected update and 4
+    /// for an operator cancel (2 is a usage error).
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CycleOutcome::Rejected { .. } => 3,
+            CycleOutcome::Cancelled { .. } => 4,
+            outcome if outcome.is_failure() => 1,
+            _ => 0,
+        }
+    }
+}
+
+impl fmt::Display for CycleOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CycleOutcome::UpToDate => write!(f, "up to date"),
+            CycleOutcome::Updated { from, to } => write!(f, "updated from {} to {}", from, to),
+            CycleOutcome::Recorded { version } => {
+                write!(f, "version {} recorded as installed on first boot", version)
+            }
+            CycleOutcome::Deferred(reason) => write!(f, "deferred: {}", reason),
+            CycleOutcome::Rejected { version, reason } => {
+                write!(f, "version {} rejected: {}", version, reason)
+            }
+            CycleOutcome::CheckFailed(reason) => write!(f, "update check failed: {}", reason),
+            CycleOutcome::DownloadFailed { version, reason } => {
+                write!(f, "download of version {} failed: {}", version, reason)
+            }
+            CycleOutcome::InstallFailed { version, reason } => {
+                write!(f, "installing version {} failed: {}", version, reason)
+            }
+            CycleOutcome::ScriptFailed { version, reason } => {
+                write!(f, "script of version {} failed: {}", version, reason)
+            }
+            CycleOutcome::HookFailed { hook, reason } => write!(f, "{} failed: {}", hook, reason),
+            CycleOutcome::Cancelled { version } => {
+                write!(f, "update to version {} cancelled by operator", version)
+            }
+        }
+    }
+}
+
+/// Result of a cycle together with how long to sleep before the next one.
+#[derive(Debug)]
+pub struct CycleReport {
+    pub outcome: CycleOutcome,
+    pub next_poll: Duration,
+}
+
+impl CycleReport {
+    pub fn new(outcome: CycleOutcome, next_poll_seconds: u64) -> Self {
+        CycleReport {
+            outcome,
+            next_poll: Duration::from_secs(next_poll_seconds),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hook_errors_are_failures_not_deferrals() {
+        let hook_failed = CycleOutcome::HookFailed {
+            hook: "precheck".to_string(),
+            reason: "timed out".to_string(),
+        };
+        assert!(hook_failed.is_failure());
+        assert_eq!(hook_failed.exit_code(), 1);
+        assert_eq!(hook_failed.to_string(), "precheck failed: timed out");
+
+        let deferred = CycleOutcome::Deferred("low battery".to_string());
+        assert!(!deferred.is_failure());
+        assert_eq!(deferred.exit_code(), 0);
+        assert_eq!(
+            CycleOutcome::Rejected {
+                version: 2,
+                reason: "quarantined".to_string()
+            }
+            .exit_code(),
+            3
+        );
+        assert_eq!(CycleOutcome::Cancelled { version: 2 }.exit_code(), 4);
+    }
+}
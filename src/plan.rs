@@ -0,0 +1,63 @@
+use crate::api_client::ApiClient;
+use crate::config::Config;
+use crate::error::UpdateError;
+use crate::local_source;
+use serde::Serialize;
+
+/// Assumed uncompressed:compressed ratio of an update archive when estimating
+/// the disk space an update needs.
+const ESTIMATED_EXPANSION_RATIO: u64 = 2;
+
+/// What the next update cycle would do, assembled without downloading.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdatePlan {
+    pub current_version: i32,
+    pub target_version: i32,
+    pub update_available: bool,
+    pub file_url: String,
+    pub download_size: Option<u64>,
+    /// Updates are always full archives for now.
+    pub delta: bool,
+    /// Archive, decrypted copy and extracted tree, with the tree estimated
+    /// from `ESTIMATED_EXPANSION_RATIO`.
+    pub estimated_disk_bytes: Option<u64>,
+    pub reboot_expected: Option<bool>,
+}
+
+pub async fn build(
+    cfg: &Config,
+    api: &ApiClient,
+    current_version: i32,
+) -> Result<UpdatePlan, UpdateError> {
+    let (update_info, download_size) = match &cfg.local_source_dir {
+        Some(dir) => {
+            let (update_info, archive_path) =
+                local_source::find_update(dir)?.ok_or_else(|| UpdateError::NoUpdateAvailable)?;
+            let size = std::fs::metadata(&archive_path).map(|m| m.len()).ok();
+            (update_info, size)
+        }
+        None => {
+            let update_info = api.check_for_updates().await?;
+            let size = match update_info.size {
+                Some(size) => Some(size),
+                None => api.remote_size(&update_info.file_url).await?,
+            };
+            (update_info, size)
+        }
+    };
+
+    let copies = if cfg.encrypted_updates { 2 } else { 1 };
+    Ok(UpdatePlan {
+        current_version,
+        target_version: update_info.version_code,
+        update_available: update_info.version_code > current_version
+            && update_info.version_code >= cfg.minimum_version,
+        file_url: update_info.file_url,
+        download_size,
+        delta: false,
+        estimated_disk_bytes: download_size
+            .map(|size| size * copies + size * ESTIMATED_EXPANSION_RATIO),
+        reboot_expected: update_info.reboot_required,
+    })
+}
@@ -0,0 +1,66 @@
+use crate::error::UpdateError;
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::Path};
+
+/// File under `download_base_dir` counting failures of the latest version.
+pub const QUARANTINE_FILE_NAME: &str = "quarantine.json";
+
+/// Consecutive failures of one version. A version reaching
+/// `max_attempts_per_version` is skipped until a newer one appears or the
+/// record is cleared.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct FailureRecord {
+    pub version: i32,
+    pub failures: u32,
+}
+
+impl FailureRecord {
+    pub fn load(base_dir: &Path) -> Option<Self> {
+        let path = base_dir.join(QUARANTINE_FILE_NAME);
+        let data = fs::read(&path).ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                tracing::warn!("Ignoring corrupt quarantine record {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    pub fn is_quarantined(&self, version: i32, max_attempts: u32) -> bool {
+        max_attempts != 0 && self.version == version && self.failures >= max_attempts
+    }
+
+    /// Counts a failure of `version` and returns the updated record.
+    pub fn record_failure(base_dir: &Path, version: i32) -> Result<Self, UpdateError> {
+        let mut record = Self::load(base_dir)
+            .filter(|record| record.version == version)
+            .unwrap_or(FailureRecord {
+                version,
+                failures: 0,
+            });
+        record.failures += 1;
+
+        let path = base_dir.join(QUARANTINE_FILE_NAME);
+        let data = serde_json::to_vec(&record).map_err(|e| {
+            UpdateError::FileIOError(format!("Failed to serialize quarantine record: {}", e))
+        })?;
+        fs::write(&path, data).map_err(|e| {
+            UpdateError::FileIOError(format!(
+                "Failed to write quarantine record {:?}: {}",
+                path, e
+            ))
+        })?;
+        Ok(record)
+    }
+
+    pub fn clear(base_dir: &Path) -> Result<(), UpdateError> {
+        let path = base_dir.join(QUARANTINE_FILE_NAME);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(UpdateError::FileIOError(
+                format!("Failed to remove quarantine record {:?}: {}", path, e),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
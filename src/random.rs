@@ -0,0 +1,25 @@
+use std::io;
+
+/// Fills `buf` from the kernel's random number generator.
+pub fn fill(buf: &mut [u8]) -> io::Result<()> {
+    // SAFETY: `buf` is valid for writes of its length.
+    let filled = unsafe { libc::getrandom(buf.as_mut_ptr().cast(), buf.len(), 0) };
+    if filled != buf.len() as isize {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A uniformly distributed value in `0..=max`, or 0 when the kernel has no
+/// randomness to give.
+pub fn up_to(max: u64) -> u64 {
+    let mut bytes = [0u8; 8];
+    if let Err(e) = fill(&mut bytes) {
+        tracing::warn!("Failed to read random bytes: {}", e);
+        return 0;
+    }
+    match max.checked_add(1) {
+        Some(range) => u64::from_ne_bytes(bytes) % range,
+        None => u64::from_ne_bytes(bytes),
+    }
+}
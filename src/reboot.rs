@@ -0,0 +1,94 @@
+use crate::api_client::ApiClient;
+use crate::config::Config;
+use crate::error::UpdateError;
+use crate::hooks;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Reboot run when no `reboot_command` is set.
+const DEFAULT_REBOOT_COMMAND: &str = "/usr/bin/sudo /usr/bin/systemctl reboot";
+
+/// How often `reboot_idle_command` is asked whether the device is idle.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Waits until `reboot_idle_command` reports the device idle, at most
+/// `reboot_max_wait_seconds`, reporting the pending reboot meanwhile. Each
+/// probe may run for `hook_timeout_seconds`.
+async fn wait_for_idle(cfg: &Config, api: &ApiClient, version: i32, idle_command: &str) {
+    let deadline = Instant::now() + Duration::from_secs(cfg.reboot_max_wait_seconds);
+    let mut reported = false;
+    loop {
+        match hooks::run_gate_hook(cfg, "reboot idle", idle_command, &CancellationToken::new())
+            .await
+        {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(e) => tracing::warn!("{}", e),
+        }
+        if Instant::now() >= deadline {
+            tracing::warn!(
+                "Device still busy after {} seconds, rebooting anyway",
+                cfg.reboot_max_wait_seconds
+            );
+            return;
+        }
+        if !reported {
+            tracing::info!("update applied, reboot pending (waiting for idle)");
+            api.report_status(
+                version,
+                "update applied, reboot pending (waiting for idle)".to_string(),
+            )
+            .await
+            .ok();
+            api.flush_status_reports().await.ok();
+            reported = true;
+        }
+        tokio::time::sleep(
+            IDLE_POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())),
+        )
+        .await;
+    }
+}
+
+/// Reboots the device after `version` was installed, once it is idle when
+/// `reboot_idle_command` is set. Reports still waiting are sent first.
+/// Returns whether the reboot command succeeded, i.e. the device is going
+/// down.
+pub async fn reboot_after_update(cfg: &Config, api: &ApiClient, version: i32) -> bool {
+    if let Some(idle_command) = &cfg.reboot_idle_command {
+        wait_for_idle(cfg, api, version, idle_command).await;
+    }
+
+    tracing::info!("Rebooting to complete the update to version {}", version);
+    api.report_status(version, "rebooting to complete the update".to_string())
+        .await
+        .ok();
+    api.flush_status_reports().await.ok();
+
+    let command = cfg
+        .reboot_command
+        .as_deref()
+        .unwrap_or(DEFAULT_REBOOT_COMMAND);
+    let result = hooks::run_gate_hook(cfg, "reboot", command, &CancellationToken::new())
+        .await
+        .and_then(|rebooting| {
+            if rebooting {
+                Ok(())
+            } else {
+                Err(UpdateError::HookError(
+                    "reboot command exited nonzero".to_string(),
+                ))
+            }
+        });
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::error!("Reboot failed: {}", e);
+            api.report_status(version, format!("reboot failed: {}", e))
+                .await
+                .ok();
+            api.flush_status_reports().await.ok();
+            false
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use crate::build_info;
+use crate::config::Config;
+use crate::error::UpdateError;
+use crate::version_history;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Files under `download_base_dir` that `--recover` leaves alone: the log,
+/// the version file, the A/B slots and `install_dir` if they live there, the
+/// installed build record and version history, and the captured status
+/// failures kept for diagnosis.
+fn preserved_paths(cfg: &Config) -> Vec<PathBuf> {
+    let mut paths = vec![
+        cfg.current_version_file.clone(),
+        cfg.download_base_dir.join("status_failures.jsonl"),
+        cfg.download_base_dir
+            .join(build_info::INSTALLED_BUILD_INFO_FILE_NAME),
+        cfg.download_base_dir
+            .join(version_history::HISTORY_FILE_NAME),
+    ];
+    if let Some(slots) = &cfg.ab_slots {
+        paths.push(slots.slot_a_dir.clone());
+        paths.push(slots.slot_b_dir.clone());
+    }
+    if let Some(dir) = &cfg.install_dir {
+        paths.push(dir.clone());
+    }
+    paths
+}
+
+/// Whether removing `path` would take a preserved file with it. Rotated
+/// logs are named after the log file, so they are matched by prefix.
+fn holds_preserved(cfg: &Config, path: &Path, preserved: &[PathBuf]) -> bool {
+    let is_log = cfg.log_file.as_ref().is_some_and(|log| {
+        log.starts_with(path)
+            || (log.parent() == path.parent()
+                && log
+                    .file_name()
+                    .zip(path.file_name())
+                    .is_some_and(|(log, name)| {
+                        name.to_string_lossy()
+                            .starts_with(log.to_string_lossy().as_ref())
+                    }))
+    });
+    is_log || preserved.iter().any(|p| p.starts_with(path))
+}
+
+/// Empties `download_base_dir` for `--recover`: downloads, partials and their
+/// resume state, extracted trees and the quarantine record all go, so the
+/// following cycle starts from scratch. Returns how many entries were removed.
+pub fn wipe_download_dir(cfg: &Config) -> Result<usize, UpdateError> {
+    let dir = &cfg.download_base_dir;
+    let preserved = preserved_paths(cfg);
+    let entries = fs::read_dir(dir).map_err(|e| {
+        UpdateError::FileSystemError(format!("Failed to list {:?} for recovery: {}", dir, e))
+    })?;
+
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if holds_preserved(cfg, &path, &preserved) {
+            tracing::debug!("Recovery keeps {:?}", path);
+            continue;
+        }
+        let result = if path.is_dir() && !path.is_symlink() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        result.map_err(|e| {
+            UpdateError::FileSystemError(format!(
+                "Failed to remove {:?} during recovery: {}",
+                path, e
+            ))
+        })?;
+        tracing::info!("Recovery removed {:?}", path);
+        removed += 1;
+    }
+    Ok(removed)
+}
@@ -0,0 +1,156 @@
+use crate::error::UpdateError;
+use crate::random;
+use std::{future::Future, time::Duration};
+
+/// How often, and how patiently, a failing operation is attempted.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one; at least one is made.
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub base_delay: Duration,
+    /// Factor the delay grows by after each retry.
+    pub multiplier: u32,
+    /// Ceiling on the delay between two attempts.
+    pub max_delay: Duration,
+    /// Whether an error is worth another attempt.
+    pub retryable: fn(&UpdateError) -> bool,
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `retry`, counting from 1.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .max(1)
+            .saturating_pow(retry.saturating_sub(1));
+        self.base_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+
+    /// `delay(retry)` with equal jitter, somewhere between half of it and all
+    /// of it, so devices failing together don't retry in lockstep.
+    pub fn jittered_delay(&self, retry: u32) -> Duration {
+        let delay = self.delay(retry);
+        let half = delay / 2;
+        let spread = u64::try_from((delay - half).as_nanos()).unwrap_or(u64::MAX);
+        half + Duration::from_nanos(random::up_to(spread))
+    }
+}
+
+/// Connection failures and timeouts, which a later attempt may get past.
+/// Answers from the server, including throttling and auth failures, are not.
+pub fn is_transient(e: &UpdateError) -> bool {
+    match e {
+        UpdateError::ApiClientError(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+        UpdateError::TimeoutError => true,
+        _ => false,
+    }
+}
+
+/// Runs `operation` until it succeeds, fails with an error `policy` doesn't
+/// retry, or runs out of attempts, sleeping with jittered exponential backoff
+/// in between. `what` names the operation in the logs.
+pub async fn retry_with_backoff<T, F, Fut>(
+    policy: &RetryPolicy,
+    what: &str,
+    mut operation: F,
+) -> Result<T, UpdateError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, UpdateError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < policy.max_attempts && (policy.retryable)(&e) => {
+                let delay = policy.jittered_delay(attempt);
+                tracing::debug!(
+                    "{} attempt {} failed ({}), retrying in {:?}",
+                    what,
+                    attempt,
+                    e,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                if attempt > 1 {
+                    tracing::warn!("{} failed after {} attempts", what, attempt);
+                }
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        max_attempts: 4,
+        base_delay: Duration::from_millis(1),
+        multiplier: 2,
+        max_delay: Duration::from_millis(5),
+        retryable: is_transient,
+    };
+
+    #[test]
+    fn delay_grows_by_multiplier() {
+        let delays: Vec<_> = (1..=3).map(|retry| POLICY.delay(retry)).collect();
+        assert_eq!(delays, [1, 2, 4].map(Duration::from_millis),);
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        assert_eq!(POLICY.delay(4), Duration::from_millis(5));
+        // Overflowing the multiplication still ends up at the ceiling.
+        assert_eq!(POLICY.delay(u32::MAX), Duration::from_millis(5));
+    }
+
+    #[test]
+    fn jittered_delay_stays_within_half_and_full_delay() {
+        for retry in 1..=5 {
+            let delay = POLICY.delay(retry);
+            for _ in 0..50 {
+                let jittered = POLICY.jittered_delay(retry);
+                assert!(
+                    jittered >= delay / 2 && jittered <= delay,
+                    "{:?} outside {:?}..={:?}",
+                    jittered,
+                    delay / 2,
+                    delay
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = retry_with_backoff(&POLICY, "test", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(UpdateError::TimeoutError)
+        })
+        .await;
+        assert!(matches!(result, Err(UpdateError::TimeoutError)));
+        assert_eq!(calls.load(Ordering::SeqCst), POLICY.max_attempts);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_permanent_errors() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = retry_with_backoff(&POLICY, "test", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(UpdateError::ConfigError("bad".to_string()))
+        })
+        .await;
+        assert!(matches!(result, Err(UpdateError::ConfigError(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
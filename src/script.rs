@@ -0,0 +1,691 @@
+use crate::config::Config;
+use crate::error::UpdateError;
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{fs::PermissionsExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+
+/// How often a running script is checked for having exited or been cancelled.
+const SCRIPT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Time a cancelled script gets to exit after `SIGTERM` before it is killed.
+const SCRIPT_TERM_GRACE: Duration = Duration::from_secs(10);
+
+/// Number of trailing output lines kept per stream for the failure message.
+const OUTPUT_TAIL_LINES: usize = 50;
+/// Longest line kept in the tail; longer lines are cut.
+const OUTPUT_TAIL_LINE_MAX: usize = 1024;
+/// Longest line read from a script's output; the rest of a longer one is
+/// skipped, so output without newlines can't exhaust memory.
+const OUTPUT_LINE_MAX_BYTES: u64 = 64 * 1024;
+
+/// Substrings (compared case-insensitively) of update script stderr that
+/// mean it ran out of disk space; `disk_full_patterns` adds to them.
+const DISK_FULL_PATTERNS: &[&str] = &["No space left on device", "Disk quota exceeded", "ENOSPC"];
+
+/// IO scheduling class applied to the update script, as with `ionice -c`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoniceClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+impl IoniceClass {
+    /// `ioprio` value for `ioprio_set`; realtime and best-effort get the
+    /// lowest level (7) within their class.
+    fn ioprio(self) -> libc::c_int {
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        let (class, level) = match self {
+            IoniceClass::Realtime => (1, 7),
+            IoniceClass::BestEffort => (2, 7),
+            IoniceClass::Idle => (3, 0),
+        };
+        (class << IOPRIO_CLASS_SHIFT) | level
+    }
+}
+
+/// Lowers the CPU and IO priority of the script process between fork and
+/// exec, so an update can't starve the device's primary workload.
+fn apply_script_priority(command: &mut Command, cfg: &Config) {
+    let nice = cfg.script_nice;
+    let ioprio = cfg.script_ionice_class.map(IoniceClass::ioprio);
+    if nice.is_none() && ioprio.is_none() {
+        return;
+    }
+
+    // SAFETY: the closure only issues async-signal-safe syscalls.
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(nice) = nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(ioprio) = ioprio {
+                const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+                if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+#[derive(Clone, Copy)]
+enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Output of the update script kept for `log_upload_api_url`: both streams
+/// interleaved as they were read, cut down to the last `max_bytes`.
+#[derive(Clone, Default)]
+pub struct ScriptLog {
+    inner: Arc<Mutex<CapturedLog>>,
+}
+
+#[derive(Default)]
+struct CapturedLog {
+    lines: VecDeque<String>,
+    bytes: usize,
+    dropped_bytes: usize,
+    max_bytes: usize,
+}
+
+impl ScriptLog {
+    /// A log capturing up to `log_upload_max_bytes` when `log_upload_api_url`
+    /// is set, and nothing otherwise.
+    pub fn for_upload(cfg: &Config) -> Self {
+        let log = ScriptLog::default();
+        if cfg.log_upload_api_url.is_some() {
+            log.inner.lock().unwrap().max_bytes = cfg.log_upload_max_bytes;
+        }
+        log
+    }
+
+    fn push(&self, stream: OutputStream, line: &str) {
+        let mut log = self.inner.lock().unwrap();
+        if log.max_bytes == 0 {
+            return;
+        }
+        let line = match stream {
+            OutputStream::Stdout => format!("{}\n", line),
+            OutputStream::Stderr => format!("[stderr] {}\n", line),
+        };
+        log.bytes += line.len();
+        log.lines.push_back(line);
+        while log.bytes > log.max_bytes {
+            let Some(dropped) = log.lines.pop_front() else {
+                break;
+            };
+            log.bytes -= dropped.len();
+            log.dropped_bytes += dropped.len();
+        }
+    }
+
+    /// The captured output, gzipped; `None` when nothing was captured.
+    pub fn gzipped(&self) -> Option<Vec<u8>> {
+        let log = self.inner.lock().unwrap();
+        if log.lines.is_empty() {
+            return None;
+        }
+        log.gzip()
+            .map_err(|e| tracing::warn!("Failed to compress update script log: {}", e))
+            .ok()
+    }
+}
+
+impl CapturedLog {
+    fn gzip(&self) -> io::Result<Vec<u8>> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        if self.dropped_bytes > 0 {
+            writeln!(encoder, "[{} earlier bytes dropped]", self.dropped_bytes)?;
+        }
+        for line in &self.lines {
+            encoder.write_all(line.as_bytes())?;
+        }
+        encoder.finish()
+    }
+}
+
+/// What is left of a script's output stream once it closed.
+#[derive(Default)]
+struct StreamOutput {
+    /// The last `OUTPUT_TAIL_LINES` lines.
+    tail: VecDeque<String>,
+    /// First line matching one of the disk-full patterns.
+    disk_full: Option<String>,
+}
+
+/// Lowercased patterns marking a stderr line as a full disk.
+fn disk_full_patterns(cfg: &Config) -> Vec<String> {
+    DISK_FULL_PATTERNS
+        .iter()
+        .copied()
+        .chain(cfg.disk_full_patterns.iter().map(String::as_str))
+        .filter(|pattern| !pattern.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Discards the rest of an overlong line, up to and including its newline.
+fn skip_line<R: BufRead>(reader: &mut R) -> io::Result<()> {
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(());
+        }
+        match available.iter().position(|&byte| byte == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let len = available.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+/// Forwards a child's output to tracing and `log` line by line as it is
+/// produced, looking for lines matching `disk_full_patterns`, and returns
+/// what is left once the stream closes.
+fn forward_output<R: Read + Send + 'static>(
+    reader: R,
+    stream: OutputStream,
+    log: ScriptLog,
+    disk_full_patterns: Vec<String>,
+) -> JoinHandle<StreamOutput> {
+    thread::spawn(move || {
+        let mut output = StreamOutput {
+            tail: VecDeque::with_capacity(OUTPUT_TAIL_LINES),
+            disk_full: None,
+        };
+        let mut reader = BufReader::new(reader);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            let read = (&mut reader)
+                .take(OUTPUT_LINE_MAX_BYTES)
+                .read_until(b'\n', &mut buf)
+                .and_then(|n| {
+                    if n as u64 == OUTPUT_LINE_MAX_BYTES && !buf.ends_with(b"\n") {
+                        skip_line(&mut reader)?;
+                        buf.extend_from_slice(b" [line truncated]");
+                    }
+                    Ok(n)
+                });
+            match read {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!("Failed to read update script output: {}", e);
+                    break;
+                }
+            }
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim_end_matches(['\r', '\n']);
+            match stream {
+                OutputStream::Stdout => tracing::info!("[update script] {}", line),
+                OutputStream::Stderr => tracing::warn!("[update script stderr] {}", line),
+            }
+            log.push(stream, line);
+
+            if output.disk_full.is_none() && !disk_full_patterns.is_empty() {
+                let lowercase = line.to_lowercase();
+                if disk_full_patterns
+                    .iter()
+                    .any(|pattern| lowercase.contains(pattern.as_str()))
+                {
+                    output.disk_full = Some(line.chars().take(OUTPUT_TAIL_LINE_MAX).collect());
+                }
+            }
+            if output.tail.len() == OUTPUT_TAIL_LINES {
+                output.tail.pop_front();
+            }
+            output
+                .tail
+                .push_back(line.chars().take(OUTPUT_TAIL_LINE_MAX).collect());
+        }
+        output
+    })
+}
+
+/// Value of a variable the updater sets in the update script's environment.
+pub enum ScriptEnvValue {
+    Plain(String),
+    /// From `script_secret_env` (or `db_password`); never printed.
+    Secret(Result<String, UpdateError>),
+}
+
+/// Variables added to the environment the update script inherits, for an
+/// update from `from` to `to` extracted into `working_dir`.
+pub fn build_script_env(
+    cfg: &Config,
+    from: i32,
+    to: i32,
+    working_dir: &Path,
+) -> Vec<(String, ScriptEnvValue)> {
+    let mut env: Vec<(String, ScriptEnvValue)> = [
+        ("PODBOX_SERVICE_NAME", cfg.service_name.clone()),
+        ("PODBOX_FROM_VERSION", from.to_string()),
+        ("PODBOX_TO_VERSION", to.to_string()),
+        ("PODBOX_UPDATE_DIR", working_dir.display().to_string()),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), ScriptEnvValue::Plain(value)))
+    .collect();
+    env.extend(
+        cfg.script_secrets()
+            .into_iter()
+            .map(|(name, value)| (name, ScriptEnvValue::Secret(value))),
+    );
+    env
+}
+
+/// Magic bytes of an ELF executable.
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+
+/// Rejects scripts that can't possibly run: empty files and, when executed
+/// directly with `require_script_shebang` on, files with neither a shebang
+/// nor an ELF header.
+fn validate_script(cfg: &Config, script_path: &Path) -> Result<(), UpdateError> {
+    let mut head = Vec::with_capacity(ELF_MAGIC.len());
+    std::fs::File::open(script_path)
+        .and_then(|file| file.take(ELF_MAGIC.len() as u64).read_to_end(&mut head))
+        .map_err(|e| {
+            UpdateError::FileSystemError(format!(
+                "Failed to read update script {:?}: {}",
+                script_path, e
+            ))
+        })?;
+
+    if head.is_empty() {
+        return Err(UpdateError::ScriptError(format!(
+            "update script appears invalid: {:?} is empty",
+            script_path
+        )));
+    }
+    if cfg.require_script_shebang
+        && cfg.script_interpreter.is_none()
+        && !head.starts_with(b"#!")
+        && !head.starts_with(ELF_MAGIC)
+    {
+        return Err(UpdateError::ScriptError(format!(
+            "update script appears invalid: {:?} has no #! line and is not an ELF binary",
+            script_path
+        )));
+    }
+    Ok(())
+}
+
+fn join_output(handle: JoinHandle<StreamOutput>) -> StreamOutput {
+    handle.join().unwrap_or_default()
+}
+
+fn make_executable(script_path: &Path) -> Result<(), UpdateError> {
+    // Make script executable (e.g., chmod +x) - specific to Unix-like systems
+    let metadata = std::fs::metadata(script_path).map_err(|e| {
+        UpdateError::FileSystemError(format!(
+            "Failed to get metadata for script {:?}: {}",
+            script_path, e
+        ))
+    })?;
+    let mut permissions = metadata.permissions();
+    permissions.set_mode(permissions.mode() | 0o755); // Add execute permissions for user, group, others (ugo+x)
+    std::fs::set_permissions(script_path, permissions).map_err(|e| {
+        UpdateError::FileSystemError(format!(
+            "Failed to set executable permission on script {:?}: {}",
+            script_path, e
+        ))
+    })?;
+
+    tracing::info!("Set executable permission on {:?}", script_path);
+    Ok(())
+}
+
+/// The update scripts of the tree at `dir`, in the order they run: the files
+/// of `update_scripts_dir` sorted by name when the tree has that directory,
+/// else just `update_script_name`.
+pub fn update_scripts(cfg: &Config, dir: &Path) -> Result<Vec<PathBuf>, UpdateError> {
+    let Some(scripts_dir) = cfg
+        .update_scripts_dir
+        .as_ref()
+        .map(|name| dir.join(name))
+        .filter(|scripts_dir| scripts_dir.is_dir())
+    else {
+        return Ok(vec![dir.join(&cfg.update_script_name)]);
+    };
+
+    let entries = fs::read_dir(&scripts_dir).map_err(|e| {
+        UpdateError::FileSystemError(format!(
+            "Failed to list update scripts in {:?}: {}",
+            scripts_dir, e
+        ))
+    })?;
+    let mut scripts = entries
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            UpdateError::FileSystemError(format!(
+                "Failed to list update scripts in {:?}: {}",
+                scripts_dir, e
+            ))
+        })?;
+    scripts.retain(|path| path.is_file());
+    scripts.sort();
+    if scripts.is_empty() {
+        return Err(UpdateError::ScriptError(format!(
+            "no update scripts in {:?}",
+            scripts_dir
+        )));
+    }
+    Ok(scripts)
+}
+
+/// Runs one update script, stopping it once `cancel` fires. This blocks
+/// until the script exits; code on the async runtime must run it on the
+/// blocking pool.
+#[tracing::instrument(name = "update_script", skip_all, fields(script = ?script_path))]
+pub fn run_update_script(
+    cfg: &Config,
+    script_path: &Path,
+    working_dir: &Path, // The script should run from within its extracted directory
+    versions: (i32, i32),
+    log: &ScriptLog,
+    cancel: &CancellationToken,
+) -> Result<(), UpdateError> {
+    tracing::info!(
+        "Running update script {:?} in working directory {:?}",
+        script_path,
+        working_dir
+    );
+
+    check_script_exists(script_path)?;
+    validate_script(cfg, script_path)?;
+    check_script_pinned(cfg, script_path, working_dir)?;
+    execute_script(cfg, script_path, working_dir, versions, log, cancel)
+}
+
+/// Runs the commit script that finalizes an update once the update script
+/// succeeded (e.g. flipping a boot flag). Blocks like `run_update_script`.
+pub fn run_commit_script(
+    cfg: &Config,
+    script_path: &Path,
+    working_dir: &Path,
+    versions: (i32, i32),
+    cancel: &CancellationToken,
+) -> Result<(), UpdateError> {
+    tracing::info!(
+        "Running commit script {:?} in working directory {:?}",
+        script_path,
+        working_dir
+    );
+
+    check_script_exists(script_path)?;
+    validate_script(cfg, script_path)?;
+    check_script_pinned(cfg, script_path, working_dir)?;
+    execute_script(
+        cfg,
+        script_path,
+        working_dir,
+        versions,
+        &ScriptLog::default(),
+        cancel,
+    )
+}
+
+/// Once any script is pinned, refuses to run a script of the extracted tree
+/// at `working_dir` unless its SHA-256 matches its pin: its entry in
+/// `allowed_scripts_sha256`, or `allowed_script_sha256` for the update
+/// script. A script without a pin is refused as well.
+fn check_script_pinned(
+    cfg: &Config,
+    script_path: &Path,
+    working_dir: &Path,
+) -> Result<(), UpdateError> {
+    if cfg.allowed_script_sha256.is_none() && cfg.allowed_scripts_sha256.is_empty() {
+        return Ok(());
+    }
+    let relative = script_path.strip_prefix(working_dir).unwrap_or(script_path);
+    let pinned = relative
+        .to_str()
+        .and_then(|name| cfg.allowed_scripts_sha256.get(name))
+        .or(cfg
+            .allowed_script_sha256
+            .as_ref()
+            .filter(|_| relative == Path::new(&cfg.update_script_name)));
+    let Some(allowed) = pinned else {
+        return Err(UpdateError::ScriptNotAllowed(format!(
+            "{:?} has no pinned hash in allowed_scripts_sha256",
+            relative
+        )));
+    };
+    crate::crypto::verify_sha256(script_path, allowed).map_err(|e| {
+        UpdateError::ScriptNotAllowed(format!(
+            "{:?} does not match its pinned hash ({})",
+            relative, e
+        ))
+    })
+}
+
+fn check_script_exists(script_path: &Path) -> Result<(), UpdateError> {
+    if !script_path.exists() {
+        return Err(UpdateError::ScriptError(format!(
+            "Script not found at {:?}",
+            script_path
+        )));
+    }
+    Ok(())
+}
+
+/// Sends `signal` to the process group the script leads, reaching whatever
+/// it started as well.
+fn signal_process_group(child: &Child, signal: libc::c_int) {
+    // SAFETY: kill has no memory-safety preconditions; the group is led by
+    // our child, which is not reaped yet or was just reaped by us.
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), signal);
+    }
+}
+
+/// Waits for `child` to exit. On cancellation its process group is sent
+/// `SIGTERM`, killed if still running after `SCRIPT_TERM_GRACE`, the child
+/// reaped, and `None` returned.
+fn wait_cancellable(
+    child: &mut Child,
+    cancel: &CancellationToken,
+) -> io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if cancel.is_cancelled() {
+            break;
+        }
+        thread::sleep(SCRIPT_POLL_INTERVAL);
+    }
+
+    tracing::warn!("Sending SIGTERM to script process group {}", child.id());
+    signal_process_group(child, libc::SIGTERM);
+    let deadline = std::time::Instant::now() + SCRIPT_TERM_GRACE;
+    while child.try_wait()?.is_none() {
+        if std::time::Instant::now() >= deadline {
+            tracing::warn!("Script process {} ignored SIGTERM, killing it", child.id());
+            break;
+        }
+        thread::sleep(SCRIPT_POLL_INTERVAL);
+    }
+    // Also stops stragglers of the group, which would keep the output pipes open.
+    signal_process_group(child, libc::SIGKILL);
+    child.wait()?;
+    Ok(None)
+}
+
+fn execute_script(
+    cfg: &Config,
+    script_path: &Path,
+    working_dir: &Path,
+    (from, to): (i32, i32),
+    log: &ScriptLog,
+    cancel: &CancellationToken,
+) -> Result<(), UpdateError> {
+    let mut command = match &cfg.script_interpreter {
+        Some(interpreter) => {
+            tracing::info!(
+                "Running {:?} with interpreter {:?}",
+                script_path,
+                interpreter
+            );
+            let mut command = Command::new(interpreter);
+            command.arg(script_path);
+            command
+        }
+        None => {
+            make_executable(script_path)?;
+            Command::new(script_path)
+        }
+    };
+
+    let env = build_script_env(cfg, from, to, working_dir)
+        .into_iter()
+        .map(|(name, value)| match value {
+            ScriptEnvValue::Plain(value) | ScriptEnvValue::Secret(Ok(value)) => Ok((name, value)),
+            ScriptEnvValue::Secret(Err(e)) => Err(e),
+        })
+        .collect::<Result<Vec<_>, UpdateError>>()?;
+    tracing::debug!(
+        "Passing environment to update script: {:?}",
+        env.iter().map(|(name, _)| name).collect::<Vec<_>>()
+    );
+
+    apply_script_priority(&mut command, cfg);
+    // Its own process group, so cancellation can stop everything it started.
+    command.process_group(0);
+    let mut child = command
+        .envs(env)
+        .current_dir(working_dir) // Run the script from its own directory
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            UpdateError::ScriptError(format!(
+                "Failed to execute update script {:?}: {}",
+                script_path, e
+            ))
+        })?;
+
+    // Both pipes are drained concurrently so neither can fill up and block the script.
+    let stdout = forward_output(
+        child.stdout.take().unwrap(),
+        OutputStream::Stdout,
+        log.clone(),
+        Vec::new(),
+    );
+    let stderr = forward_output(
+        child.stderr.take().unwrap(),
+        OutputStream::Stderr,
+        log.clone(),
+        disk_full_patterns(cfg),
+    );
+
+    let status = wait_cancellable(&mut child, cancel).map_err(|e| {
+        UpdateError::ScriptError(format!(
+            "Failed to wait for update script {:?}: {}",
+            script_path, e
+        ))
+    })?;
+    let stdout = join_output(stdout);
+    let stderr = join_output(stderr);
+    let Some(status) = status else {
+        return Err(UpdateError::Cancelled);
+    };
+
+    if status.success() {
+        tracing::info!("Update script executed successfully.");
+        Ok(())
+    } else {
+        let error_message = format!(
+            "Update script failed with status: {:?}.\nSTDOUT (tail):\n{}\nSTDERR (tail):\n{}",
+            status.code(),
+            Vec::from(stdout.tail).join("\n"),
+            Vec::from(stderr.tail).join("\n")
+        );
+        tracing::error!("{}", error_message);
+        match stderr.disk_full {
+            Some(line) => Err(UpdateError::ScriptDiskFull(format!(
+                "stderr reported {:?}. {}",
+                line, error_message
+            ))),
+            None => Err(UpdateError::ScriptError(error_message)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned_scripts_must_match_their_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("update.sh"), "#!/bin/sh\n").unwrap();
+        fs::write(dir.path().join("commit.sh"), "#!/bin/sh\n").unwrap();
+        // SHA-256 of "#!/bin/sh\n".
+        let hash = "a8076d3d28d21e02012b20eaf7dbf75409a6277134439025f282e368e3305abf";
+        let cfg = crate::test_support::config(&format!("allowed_script_sha256 = \"{}\"", hash));
+        let update = dir.path().join("update.sh");
+        let commit = dir.path().join("commit.sh");
+
+        check_script_pinned(&cfg, &update, dir.path()).unwrap();
+        assert!(matches!(
+            check_script_pinned(&cfg, &commit, dir.path()),
+            Err(UpdateError::ScriptNotAllowed(_))
+        ));
+
+        let mut cfg = cfg;
+        cfg.allowed_scripts_sha256
+            .insert("commit.sh".to_string(), "00".repeat(32));
+        assert!(matches!(
+            check_script_pinned(&cfg, &commit, dir.path()),
+            Err(UpdateError::ScriptNotAllowed(_))
+        ));
+        cfg.allowed_scripts_sha256
+            .insert("commit.sh".to_string(), hash.to_string());
+        check_script_pinned(&cfg, &commit, dir.path()).unwrap();
+    }
+
+    #[test]
+    fn overlong_output_lines_are_cut() {
+        let mut output = vec![b'x'; 3 * OUTPUT_LINE_MAX_BYTES as usize];
+        output.extend_from_slice(b"\nnext\n");
+        let log = ScriptLog::default();
+        log.inner.lock().unwrap().max_bytes = usize::MAX;
+
+        let stream = join_output(forward_output(
+            io::Cursor::new(output),
+            OutputStream::Stdout,
+            log.clone(),
+            Vec::new(),
+        ));
+
+        assert_eq!(stream.tail.len(), 2);
+        assert_eq!(stream.tail[1], "next");
+        let captured = log.inner.lock().unwrap();
+        assert!(captured.lines[0].len() < OUTPUT_LINE_MAX_BYTES as usize + 32);
+        assert!(captured.lines[0].ends_with("[line truncated]\n"));
+    }
+}
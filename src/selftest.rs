@@ -0,0 +1,61 @@
+use crate::api_client::ApiClient;
+use crate::config::{get_current_version, Config};
+use crate::error::UpdateError;
+
+struct CheckResult {
+    name: &'static str,
+    result: Result<(), UpdateError>,
+}
+
+/// Verifies the device is provisioned correctly without downloading or
+/// applying anything. Prints a pass/fail line per check and returns whether
+/// all of them passed.
+pub async fn run(cfg: &Config, api: &ApiClient) -> bool {
+    let checks = vec![
+        CheckResult {
+            name: "decryption key",
+            result: check_decryption_key(cfg),
+        },
+        CheckResult {
+            name: "download directory writable",
+            result: crate::probe_download_dir(&cfg.download_base_dir),
+        },
+        CheckResult {
+            name: "version file readable",
+            result: get_current_version(cfg).map(|_| ()),
+        },
+        CheckResult {
+            name: "update check endpoint",
+            result: api.probe_endpoint(&cfg.update_check_api_url).await,
+        },
+        CheckResult {
+            name: "status report endpoint",
+            result: api.probe_endpoint(&cfg.status_report_api_url).await,
+        },
+    ];
+
+    let mut all_passed = true;
+    for check in &checks {
+        match &check.result {
+            Ok(()) => println!("[PASS] {}", check.name),
+            Err(e) => {
+                all_passed = false;
+                println!("[FAIL] {}: {}", check.name, e);
+            }
+        }
+    }
+    all_passed
+}
+
+fn check_decryption_key(cfg: &Config) -> Result<(), UpdateError> {
+    for (i, key) in cfg.get_decryption_keys()?.iter().enumerate() {
+        if key.len() != 32 {
+            return Err(UpdateError::ConfigError(format!(
+                "decryption key #{} is {} bytes, expected 32",
+                i + 1,
+                key.len()
+            )));
+        }
+    }
+    Ok(())
+}
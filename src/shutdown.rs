@@ -0,0 +1,46 @@
+use crate::api_client::ApiClient;
+use std::sync::{Arc, Mutex};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Flushes batched status reports when the updater receives `SIGTERM`, then
+/// exits. The flush runs on its own task, so it happens even while a cycle is
+/// blocked on the update script.
+#[derive(Clone, Default)]
+pub struct FlushOnTerminate {
+    current: Arc<Mutex<Option<ApiClient>>>,
+}
+
+impl FlushOnTerminate {
+    /// Replaces the default `SIGTERM` handling. When the handler can't be
+    /// installed, reports still queued at exit are lost, which is logged.
+    pub fn listen() -> Self {
+        let flush = FlushOnTerminate::default();
+        let mut signals = match signal(SignalKind::terminate()) {
+            Ok(signals) => signals,
+            Err(e) => {
+                tracing::warn!(
+                    "Cannot listen for SIGTERM, batched reports may be lost: {}",
+                    e
+                );
+                return flush;
+            }
+        };
+
+        let current = Arc::clone(&flush.current);
+        tokio::spawn(async move {
+            signals.recv().await;
+            tracing::info!("SIGTERM received, flushing status reports before exiting");
+            let api = current.lock().unwrap().clone();
+            if let Some(api) = api {
+                api.flush_status_reports().await.ok();
+            }
+            std::process::exit(0);
+        });
+        flush
+    }
+
+    /// Client whose queue is flushed; replaced when the config is reloaded.
+    pub fn watch(&self, api: &ApiClient) {
+        *self.current.lock().unwrap() = Some(api.clone());
+    }
+}
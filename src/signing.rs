@@ -0,0 +1,55 @@
+//! HMAC signing of status reports with `hmac_secret`.
+//!
+//! The signature is `HMAC-SHA256(hmac_secret, "<timestamp>\n<nonce>\n<body>")`,
+//! hex-encoded, where `<body>` is the exact JSON request body sent (fields in
+//! declaration order, no whitespace), `<timestamp>` the Unix time in seconds
+//! and `<nonce>` 32 random hex characters. The three are sent in
+//! `x-signature`, `x-signature-timestamp` and `x-signature-nonce`; the server
+//! rejects stale timestamps and nonces it has seen before.
+
+use crate::error::UpdateError;
+use crate::random;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SIGNATURE_HEADER: &str = "x-signature";
+pub const TIMESTAMP_HEADER: &str = "x-signature-timestamp";
+pub const NONCE_HEADER: &str = "x-signature-nonce";
+
+pub struct Signature {
+    pub timestamp: String,
+    pub nonce: String,
+    pub signature: String,
+}
+
+fn random_nonce() -> Result<String, UpdateError> {
+    let mut nonce = [0u8; 16];
+    random::fill(&mut nonce).map_err(|e| {
+        UpdateError::FileIOError(format!("Failed to generate a request nonce: {}", e))
+    })?;
+    Ok(hex::encode(nonce))
+}
+
+/// Signs a request `body` with a fresh timestamp and nonce.
+pub fn sign(secret: &str, body: &[u8]) -> Result<Signature, UpdateError> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .to_string();
+    let nonce = random_nonce()?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|e| UpdateError::ConfigError(format!("Invalid hmac_secret: {}", e)))?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b"\n");
+    mac.update(nonce.as_bytes());
+    mac.update(b"\n");
+    mac.update(body);
+    Ok(Signature {
+        timestamp,
+        nonce,
+        signature: hex::encode(mac.finalize().into_bytes()),
+    })
+}
@@ -0,0 +1,201 @@
+use crate::config::Config;
+use crate::error::UpdateError;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+};
+
+/// Directory under `download_base_dir` the `protected_paths` are copied to
+/// while the update script runs.
+const SNAPSHOT_DIR_NAME: &str = "protected_snapshot";
+
+/// File in the snapshot directory listing the snapshotted paths, written
+/// once every copy is complete.
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Copies `src` to `dst`, recursing into directories and recreating symlinks
+/// rather than following them. Permissions are kept.
+fn copy_tree(src: &Path, dst: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(src)?;
+    if metadata.file_type().is_symlink() {
+        symlink(fs::read_link(src)?, dst)
+    } else if metadata.is_dir() {
+        fs::create_dir(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+        fs::set_permissions(dst, metadata.permissions())
+    } else {
+        fs::copy(src, dst).map(|_| ())
+    }
+}
+
+fn remove_path(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// A protected path with whether it existed when the snapshot was taken;
+/// one that didn't is removed on restore. Its copy is named after its index.
+#[derive(Serialize, Deserialize)]
+struct ProtectedPath {
+    path: PathBuf,
+    existed: bool,
+}
+
+/// Copies of the `protected_paths` taken before the update script runs, put
+/// back if the update fails. A snapshot still on disk at startup was left by
+/// an update that never finished.
+///
+/// Taking and restoring a snapshot copies whole trees and blocks; code on
+/// the async runtime runs them on the blocking pool.
+pub struct Snapshot {
+    dir: PathBuf,
+    paths: Vec<ProtectedPath>,
+}
+
+impl Snapshot {
+    /// Snapshots the `protected_paths`, or returns `None` when there are none.
+    pub fn take(cfg: &Config) -> Result<Option<Self>, UpdateError> {
+        if cfg.protected_paths.is_empty() {
+            return Ok(None);
+        }
+        let dir = cfg.download_base_dir.join(SNAPSHOT_DIR_NAME);
+        remove_path(&dir)
+            .and_then(|_| fs::create_dir(&dir))
+            .map_err(|e| {
+                UpdateError::FileSystemError(format!(
+                    "Failed to create snapshot directory {:?}: {}",
+                    dir, e
+                ))
+            })?;
+
+        let mut paths = Vec::with_capacity(cfg.protected_paths.len());
+        for (i, path) in cfg.protected_paths.iter().enumerate() {
+            let exists = fs::symlink_metadata(path).is_ok();
+            if exists {
+                copy_tree(path, &dir.join(i.to_string())).map_err(|e| {
+                    UpdateError::FileSystemError(format!("Failed to snapshot {:?}: {}", path, e))
+                })?;
+            }
+            paths.push(ProtectedPath {
+                path: path.clone(),
+                existed: exists,
+            });
+        }
+        let snapshot = Snapshot { dir, paths };
+        snapshot.write_manifest()?;
+        tracing::info!("Snapshotted {} protected paths", snapshot.paths.len());
+        Ok(Some(snapshot))
+    }
+
+    fn write_manifest(&self) -> Result<(), UpdateError> {
+        let manifest = self.dir.join(MANIFEST_FILE_NAME);
+        let tmp = manifest.with_extension("json.tmp");
+        serde_json::to_vec(&self.paths)
+            .map_err(io::Error::other)
+            .and_then(|json| fs::write(&tmp, json))
+            .and_then(|_| fs::rename(&tmp, &manifest))
+            .map_err(|e| {
+                UpdateError::FileSystemError(format!(
+                    "Failed to write snapshot manifest {:?}: {}",
+                    manifest, e
+                ))
+            })
+    }
+
+    /// The snapshot an interrupted update left under `download_base_dir`,
+    /// if any. One without a manifest was still being taken, so the update
+    /// script never ran; it is removed.
+    pub fn leftover(cfg: &Config) -> Result<Option<Self>, UpdateError> {
+        let dir = cfg.download_base_dir.join(SNAPSHOT_DIR_NAME);
+        let manifest = match fs::read(dir.join(MANIFEST_FILE_NAME)) {
+            Ok(manifest) => manifest,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                remove_path(&dir).map_err(|e| {
+                    UpdateError::FileSystemError(format!(
+                        "Failed to remove incomplete snapshot {:?}: {}",
+                        dir, e
+                    ))
+                })?;
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(UpdateError::FileSystemError(format!(
+                    "Failed to read snapshot manifest in {:?}: {}",
+                    dir, e
+                )))
+            }
+        };
+        let paths = serde_json::from_slice(&manifest).map_err(|e| {
+            UpdateError::FileSystemError(format!("Invalid snapshot manifest in {:?}: {}", dir, e))
+        })?;
+        Ok(Some(Snapshot { dir, paths }))
+    }
+
+    /// Puts every protected path back as it was, returning those restored.
+    /// A path that can't be restored is logged and skipped.
+    pub fn restore(&self) -> Vec<PathBuf> {
+        let mut restored = Vec::new();
+        for (i, ProtectedPath { path, existed }) in self.paths.iter().enumerate() {
+            let result = remove_path(path).and_then(|_| {
+                if *existed {
+                    copy_tree(&self.dir.join(i.to_string()), path)
+                } else {
+                    Ok(())
+                }
+            });
+            match result {
+                Ok(()) => {
+                    tracing::info!("Restored protected path {:?}", path);
+                    restored.push(path.clone());
+                }
+                Err(e) => tracing::error!("Failed to restore protected path {:?}: {}", path, e),
+            }
+        }
+        restored
+    }
+
+    /// Removes the snapshot once it is no longer needed.
+    pub fn discard(self) {
+        if let Err(e) = remove_path(&self.dir) {
+            tracing::warn!("Failed to remove snapshot {:?}: {}", self.dir, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MINIMAL_CONFIG;
+
+    #[test]
+    fn leftover_snapshot_restores_protected_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let protected = dir.path().join("config.json");
+        fs::write(&protected, "old").unwrap();
+        let cfg = Config::parse(&format!(
+            "{}protected_paths = [{:?}]\n",
+            MINIMAL_CONFIG.replace("/opt/updater_downloads", dir.path().to_str().unwrap()),
+            protected
+        ))
+        .unwrap();
+
+        // The updater dies while the script rewrites the protected file.
+        Snapshot::take(&cfg).unwrap().unwrap();
+        fs::write(&protected, "half-written").unwrap();
+
+        let leftover = Snapshot::leftover(&cfg).unwrap().unwrap();
+        assert_eq!(leftover.restore(), vec![protected.clone()]);
+        leftover.discard();
+        assert_eq!(fs::read_to_string(&protected).unwrap(), "old");
+        assert!(Snapshot::leftover(&cfg).unwrap().is_none());
+    }
+}
@@ -0,0 +1,77 @@
+//! `--tail-status`: a single line on stdout following the cycle in progress
+//! (checking -> downloading 45% -> extracting -> running script -> done),
+//! independent of the log level.
+
+use std::{
+    fmt::Display,
+    io::{self, IsTerminal, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Line shown last, so repeated updates don't reprint it.
+static SHOWN: Mutex<String> = Mutex::new(String::new());
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Replaces the status line on a terminal; elsewhere, e.g. piped into a
+/// file, each change gets a line of its own. `end` finishes the line.
+fn show(line: String, end: bool) {
+    let mut shown = SHOWN.lock().unwrap();
+    if *shown == line {
+        return;
+    }
+    let mut stdout = io::stdout().lock();
+    let result = if stdout.is_terminal() {
+        write!(stdout, "\r{}\x1b[K{}", line, if end { "\n" } else { "" })
+    } else {
+        writeln!(stdout, "{}", line)
+    };
+    result.and_then(|_| stdout.flush()).ok();
+    *shown = if end { String::new() } else { line };
+}
+
+/// Shows the update phase just entered, as named by `PhaseTimings`.
+pub fn phase(phase: &str) {
+    if !enabled() {
+        return;
+    }
+    let label = match phase {
+        "check" => "checking",
+        "download" => "downloading",
+        "verify" => "verifying",
+        "decrypt" => "decrypting",
+        "extract" => "extracting",
+        "script" => "running script",
+        other => other,
+    };
+    show(label.to_string(), false);
+}
+
+pub fn download_progress(written: u64, total: Option<u64>) {
+    if !enabled() {
+        return;
+    }
+    let line = match total.filter(|total| *total > 0) {
+        Some(total) => format!("downloading {}%", written.saturating_mul(100) / total),
+        None => format!("downloading {} MiB", written / (1024 * 1024)),
+    };
+    show(line, false);
+}
+
+/// Ends the line of a cycle with how it went.
+pub fn finished(summary: impl Display) {
+    if !enabled() {
+        return;
+    }
+    show(format!("done: {}", summary), true);
+}
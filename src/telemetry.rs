@@ -0,0 +1,80 @@
+use crate::config::Config;
+use crate::error::UpdateError;
+use tracing_subscriber::{Layer, Registry};
+
+pub type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Export of spans to the OTLP collector at `otlp_endpoint`, available when
+/// built with the `otlp` feature. Dropping it exports the spans still
+/// buffered.
+#[derive(Default)]
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Telemetry {
+    /// Sets up the export pipeline and returns the layer feeding it, or no
+    /// layer when `otlp_endpoint` is unset.
+    #[cfg(feature = "otlp")]
+    pub fn init(config: Option<&Config>) -> Result<(Self, Option<BoxedLayer>), UpdateError> {
+        use opentelemetry::{trace::TracerProvider as _, KeyValue};
+        use opentelemetry_otlp::WithExportConfig;
+        use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+
+        let Some((config, endpoint)) =
+            config.and_then(|c| c.otlp_endpoint.as_ref().map(|endpoint| (c, endpoint)))
+        else {
+            return Ok((Telemetry::default(), None));
+        };
+
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|e| {
+                UpdateError::ConfigError(format!(
+                    "Failed to set up OTLP export to {}: {}",
+                    endpoint, e
+                ))
+            })?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(env!("CARGO_PKG_NAME"))
+                    .with_attribute(KeyValue::new(
+                        "podbox.service_name",
+                        config.service_name.clone(),
+                    ))
+                    .build(),
+            )
+            .build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("podbox_update"));
+        Ok((
+            Telemetry {
+                provider: Some(provider),
+            },
+            Some(Box::new(layer)),
+        ))
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    pub fn init(config: Option<&Config>) -> Result<(Self, Option<BoxedLayer>), UpdateError> {
+        if config.is_some_and(|c| c.otlp_endpoint.is_some()) {
+            eprintln!("otlp_endpoint is set but this build lacks the otlp feature, ignoring it");
+        }
+        Ok((Telemetry::default(), None))
+    }
+}
+
+#[cfg(feature = "otlp")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to export the remaining spans: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,22 @@
+//! Fixtures shared by the unit tests.
+
+use crate::config::Config;
+
+/// The fields every config had before optional ones were added.
+pub const MINIMAL_CONFIG: &str = r#"
+service_name = "podbox_update"
+current_version_file = "/etc/podbox_update/version.txt"
+update_check_api_url = "https://example.com/update"
+status_report_api_url = "https://example.com/status"
+poll_interval_seconds = 300
+download_base_dir = "/opt/updater_downloads"
+decryption_key_hex = "1234567891234567891234567891234567891234567891234567891234567890"
+update_script_name = "update.sh"
+db_password = ""
+device_token = ""
+"#;
+
+/// `MINIMAL_CONFIG` with the TOML lines of `extra` on top.
+pub fn config(extra: &str) -> Config {
+    Config::parse(&format!("{}{}", MINIMAL_CONFIG, extra)).unwrap()
+}
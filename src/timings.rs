@@ -0,0 +1,49 @@
+use crate::status_line;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Wall-clock time spent in each phase of an update cycle. Entering a phase
+/// ends the previous one, so phases are measured back to back. The phase is
+/// also recorded as `phase` on the current span and shown by `--tail-status`.
+#[derive(Default)]
+pub struct PhaseTimings {
+    current: Option<(&'static str, Instant)>,
+    finished: Vec<(&'static str, Duration)>,
+}
+
+impl PhaseTimings {
+    pub fn enter(&mut self, phase: &'static str) {
+        self.finish();
+        tracing::Span::current().record("phase", phase);
+        status_line::phase(phase);
+        self.current = Some((phase, Instant::now()));
+    }
+
+    pub fn finish(&mut self) {
+        if let Some((phase, started)) = self.current.take() {
+            self.finished.push((phase, started.elapsed()));
+        }
+    }
+
+    /// Whether any phase past the update check ran.
+    pub fn did_work(&self) -> bool {
+        self.finished.iter().any(|(phase, _)| *phase != "check")
+    }
+
+    /// `phase=<ms>ms` pairs in the order the phases ran.
+    pub fn summary(&self) -> String {
+        self.finished
+            .iter()
+            .map(|(phase, took)| format!("{}={}ms", phase, took.as_millis()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn as_millis(&self) -> BTreeMap<&'static str, u64> {
+        let mut millis = BTreeMap::new();
+        for (phase, took) in &self.finished {
+            *millis.entry(*phase).or_insert(0) += took.as_millis() as u64;
+        }
+        millis
+    }
+}
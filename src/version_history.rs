@@ -0,0 +1,54 @@
+use crate::error::UpdateError;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+/// File under `download_base_dir` listing the versions seen installed.
+pub const HISTORY_FILE_NAME: &str = "version_history.json";
+
+/// Versions kept in the history, oldest dropped first.
+const HISTORY_LEN: usize = 10;
+
+/// Versions installed by the updater, latest last, kept so a corrupt version
+/// file can be restored.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct VersionHistory {
+    versions: Vec<i32>,
+}
+
+fn load(base_dir: &Path) -> VersionHistory {
+    let path = base_dir.join(HISTORY_FILE_NAME);
+    let Ok(data) = fs::read(&path) else {
+        return VersionHistory::default();
+    };
+    serde_json::from_slice(&data).unwrap_or_else(|e| {
+        tracing::warn!("Ignoring corrupt version history {:?}: {}", path, e);
+        VersionHistory::default()
+    })
+}
+
+/// Adds `version` to the history unless it is already the latest entry.
+pub fn record(base_dir: &Path, version: i32) -> Result<(), UpdateError> {
+    let mut history = load(base_dir);
+    if history.versions.last() == Some(&version) {
+        return Ok(());
+    }
+    history.versions.push(version);
+    let excess = history.versions.len().saturating_sub(HISTORY_LEN);
+    history.versions.drain(..excess);
+
+    let path = base_dir.join(HISTORY_FILE_NAME);
+    let tmp_path = path.with_extension("json.tmp");
+    let data = serde_json::to_vec(&history).map_err(|e| {
+        UpdateError::FileIOError(format!("Failed to serialize version history: {}", e))
+    })?;
+    fs::write(&tmp_path, data)
+        .and_then(|_| fs::rename(&tmp_path, &path))
+        .map_err(|e| {
+            UpdateError::FileIOError(format!("Failed to write version history {:?}: {}", path, e))
+        })
+}
+
+/// The version installed last.
+pub fn last_known_good(base_dir: &Path) -> Option<i32> {
+    load(base_dir).versions.last().copied()
+}
@@ -0,0 +1,152 @@
+use crate::api_client::ApiClient;
+use crate::config::Config;
+use crate::error::UpdateError;
+use crate::hooks;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Restart of `service_name`; `{service}` is replaced with it.
+const RESTART_COMMAND: &str = "/usr/bin/sudo /usr/bin/systemctl restart {service}";
+
+/// Probe run when no `service_health_command` is set.
+const DEFAULT_HEALTH_COMMAND: &str = "systemctl is-active --quiet {service}";
+
+/// How often `service_health_command` is run after a restart.
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The health probe of `service_name`.
+fn health_command(cfg: &Config) -> String {
+    cfg.service_health_command
+        .as_deref()
+        .unwrap_or(DEFAULT_HEALTH_COMMAND)
+        .replace("{service}", &cfg.service_name)
+}
+
+/// Runs `service_health_command` once, off the reactor, before the commit
+/// script finalizes an update whose service isn't restarted by the updater.
+/// Fails with `ServiceUnhealthy` unless it passes.
+pub async fn check_healthy(cfg: &Config, cancel: &CancellationToken) -> Result<(), UpdateError> {
+    let health = health_command(cfg);
+    match hooks::run_gate_hook(cfg, "service health", &health, cancel).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(UpdateError::ServiceUnhealthy(format!(
+            "{} failed its health check",
+            cfg.service_name
+        ))),
+        Err(UpdateError::Cancelled) => Err(UpdateError::Cancelled),
+        Err(e) => Err(UpdateError::ServiceUnhealthy(format!(
+            "{} health check failed: {}",
+            cfg.service_name, e
+        ))),
+    }
+}
+
+/// Restarts `service_name`, then waits at most `service_health_timeout_seconds`
+/// for the health probe to pass. A restart command exiting nonzero counts as
+/// a failed attempt. Both commands run off the reactor and are killed after
+/// `hook_timeout_seconds`; the only error is `Cancelled`.
+async fn restart_once(cfg: &Config, cancel: &CancellationToken) -> Result<bool, UpdateError> {
+    let restart = RESTART_COMMAND.replace("{service}", &cfg.service_name);
+    match hooks::run_gate_hook(cfg, "service restart", &restart, cancel).await {
+        Ok(true) => {}
+        Ok(false) => {
+            tracing::warn!("Restart command of {} exited nonzero", cfg.service_name);
+            return Ok(false);
+        }
+        Err(UpdateError::Cancelled) => return Err(UpdateError::Cancelled),
+        Err(e) => {
+            tracing::warn!("{}", e);
+            return Ok(false);
+        }
+    }
+
+    let health = health_command(cfg);
+    let deadline = Instant::now() + Duration::from_secs(cfg.service_health_timeout_seconds);
+    loop {
+        match hooks::run_gate_hook(cfg, "service health", &health, cancel).await {
+            Ok(true) => return Ok(true),
+            Ok(false) => {}
+            Err(UpdateError::Cancelled) => return Err(UpdateError::Cancelled),
+            Err(e) => tracing::warn!("{}", e),
+        }
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        tokio::time::sleep(
+            HEALTH_POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())),
+        )
+        .await;
+    }
+}
+
+/// Restarts `service_name` once an update was installed, up to
+/// `post_update_restart_attempts` times until it is healthy, reporting each
+/// attempt. Fails with `ServiceUnhealthy` when it never came back.
+pub async fn restart_until_healthy(
+    cfg: &Config,
+    api: &ApiClient,
+    version: i32,
+    cancel: &CancellationToken,
+) -> Result<(), UpdateError> {
+    let attempts = cfg.post_update_restart_attempts;
+    for attempt in 1..=attempts {
+        tracing::info!(
+            "Restarting {} (attempt {}/{})",
+            cfg.service_name,
+            attempt,
+            attempts
+        );
+        api.report_status(
+            version,
+            format!(
+                "restarting {} (attempt {}/{})",
+                cfg.service_name, attempt, attempts
+            ),
+        )
+        .await
+        .ok();
+        if restart_once(cfg, cancel).await? {
+            tracing::info!("{} is healthy", cfg.service_name);
+            api.report_status(
+                version,
+                format!(
+                    "{} healthy after restart attempt {}/{}",
+                    cfg.service_name, attempt, attempts
+                ),
+            )
+            .await
+            .ok();
+            return Ok(());
+        }
+        tracing::warn!(
+            "{} not healthy after restart attempt {}/{}",
+            cfg.service_name,
+            attempt,
+            attempts
+        );
+    }
+    Err(UpdateError::ServiceUnhealthy(format!(
+        "{} not healthy after {} restart attempts",
+        cfg.service_name, attempts
+    )))
+}
+
+/// Restarts `service_name` once more after a failed update was rolled back,
+/// so it runs the restored files again. This runs even when the update was
+/// cancelled, so it can't be cancelled itself.
+pub async fn restart_after_rollback(cfg: &Config, api: &ApiClient, version: i32) {
+    let healthy = restart_once(cfg, &CancellationToken::new())
+        .await
+        .unwrap_or(false);
+    let message = if healthy {
+        format!("{} healthy again after rollback", cfg.service_name)
+    } else {
+        format!("{} still not healthy after rollback", cfg.service_name)
+    };
+    if healthy {
+        tracing::info!("{}", message);
+    } else {
+        tracing::error!("{}", message);
+    }
+    api.report_status(version, message).await.ok();
+}